@@ -0,0 +1,159 @@
+// throughput of the RLE engine over a few data shapes, run with
+// cargo run --release -p shoko --example rle_bench [MiB per shape]
+//
+// "before" is the byte-at-a-time engine the current one replaced, kept here so both columns
+// of the README table come out of the same run
+use std::env;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use shoko::compress::compress;
+use shoko::decompress::decompress;
+
+const ROUNDS: usize = 5;
+const CLEVEL: u8 = 5;
+
+fn main() {
+    let mib: usize = env::args().nth(1).and_then(|s| s.parse().ok()).unwrap_or(64);
+    let len = mib * 1024 * 1024;
+
+    println!("| shape | ratio | compress before | compress after | decompress before | decompress after |");
+    println!("|---|---|---|---|---|---|");
+    for (name, data) in shapes(len) {
+        let packed = compress(&data, CLEVEL);
+        assert_eq!(baseline::compress(&data, CLEVEL), packed);
+        assert_eq!(decompress(&packed).unwrap(), data);
+        assert_eq!(baseline::decompress(&packed).unwrap(), data);
+
+        let enc_before = best_of(|| drop(black_box(baseline::compress(black_box(&data), CLEVEL))));
+        let enc = best_of(|| drop(black_box(compress(black_box(&data), CLEVEL))));
+        let dec_before = best_of(|| drop(black_box(baseline::decompress(black_box(&packed)).unwrap())));
+        let dec = best_of(|| drop(black_box(decompress(black_box(&packed)).unwrap())));
+        println!(
+            "| {} | {:.2}x | {:.0} MB/s | {:.0} MB/s | {:.0} MB/s | {:.0} MB/s |",
+            name,
+            data.len() as f64 / packed.len() as f64,
+            mb_per_s(len, enc_before),
+            mb_per_s(len, enc),
+            mb_per_s(len, dec_before),
+            mb_per_s(len, dec),
+        );
+    }
+}
+
+fn shapes(len: usize) -> Vec<(&'static str, Vec<u8>)> {
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    let mut next = move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+
+    let zeros = vec![0u8; len];
+    let random: Vec<u8> = (0..len).map(|_| next() as u8).collect();
+    let text: Vec<u8> = b"the quick brown fox jumps over the lazy dog, "
+        .iter()
+        .cycle()
+        .take(len)
+        .copied()
+        .collect();
+    let mut short_runs = Vec::with_capacity(len);
+    while short_runs.len() < len {
+        let r = next();
+        let n = (1 + (r >> 8) % 6) as usize;
+        short_runs.extend(std::iter::repeat_n(r as u8, n.min(len - short_runs.len())));
+    }
+    let mut long_runs = Vec::with_capacity(len);
+    while long_runs.len() < len {
+        let r = next();
+        let n = (64 + (r >> 8) % 4096) as usize;
+        long_runs.extend(std::iter::repeat_n(r as u8, n.min(len - long_runs.len())));
+    }
+
+    vec![
+        ("zeros", zeros),
+        ("random", random),
+        ("text", text),
+        ("short runs (1-6)", short_runs),
+        ("long runs (64-4K)", long_runs),
+    ]
+}
+
+fn best_of(mut f: impl FnMut()) -> Duration {
+    (0..ROUNDS)
+        .map(|_| {
+            let start = Instant::now();
+            f();
+            start.elapsed()
+        })
+        .min()
+        .unwrap()
+}
+
+fn mb_per_s(len: usize, took: Duration) -> f64 {
+    len as f64 / took.as_secs_f64() / 1_000_000.0
+}
+
+/// the engine as it was before the word-at-a-time rewrite, same output format
+mod baseline {
+    pub fn compress(data: &[u8], clevel: u8) -> Vec<u8> {
+        let threshold = match clevel {
+            1..=3 => 4,
+            4..=6 => 3,
+            7..=9 => 2,
+            _ => 3,
+        };
+        let mut out = Vec::new();
+        let mut i = 0;
+        while i < data.len() {
+            let mut run_len = 1;
+            while i + run_len < data.len() && data[i + run_len] == data[i] && run_len < 255 {
+                run_len += 1;
+            }
+            if run_len >= threshold {
+                out.extend_from_slice(&[0x00, run_len as u8, data[i]]);
+                i += run_len;
+                continue;
+            }
+            let mut end = i;
+            while end < data.len() && (end + 1 >= data.len() || data[end] != data[end + 1]) && end - i < 254 {
+                end += 1;
+            }
+            let end = end.max(i + 1);
+            out.push(0x01);
+            out.push((end - i) as u8);
+            out.extend_from_slice(&data[i..end]);
+            i = end;
+        }
+        out
+    }
+
+    pub fn decompress(data: &[u8]) -> Result<Vec<u8>, String> {
+        let mut out = Vec::new();
+        let mut i = 0;
+        while i < data.len() {
+            let control = data[i];
+            i += 1;
+            match control {
+                0x00 => {
+                    if i + 1 >= data.len() {
+                        return Err("unexpected end of run".to_string());
+                    }
+                    for _ in 0..data[i] {
+                        out.push(data[i + 1]);
+                    }
+                    i += 2;
+                }
+                0x01 => {
+                    let len = *data.get(i).ok_or("missing literal length")? as usize;
+                    i += 1;
+                    out.extend_from_slice(data.get(i..i + len).ok_or("literal length exceeds data")?);
+                    i += len;
+                }
+                _ => return Err(format!("invalid control byte {:#04x}", control)),
+            }
+        }
+        Ok(out)
+    }
+}
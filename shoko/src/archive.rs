@@ -1,22 +1,169 @@
+use std::collections::BTreeMap;
 use std::fs::{File, OpenOptions};
-use std::io::{self, Write, Seek, SeekFrom};
-use crate::read::ShokoReader;
-use crate::write::ShokoWriter;
+use std::io::{self, Read, Write, Seek, SeekFrom};
+use std::path::PathBuf;
+use rand::RngCore;
+use zeroize::Zeroizing;
+use crate::chunk::{ChunkHash, StoredChunk};
+use crate::codec;
+use crate::delta;
+use crate::dict::DictionarySlot;
+use crate::encrypt::{Argon2Params, ArchiveId, BlobBinding, Cipher, Encryption, HeaderMac, KeyDerivation, KeyId};
+use crate::keys::{DefaultKey, KeySlot, RawKey};
+use crate::format::{self, ExtReader, ExtWriter, FieldReader, CURRENT_VERSION, HEADER_LEN, MAGIC_V3};
+use crate::limits::{LimitExceeded, LimitKind, Limits};
+use crate::padding::Padding;
+use crate::read::{self, ShokoReader};
+use crate::recipient::RecipientStanza;
+use crate::shadow::{SecretKey, SecureBuf};
+use crate::sign::ArchiveSignature;
+use crate::write::{self, Sealer, ShokoWriter};
 
+#[derive(Default, Clone)]
 pub struct ShokoEntry {
     pub path: String,
     pub size: u64,
     pub offset: u64,
     pub compression_level: u8,
+    /// plaintext length, None for entries written before it was recorded
+    pub original_size: Option<u64>,
+    /// older versions this entry is a delta against, full copy first. empty for normal entries
+    pub delta_chain: Vec<BlobRef>,
+    /// set when the blob at offset/size is a solid block shared with other entries
+    pub solid: Option<SolidSlice>,
+    /// set for deduplicated entries, which have no blob of their own (offset and size are 0)
+    /// and are the concatenation of these chunks from the archive's chunk store
+    pub chunks: Option<Vec<ChunkHash>>,
+    /// the write that sealed this entry's blob, part of its associated data. 0 for entries
+    /// from before generations were recorded
+    pub generation: u64,
+    /// same thing for each delta chain link, in chain order
+    pub delta_generations: Vec<u64>,
+    /// the key this entry's blobs are sealed under, wrapped by the archive key. None for
+    /// chunked entries, unencrypted archives and entries from before data keys
+    pub data_key: Option<Vec<u8>>,
+}
+
+/// where a solid member's bytes sit inside its decoded block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SolidSlice {
+    pub offset: u64,
+    pub len: u64,
+}
+
+/// where one sealed blob sits in the file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BlobRef {
+    pub offset: u64,
+    pub size: u64,
+    pub compression_level: u8,
+}
+
+impl ShokoEntry {
+    pub fn blob(&self) -> BlobRef {
+        BlobRef {
+            offset: self.offset,
+            size: self.size,
+            compression_level: self.compression_level,
+        }
+    }
+
+    pub fn is_delta(&self) -> bool {
+        !self.delta_chain.is_empty()
+    }
+
+    pub fn is_solid(&self) -> bool {
+        self.solid.is_some()
+    }
+
+    pub fn is_chunked(&self) -> bool {
+        self.chunks.is_some()
+    }
 }
 
 pub struct ShokoArchive {
     pub(crate) file: File,
+    /// where `file` lives, for work that replaces the whole file (rekey)
+    pub(crate) path: PathBuf,
     pub entries: Vec<ShokoEntry>,
+    pub(crate) version: u8,
+    pub(crate) max_delta_chain: usize,
+    /// last decoded solid block, so pulling its members out one by one doesn't redo the work.
+    /// it's plaintext, so it's wiped when it goes
+    pub(crate) block_cache: Option<(BlobRef, Zeroizing<Vec<u8>>)>,
+    pub(crate) dictionaries: Vec<DictionarySlot>,
+    pub(crate) active_dictionary: Option<u32>,
+    /// deduplicated chunk store, sorted so the header comes out the same every time
+    pub(crate) chunks: BTreeMap<ChunkHash, StoredChunk>,
+    pub(crate) limits: Limits,
+    /// bytes handed out by `extract_file`, what `max_total_size` is checked against
+    pub(crate) extracted: u64,
+    pub(crate) encryption: Encryption,
+    pub(crate) kdf: KeyDerivation,
+    /// the key, once it's been worked out. derivation can be slow so it happens once per handle
+    pub(crate) cipher: Option<Cipher>,
+    /// the random key `create_with` picked and how the creator's secret will be stretched. it
+    /// goes into slot 0 the first time the handle is unlocked
+    pub(crate) new_key: Option<(KeyDerivation, SecretKey)>,
+    /// None for archives from before blobs were bound to their place, a defrag gives them one
+    pub(crate) archive_id: Option<ArchiveId>,
+    /// last generation handed out, kept in the header so a deleted entry's number isn't reused
+    pub(crate) generation: u64,
+    /// paths, sizes and offsets are written sealed, see `ArchiveOptions::encrypt_index`
+    pub(crate) seal_index: bool,
+    /// a sealed index that couldn't be read yet for lack of a key, `entries` is empty meanwhile
+    pub(crate) sealed_index: Option<Vec<u8>>,
+    pub(crate) key_slots: Vec<KeySlot>,
+    pub(crate) recipients: Vec<RecipientStanza>,
+    pub(crate) signature: Option<ArchiveSignature>,
+    /// which key the blobs are sealed under, None for archives from before it was recorded
+    pub(crate) key_id: Option<KeyId>,
+    pub(crate) padding: Padding,
+    /// authenticates the key records, see `mac_records`. kept as read so a handle without the
+    /// key can still rewrite an index whose key records it didn't touch
+    pub(crate) header_mac: Option<HeaderMac>,
+}
+
+/// choices made once when an archive is created, they're recorded in the header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArchiveOptions {
+    pub encryption: Encryption,
+    /// stretch the creator's secret with Argon2id at these costs, None uses it as a raw 32 byte
+    /// key. either way it only unwraps the archive key, which is random
+    pub kdf: Option<Argon2Params>,
+    /// seal the index too, so paths and sizes can't be listed without the key. only the
+    /// encryption and key derivation records stay readable. ignored for unencrypted archives
+    pub encrypt_index: bool,
+    /// pad blobs before sealing so their sizes don't give the contents away. the index is
+    /// sealed too then, whatever `encrypt_index` says. ignored for unencrypted archives
+    pub padding: Padding,
+}
+
+impl Default for ArchiveOptions {
+    fn default() -> Self {
+        Self {
+            encryption: Encryption::default(),
+            kdf: Some(Argon2Params::default()),
+            encrypt_index: false,
+            padding: Padding::None,
+        }
+    }
 }
 
 impl ShokoArchive {
+    /// creates an encrypted archive, see `create_with` for the other options
     pub fn create(path: &str) -> io::Result<Self> {
+        Self::create_with(path, ArchiveOptions::default())
+    }
+
+    /// encrypted archives get a random key here. the secret the handle is first unlocked with
+    /// (`unlock_with`, or SHOKO_KEY on the first write) is wrapped into key slot 0 and can be
+    /// removed like any other slot. dropped before that, the archive stays empty and unopenable
+    pub fn create_with(path: &str, options: ArchiveOptions) -> io::Result<Self> {
+        // nothing would open it again
+        if options.kdf.is_some_and(|params| !params.within_max()) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Argon2 costs can be at most 4 GiB of memory, 16 passes and 16 lanes"));
+        }
         let mut file = OpenOptions::new()
             .read(true)
             .write(true)
@@ -24,12 +171,31 @@ impl ShokoArchive {
             .truncate(true)
             .open(path)?;
 
-        file.write_all(b"SHOKO001")?;
-        
-        Ok(Self {
-            file,
-            entries: Vec::new(),
-        })
+        file.write_all(MAGIC_V3)?;
+
+        let mut archive = Self::from_file(file, path, CURRENT_VERSION);
+        let mut id = ArchiveId::default();
+        rand::rng().fill_bytes(&mut id);
+        archive.archive_id = Some(id);
+        archive.encryption = options.encryption;
+        if options.encryption.is_encrypted() {
+            let key = SecretKey::new_with(|key| {
+                rand::rng().fill_bytes(key);
+                Ok(())
+            })?;
+            let creator = options.kdf.map_or(KeyDerivation::Raw, KeyDerivation::argon2id);
+            archive.kdf = KeyDerivation::Wrapped;
+            archive.new_key = Some((creator, key));
+        }
+        // a clear index lists every entry's real size, padding the blobs would hide nothing
+        let seal_index = options.encrypt_index || options.padding != Padding::None;
+        archive.seal_index = seal_index && options.encryption.is_encrypted();
+        if options.encryption.is_encrypted() {
+            archive.padding = options.padding;
+        }
+        // an empty index already records the options, so reopening before any write sees them
+        archive.rewrite_index()?;
+        Ok(archive)
     }
 
     pub fn open(path: &str) -> io::Result<Self> {
@@ -38,131 +204,622 @@ impl ShokoArchive {
             .write(true)
             .open(path)?;
 
-        let mut entries = Vec::new();
-        
+        let mut magic = [0u8; 8];
+        file.read_exact(&mut magic)?;
+        let version = format::version_from_magic(&magic)?;
+
+        let mut archive = Self::from_file(file, path, version);
+        archive.load_index()?;
+        Ok(archive)
+    }
+
+    pub(crate) fn from_file(file: File, path: &str, version: u8) -> Self {
+        Self {
+            file,
+            path: PathBuf::from(path),
+            entries: Vec::new(),
+            version,
+            max_delta_chain: 0,
+            block_cache: None,
+            dictionaries: Vec::new(),
+            active_dictionary: None,
+            chunks: BTreeMap::new(),
+            limits: Limits::default(),
+            extracted: 0,
+            // no encryption record means one of the original archives
+            encryption: Encryption::Aes256Gcm,
+            kdf: KeyDerivation::Raw,
+            cipher: None,
+            new_key: None,
+            archive_id: None,
+            generation: 0,
+            seal_index: false,
+            sealed_index: None,
+            key_slots: Vec::new(),
+            recipients: Vec::new(),
+            signature: None,
+            key_id: None,
+            padding: Padding::None,
+            header_mac: None,
+        }
+    }
+
+    /// the options this archive was created with. the Argon2 costs are the creator slot's, or
+    /// the archive's own for archives from before the random key
+    pub fn options(&self) -> ArchiveOptions {
+        let kdf = match self.creator_kdf() {
+            KeyDerivation::Raw | KeyDerivation::Wrapped => None,
+            KeyDerivation::Argon2id { params, .. } => Some(params),
+        };
+        ArchiveOptions { encryption: self.encryption, kdf, encrypt_index: self.seal_index, padding: self.padding }
+    }
+
+    pub fn key_derivation(&self) -> KeyDerivation {
+        self.kdf
+    }
+
+    /// supplies the passphrase (or raw key, for archives without a KDF) up front, otherwise it's
+    /// taken from SHOKO_KEY or SHOKO_KEY_FILE the first time a blob needs it. see `unlock_with` for
+    /// other sources
+    pub fn unlock(&mut self, secret: &[u8]) -> io::Result<()> {
+        self.unlock_with(&RawKey::new(secret))
+    }
+
+    /// what blobs get sealed and opened with, works out the key the first time round
+    pub(crate) fn cipher(&mut self) -> io::Result<Cipher> {
+        if !self.encryption.is_encrypted() {
+            return Ok(Cipher::Plain);
+        }
+        if let Some(cipher) = &self.cipher {
+            return Ok(cipher.clone());
+        }
+        self.unlock_with(&DefaultKey)?;
+        Ok(self.cipher.clone().unwrap())
+    }
+
+    /// a new empty archive at `path` sealed exactly like this one, same salt, key, key slots
+    /// and recipients included. it keeps the archive id too, archives without one get one
+    pub(crate) fn create_sibling(&mut self, path: &str) -> io::Result<ShokoArchive> {
+        let cipher = self.cipher()?;
+        let mut sibling = ShokoArchive::create_with(path, ArchiveOptions { kdf: None, ..self.options() })?;
+        sibling.kdf = self.kdf;
+        sibling.new_key = None;
+        sibling.key_id = cipher.key_id();
+        sibling.cipher = Some(cipher);
+        sibling.key_slots = self.key_slots.clone();
+        sibling.recipients = self.recipients.clone();
+        if self.archive_id.is_some() {
+            sibling.archive_id = self.archive_id;
+            sibling.generation = self.generation;
+        }
+        sibling.rewrite_index()?;
+        Ok(sibling)
+    }
+
+    pub fn encryption(&self) -> Encryption {
+        self.encryption
+    }
+
+    /// true while the index is sealed and no key has been given yet, `entries` is empty until then
+    pub fn is_locked(&self) -> bool {
+        self.sealed_index.is_some()
+    }
+
+    /// makes sure `entries` is the real index before anything reads or replaces it, unlocking
+    /// with SHOKO_KEY if nothing else has
+    pub(crate) fn ensure_index(&mut self) -> io::Result<()> {
+        if self.is_locked() {
+            self.cipher()?;
+        }
+        Ok(())
+    }
+
+    /// the fingerprint of the key this archive expects, readable without the key
+    pub fn key_id(&self) -> Option<KeyId> {
+        self.key_id
+    }
+
+    pub fn archive_id(&self) -> Option<ArchiveId> {
+        self.archive_id
+    }
+
+    pub(crate) fn next_generation(&mut self) -> u64 {
+        self.generation += 1;
+        self.generation
+    }
+
+    /// (re)reads the trailing index, an archive without a valid trailer is treated as empty
+    pub(crate) fn load_index(&mut self) -> io::Result<()> {
+        self.entries.clear();
+        self.block_cache = None;
+        self.dictionaries.clear();
+        self.active_dictionary = None;
+        self.chunks.clear();
+        let (encryption, kdf) = (self.encryption, self.kdf);
+        self.encryption = Encryption::Aes256Gcm;
+        self.kdf = KeyDerivation::Raw;
+        self.archive_id = None;
+        self.generation = 0;
+        self.seal_index = false;
+        self.sealed_index = None;
+        self.key_slots.clear();
+        self.recipients.clear();
+        self.signature = None;
+        self.key_id = None;
+        self.padding = Padding::None;
+        self.header_mac = None;
+
         let footer_data = {
-            let mut reader = ShokoReader::new(&mut file);
+            let mut reader = ShokoReader::new(&mut self.file);
             reader.get_footer_info().ok()
         };
 
         if let Some((index_start, entry_count)) = footer_data {
-            file.seek(SeekFrom::Start(index_start))?;
-            let mut reader = ShokoReader::new(&mut file);
+            self.file.seek(SeekFrom::Start(index_start))?;
+            let mut reader = ShokoReader::new(&mut self.file);
+            let header = if self.version >= 3 { reader.read_header_block()? } else { Vec::new() };
             for _ in 0..entry_count {
-                let (path, size, offset, clevel) = reader.read_index_entry()?;
-                entries.push(ShokoEntry {
-                    path,
-                    size,
-                    offset,
-                    compression_level: clevel,
-                });
+                let entry = reader.read_index_entry(self.version)?;
+                self.entries.push(entry);
             }
+            self.apply_header(&header)?;
+        }
+        // a reload after defrag keeps the key, anything else has to be unlocked again
+        if (self.encryption, self.kdf) != (encryption, kdf) {
+            self.cipher = None;
+        }
+        if self.cipher.is_some() {
+            self.check_header_mac()?;
+            self.unseal_index()?;
         }
+        Ok(())
+    }
 
-        Ok(Self { file, entries })
+    /// reads the index `load_index` had to leave sealed, once there's a key
+    pub(crate) fn unseal_index(&mut self) -> io::Result<()> {
+        let (Some(sealed), Some(cipher)) = (&self.sealed_index, &self.cipher) else {
+            return Ok(());
+        };
+        let mut inner = &cipher.unseal(sealed, &BlobBinding::Index.aad(self.archive_id.as_ref()))?[..];
+        self.sealed_index = None;
+
+        let header = read::read_header_block(&mut inner)?;
+        let mut count = [0u8; 4];
+        inner.read_exact(&mut count)?;
+        for _ in 0..u32::from_le_bytes(count) {
+            self.entries.push(read::read_index_entry(&mut inner, self.version)?);
+        }
+        self.apply_header(&header)
     }
 
-    pub(crate) fn rewrite_index(&mut self) -> io::Result<()> {
-        let index_start = if self.entries.is_empty() {
-            8
-        } else {
-            self.entries.iter()
-                .map(|e| e.offset + e.size)
-                .max()
-                .unwrap_or(8)
+    fn apply_header(&mut self, header: &[u8]) -> io::Result<()> {
+        let mut empty_seal = false;
+        let mut records = ExtReader::new(header);
+        while let Some((tag, payload)) = records.next_record()? {
+            let mut fields = FieldReader::new(payload);
+            match tag {
+                format::HDR_DICTIONARY => {
+                    let id = fields.u32()?;
+                    let blob = read_blob_fields(&mut fields)?;
+                    self.dictionaries.push(DictionarySlot { id, blob, loaded: None });
+                }
+                format::HDR_CHUNK => {
+                    let hash: ChunkHash = fields.bytes(32)?.try_into().unwrap();
+                    let blob = read_blob_fields(&mut fields)?;
+                    // the wrapped data key takes the rest, older records end with the blob
+                    let data_key = payload.get(32 + 17..).filter(|k| !k.is_empty()).map(<[u8]>::to_vec);
+                    self.chunks.insert(hash, StoredChunk { blob, data_key });
+                }
+                format::HDR_ENCRYPTION => {
+                    self.encryption = Encryption::from_id(fields.u8()?)?;
+                }
+                format::HDR_KEY_DERIVATION => {
+                    self.kdf = KeyDerivation::from_bytes(payload)?;
+                }
+                format::HDR_ARCHIVE_ID => {
+                    self.archive_id = Some(fields.bytes(16)?.try_into().unwrap());
+                    self.generation = fields.u64()?;
+                }
+                format::HDR_KEY_SLOT => {
+                    let slot = KeySlot::from_bytes(payload)?;
+                    // the sealed index repeats the key records, don't count a slot twice
+                    if !self.key_slots.contains(&slot) {
+                        self.key_slots.push(slot);
+                    }
+                }
+                format::HDR_RECIPIENT => {
+                    let stanza = RecipientStanza::from_bytes(payload)?;
+                    if !self.recipients.contains(&stanza) {
+                        self.recipients.push(stanza);
+                    }
+                }
+                format::HDR_PADDING => {
+                    self.padding = Padding::from_bytes(payload)?;
+                }
+                format::HDR_KEY_ID => {
+                    self.key_id = Some(fields.bytes(8)?.try_into().unwrap());
+                }
+                format::HDR_SIGNATURE => {
+                    self.signature = Some(ArchiveSignature::from_bytes(payload)?);
+                }
+                format::HDR_HEADER_MAC => {
+                    self.header_mac = Some(fields.bytes(32)?.try_into().unwrap());
+                }
+                format::HDR_SEALED_INDEX => {
+                    self.seal_index = true;
+                    // empty when it was written before there was a key
+                    if payload.is_empty() {
+                        empty_seal = true;
+                    } else {
+                        self.sealed_index = Some(payload.to_vec());
+                    }
+                }
+                _ => {}
+            }
+        }
+        // a recorded key id means a key was there to seal with, so an empty index was swapped in
+        if empty_seal && self.key_id.is_some() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "The sealed index is missing, the archive has been tampered with"));
+        }
+        // the newest dictionary is the one new LZ entries use
+        self.active_dictionary = self.dictionaries.iter().map(|d| d.id).max();
+        Ok(())
+    }
+
+    /// what it takes to get at the key, the only records a sealed index leaves in the clear
+    fn key_records(&self, header: &mut ExtWriter) {
+        self.mac_covered_records(header);
+        if let Some(mac) = self.header_mac {
+            header.record(format::HDR_HEADER_MAC, &mac);
+        }
+        // in the clear even when the index is sealed, checking who signed shouldn't need the key
+        if let Some(signature) = self.signature {
+            header.record(format::HDR_SIGNATURE, &signature.to_bytes());
+        }
+    }
+
+    /// the key records minus the MAC and the signature, which has its own
+    fn mac_covered_records(&self, header: &mut ExtWriter) {
+        header.record(format::HDR_ENCRYPTION, &[self.encryption.id()]);
+        if self.encryption.is_encrypted() {
+            header.record(format::HDR_KEY_DERIVATION, &self.kdf.to_bytes());
+            if let Some(id) = self.key_id {
+                header.record(format::HDR_KEY_ID, &id);
+            }
+            // readers have to know to strip it, so it stays in the clear with the key records
+            if self.padding != Padding::None {
+                header.record(format::HDR_PADDING, &self.padding.to_bytes());
+            }
+        }
+        if let Some(id) = self.archive_id {
+            let mut payload = id.to_vec();
+            payload.extend_from_slice(&self.generation.to_le_bytes());
+            header.record(format::HDR_ARCHIVE_ID, &payload);
+        }
+        for slot in &self.key_slots {
+            header.record(format::HDR_KEY_SLOT, &slot.to_bytes());
+        }
+        for stanza in &self.recipients {
+            header.record(format::HDR_RECIPIENT, &stanza.to_bytes());
+        }
+    }
+
+    /// what the header MAC is over: the covered key records, then whether the index is sealed
+    fn mac_records(&self) -> Vec<u8> {
+        let mut header = ExtWriter::default();
+        self.mac_covered_records(&mut header);
+        let mut records = header.into_bytes();
+        records.push(self.seal_index as u8);
+        records
+    }
+
+    /// the MAC the next index write should carry. a handle without the key keeps the one it
+    /// read, everything that changes the covered records needs the key anyway
+    fn refresh_header_mac(&mut self) {
+        if !self.encryption.is_encrypted() {
+            self.header_mac = None;
+        } else if let Some(cipher) = &self.cipher {
+            self.header_mac = cipher.header_mac(&self.mac_records());
+        }
+    }
+
+    /// turns away a header whose key records were changed by someone without the key. archives
+    /// from before the MAC don't have one, but they don't record a key id either
+    pub(crate) fn check_header_mac(&self) -> io::Result<()> {
+        let Some(cipher) = &self.cipher else {
+            return Ok(());
+        };
+        let intact = match &self.header_mac {
+            Some(mac) => cipher.header_mac_matches(&self.mac_records(), mac),
+            None => self.key_id.is_none(),
+        };
+        if !intact {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "The archive header has been tampered with (its MAC doesn't match)"));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn header_block(&self) -> Vec<u8> {
+        let mut header = ExtWriter::default();
+        self.key_records(&mut header);
+        for dict in &self.dictionaries {
+            let mut payload = dict.id.to_le_bytes().to_vec();
+            put_blob_fields(&mut payload, dict.blob);
+            header.record(format::HDR_DICTIONARY, &payload);
+        }
+        for (hash, chunk) in &self.chunks {
+            let mut payload = hash.to_vec();
+            put_blob_fields(&mut payload, chunk.blob);
+            payload.extend(chunk.data_key.iter().flatten());
+            header.record(format::HDR_CHUNK, &payload);
+        }
+        header.into_bytes()
+    }
+
+    /// the header of a sealed index: the key records, then the real header block and the
+    /// entries sealed as one blob. the footer's entry count stays 0 so there's nothing to count
+    fn sealed_header_block(&mut self) -> io::Result<Vec<u8>> {
+        let mut header = ExtWriter::default();
+        self.key_records(&mut header);
+        // new archives write their empty index before there's a key, and there's nothing to hide.
+        // once there's a key even an empty index is sealed, so the empty form can't stand in for it
+        let empty = self.entries.is_empty() && self.dictionaries.is_empty() && self.chunks.is_empty();
+        if empty && self.cipher.is_none() {
+            header.record(format::HDR_SEALED_INDEX, &[]);
+            return Ok(header.into_bytes());
+        }
+
+        let block = self.header_block();
+        let mut inner = (block.len() as u32).to_le_bytes().to_vec();
+        inner.extend_from_slice(&block);
+        inner.extend_from_slice(&(self.entries.len() as u32).to_le_bytes());
+        for entry in &self.entries {
+            inner.extend(write::index_entry_bytes(entry));
+        }
+        let sealed = self.cipher()?.seal(&inner, &BlobBinding::Index.aad(self.archive_id.as_ref()))?;
+        header.record(format::HDR_SEALED_INDEX, &sealed);
+        Ok(header.into_bytes())
+    }
+
+    /// every blob something in the index still points at
+    pub(crate) fn referenced_blobs(&self) -> impl Iterator<Item = BlobRef> + '_ {
+        self.entries.iter()
+            .flat_map(|e| e.delta_chain.iter().copied().chain(std::iter::once(e.blob())))
+            .chain(self.dictionaries.iter().map(|d| d.blob))
+            .chain(self.chunks.values().map(|c| c.blob))
+    }
+
+    /// first byte past the last referenced blob, which is also where the index starts
+    pub(crate) fn data_end(&self) -> u64 {
+        self.referenced_blobs()
+            .map(|b| b.offset + b.size)
+            .max()
+            .unwrap_or(HEADER_LEN)
+    }
+
+    /// what worker threads need to seal blobs the same way this archive would
+    pub(crate) fn sealer(&mut self) -> io::Result<Sealer> {
+        let dict = match self.active_dictionary {
+            Some(id) => Some(self.dictionary(id)?),
+            None => None,
+        };
+        Ok(Sealer { dict, cipher: self.cipher()?, archive_id: self.archive_id, padding: self.padding })
+    }
+
+    /// seals `data` and writes it at the end of the data region. nothing references it yet,
+    /// so the caller has to hook it into the index before the next `data_end`
+    pub(crate) fn append_blob(&mut self, data: &[u8], clevel: u8, binding: BlobBinding) -> io::Result<BlobRef> {
+        let sealer = self.sealer()?;
+        self.append_blob_with(&sealer, data, clevel, binding)
+    }
+
+    /// `append_blob` sealed by `sealer`, for blobs under an entry's own data key
+    pub(crate) fn append_blob_with(&mut self, sealer: &Sealer, data: &[u8], clevel: u8, binding: BlobBinding) -> io::Result<BlobRef> {
+        let sealed = sealer.seal(data, clevel, binding)?;
+        let offset = self.data_end();
+        self.block_cache = None;
+
+        self.file.seek(SeekFrom::Start(offset))?;
+        let size = ShokoWriter::new(&mut self.file).write_raw(&sealed)?;
+        Ok(BlobRef { offset, size, compression_level: clevel })
+    }
+
+    /// reads, decrypts and decodes one blob, pulling in its dictionary if it has one.
+    /// `binding` has to be what it was sealed under or it won't open
+    pub(crate) fn read_blob_ref(&mut self, blob: BlobRef, binding: BlobBinding) -> io::Result<Vec<u8>> {
+        let cipher = self.cipher()?;
+        self.read_blob_with(blob, binding, cipher)
+    }
+
+    /// `read_blob_ref` opened with `cipher`, for blobs under an entry's own data key
+    pub(crate) fn read_blob_with(&mut self, blob: BlobRef, binding: BlobBinding, cipher: Cipher) -> io::Result<Vec<u8>> {
+        let aad = binding.aad(self.archive_id.as_ref());
+        // decrypted but still compressed, nobody gets to see it so it's wiped right after decoding
+        let payload = Zeroizing::new(ShokoReader::new(&mut self.file).with_cipher(cipher).read_sealed(blob.offset, blob.size, &aad)?);
+        let payload = self.padding.unpad(&payload)?;
+        let dict = match codec::dictionary_id(payload, blob.compression_level) {
+            Some(id) => Some(self.dictionary(id)?),
+            None => None,
         };
+        let (max_out, kind) = self.blob_bound(blob.size);
+        codec::decode_limited(payload, blob.compression_level, dict.as_ref(), max_out)
+            .map_err(|e| match (LimitExceeded::from_io(&e), kind) {
+                (Some(_), LimitKind::Ratio) => {
+                    LimitExceeded::new(LimitKind::Ratio, self.limits.max_ratio.unwrap_or(0)).into()
+                }
+                _ => e,
+            })
+    }
+
+    pub(crate) fn rewrite_index(&mut self) -> io::Result<()> {
+        // an index we haven't read can't be written back
+        self.ensure_index()?;
+        if self.version < CURRENT_VERSION {
+            // blob layout is the same across versions, only the index grew, so upgrading is just the magic
+            self.file.seek(SeekFrom::Start(0))?;
+            self.file.write_all(MAGIC_V3)?;
+            self.version = CURRENT_VERSION;
+        }
+
+        // the region past data_end gets reused, so a cached block could go stale
+        self.block_cache = None;
+        let index_start = self.data_end();
+        self.refresh_header_mac();
 
         self.file.seek(SeekFrom::Start(index_start))?;
-        
+
+        let (header, entries) = if self.seal_index {
+            (self.sealed_header_block()?, &[][..])
+        } else {
+            (self.header_block(), &self.entries[..])
+        };
         let mut writer = ShokoWriter::new(&mut self.file);
-        for entry in &self.entries {
-            writer.write_index_entry(
-                &entry.path,
-                entry.size,
-                entry.offset,
-                entry.compression_level,
-            )?;
+        writer.write_header_block(&header)?;
+        for entry in entries {
+            writer.write_index_entry(entry)?;
         }
 
-        let entry_count = self.entries.len() as u32;
-        writer.finalize(index_start, entry_count)?;
-        
+        writer.finalize(index_start, entries.len() as u32)?;
+
         let final_size = self.file.stream_position()?;
         self.file.set_len(final_size)?;
         Ok(())
     }
 
     pub fn write_file_direct(&mut self, internal_path: &str, content: &[u8], clevel: u8) -> io::Result<()> {
-        let data_offset = if self.entries.is_empty() {
-            8
+        self.ensure_index()?;
+        let delta = if self.max_delta_chain > 0 {
+            self.prepare_delta(internal_path, content)?
         } else {
-            self.entries.iter()
-                .map(|e| e.offset + e.size)
-                .max()
-                .unwrap_or(8)
+            None
         };
-
-        self.file.seek(SeekFrom::Start(data_offset))?;
-        
-        let compressed_size = {
-            let mut writer = ShokoWriter::new(&mut self.file);
-            writer.write_blob(content, clevel)?
+        let sealer = self.sealer()?;
+        let (payload, delta_chain, delta_generations, sealer, data_key) = match delta {
+            // a new link stays under the chain's key, the chain is read back with one key
+            Some(d) => {
+                let cipher = self.data_cipher(d.data_key.as_deref())?;
+                (d.payload, d.chain, d.generations, Sealer { cipher, ..sealer }, d.data_key)
+            }
+            None => {
+                let (sealer, data_key) = sealer.with_data_key()?;
+                (content.to_vec(), Vec::new(), Vec::new(), sealer, data_key)
+            }
         };
 
+        let generation = self.next_generation();
+        let blob = self.append_blob_with(&sealer, &payload, clevel, BlobBinding::Entry { path: internal_path, generation })?;
+
         self.entries.retain(|e| e.path != internal_path);
         self.entries.push(ShokoEntry {
             path: internal_path.to_string(),
-            size: compressed_size,
-            offset: data_offset,
+            size: blob.size,
+            offset: blob.offset,
             compression_level: clevel,
+            original_size: Some(content.len() as u64),
+            delta_chain,
+            generation,
+            delta_generations,
+            data_key,
+            ..Default::default()
         });
 
         self.rewrite_index()
     }
 
     pub fn extract_file(&mut self, internal_path: &str) -> io::Result<Vec<u8>> {
+        let data = self.read_entry(internal_path)?;
+        self.check_entry_len(data.len() as u64)?;
+        self.extracted += data.len() as u64;
+        Ok(data)
+    }
+
+    /// `extract_file` for secrets: the plaintext ends up in mlocked memory that's wiped on drop,
+    /// and the buffers it passed through on the way are wiped too
+    pub fn extract_secure(&mut self, internal_path: &str) -> io::Result<SecureBuf> {
+        let data = Zeroizing::new(self.read_entry(internal_path)?);
+        self.check_entry_len(data.len() as u64)?;
+        self.extracted += data.len() as u64;
+        SecureBuf::from_slice(&data)
+    }
+
+    /// `extract_file` without counting towards `max_total_size`, for reads the library
+    /// does on its own behalf (defrag, delta bases, stats)
+    pub(crate) fn read_entry(&mut self, internal_path: &str) -> io::Result<Vec<u8>> {
+        self.ensure_index()?;
         let entry = self.entries.iter()
             .find(|e| e.path == internal_path)
             .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "File not in archive"))?;
 
-        let mut reader = ShokoReader::new(&mut self.file);
-        reader.read_blob(entry.offset, entry.size, entry.compression_level)
+        // the recorded size can lie, the decoders check again, but an honest one fails fast
+        if let (Some(size), Some(max)) = (entry.original_size, self.limits.max_entry_size) {
+            if size > max {
+                return Err(LimitExceeded::new(LimitKind::EntrySize, max).into());
+            }
+        }
+
+        let data_key = entry.data_key.clone();
+        if let Some(slice) = entry.solid {
+            let (block, generation) = (entry.blob(), entry.generation);
+            return self.read_solid(block, slice, generation, data_key.as_deref());
+        }
+        if let Some(hashes) = entry.chunks.clone() {
+            return self.read_chunked(&hashes);
+        }
+
+        let mut blobs = entry.delta_chain.clone();
+        blobs.push(entry.blob());
+        // entries from before generations were recorded have none, their archive has no id either
+        let mut generations = entry.delta_generations.clone();
+        generations.push(entry.generation);
+        generations.resize(blobs.len(), 0);
+        let path = entry.path.clone();
+        let bound = |i: usize| BlobBinding::Entry { path: &path, generation: generations[i] };
+
+        let cipher = self.data_cipher(data_key.as_deref())?;
+        let mut data = self.read_blob_with(blobs[0], bound(0), cipher.clone())?;
+        for (i, blob) in blobs.iter().enumerate().skip(1) {
+            let patch = self.read_blob_with(*blob, bound(i), cipher.clone())?;
+            let max_out = self.limits.max_entry_size.unwrap_or(u64::MAX);
+            data = delta::apply_limited(&data, &patch, max_out)?;
+        }
+        Ok(data)
     }
 
     pub fn defrag(&mut self) -> io::Result<()> {
-        let temp_path = ".shoko_defrag.tmp";
-        
-        // Fix: Clone metadata needed for extraction to avoid borrow conflict
-        let entry_metadata: Vec<(String, u8)> = self.entries.iter()
-            .map(|e| (e.path.clone(), e.compression_level))
-            .collect();
+        // next to the archive rather than in the cwd, so two defrags never share a temp file
+        let temp_path = format!("{}.defrag", self.path.display());
 
         {
-            let mut new_archive = ShokoArchive::create(temp_path)?;
-
-            for (path, clevel) in entry_metadata {
-                let data = self.extract_file(&path)?;
-                new_archive.write_file_direct(&path, &data, clevel)?;
+            let mut new_archive = self.create_sibling(&temp_path)?;
+            // LZ entries get recompressed against the active dictionary, older ones are dropped
+            if let Some(id) = self.active_dictionary {
+                let dict = self.dictionary(id)?;
+                new_archive.add_dictionary(&dict.data)?;
             }
+            self.copy_entries_into(&mut new_archive)?;
         }
 
-        let mut temp_file = File::open(temp_path)?;
+        let mut temp_file = File::open(&temp_path)?;
         self.file.set_len(0)?;
         self.file.seek(SeekFrom::Start(0))?;
         io::copy(&mut temp_file, &mut self.file)?;
-        let _ = std::fs::remove_file(temp_path);
-        
-        let mut reader = ShokoReader::new(&mut self.file);
-        if let Ok((index_start, entry_count)) = reader.get_footer_info() {
-            self.file.seek(SeekFrom::Start(index_start))?;
-            self.entries.clear();
-            let mut reader_inner = ShokoReader::new(&mut self.file);
-            for _ in 0..entry_count {
-                let (path, size, offset, clevel) = reader_inner.read_index_entry()?;
-                self.entries.push(ShokoEntry { path, size, offset, compression_level: clevel });
-            }
-        }
+        let _ = std::fs::remove_file(&temp_path);
 
-        Ok(())
+        self.version = CURRENT_VERSION;
+        self.load_index()
     }
 }
+
+fn read_blob_fields(fields: &mut FieldReader) -> io::Result<BlobRef> {
+    Ok(BlobRef {
+        offset: fields.u64()?,
+        size: fields.u64()?,
+        compression_level: fields.u8()?,
+    })
+}
+
+fn put_blob_fields(out: &mut Vec<u8>, blob: BlobRef) {
+    out.extend_from_slice(&blob.offset.to_le_bytes());
+    out.extend_from_slice(&blob.size.to_le_bytes());
+    out.push(blob.compression_level);
+}
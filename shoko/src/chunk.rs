@@ -0,0 +1,168 @@
+// content-defined chunking: entries are cut into chunks where a rolling gear hash says so, and
+// every chunk is stored once per archive under its SHA-256. an insert or edit only moves the
+// boundaries around it, so a mostly unchanged file costs only the chunks that did change.
+//
+// each chunk has a data key of its own (see datakey.rs), kept in its header record. once no
+// entry has a chunk any more, deleting drops the record and the key with it
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+use sha2::{Digest, Sha256};
+
+use crate::archive::{BlobRef, ShokoArchive};
+use crate::encrypt::BlobBinding;
+use crate::limits::{LimitExceeded, LimitKind};
+use crate::pipeline::{walk_parallel, PackItem, PackUnit, UnitKind};
+
+pub type ChunkHash = [u8; 32];
+
+/// one chunk of the store
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct StoredChunk {
+    pub blob: BlobRef,
+    /// wrapped by the archive key, None for chunks from before they had their own
+    pub data_key: Option<Vec<u8>>,
+}
+
+pub const MIN_CHUNK: usize = 2 * 1024;
+pub const AVG_CHUNK: usize = 8 * 1024;
+pub const MAX_CHUNK: usize = 64 * 1024;
+
+/// normalized chunking: a stricter cut condition below the average size and a looser one above
+/// it keeps chunk sizes bunched around AVG_CHUNK. the gear hash shifts left, so only its top bits
+/// depend on the whole window and those are the ones we test
+const CUT_BITS_SMALL: u32 = AVG_CHUNK.trailing_zeros() + 2;
+const CUT_BITS_LARGE: u32 = AVG_CHUNK.trailing_zeros() - 2;
+
+const GEAR: [u64; 256] = gear_table();
+
+/// splitmix64 over 0..256, fixed forever since chunk boundaries (and so dedup) depend on it
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x5348_4f4b_4f43_4443;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+/// length of the chunk at the start of `data`
+fn next_cut(data: &[u8]) -> usize {
+    if data.len() <= MIN_CHUNK {
+        return data.len();
+    }
+    let end = data.len().min(MAX_CHUNK);
+    let normal = end.min(AVG_CHUNK);
+
+    let mut fp: u64 = 0;
+    for (i, &b) in data.iter().enumerate().take(end).skip(MIN_CHUNK) {
+        fp = (fp << 1).wrapping_add(GEAR[b as usize]);
+        let bits = if i < normal { CUT_BITS_SMALL } else { CUT_BITS_LARGE };
+        if fp >> (64 - bits) == 0 {
+            return i + 1;
+        }
+    }
+    end
+}
+
+/// cuts `data` into content-defined chunks, an empty input gives no chunks at all
+pub fn split(data: &[u8]) -> Vec<&[u8]> {
+    let mut chunks = Vec::new();
+    let mut rest = data;
+    while !rest.is_empty() {
+        let (head, tail) = rest.split_at(next_cut(rest));
+        chunks.push(head);
+        rest = tail;
+    }
+    chunks
+}
+
+pub fn hash(data: &[u8]) -> ChunkHash {
+    Sha256::digest(data).into()
+}
+
+pub fn to_hex(hash: &ChunkHash) -> String {
+    hash.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+impl ShokoArchive {
+    /// stores `content` as a list of chunks, only chunks the archive doesn't have yet hit the disk
+    pub fn write_file_chunked(&mut self, internal_path: &str, content: &[u8], clevel: u8) -> io::Result<()> {
+        let unit = PackUnit { members: vec![PackItem::bytes(internal_path, content.to_vec())], kind: UnitKind::Chunked };
+        self.write_units(vec![unit], clevel, 1).map(|_| ())
+    }
+
+    /// bulk version of `write_file_chunked`, chunking and sealing run on `jobs` workers
+    pub fn write_files_chunked(&mut self, items: Vec<PackItem>, clevel: u8, jobs: usize) -> io::Result<Vec<String>> {
+        let units = items.into_iter()
+            .map(|item| PackUnit { members: vec![item], kind: UnitKind::Chunked })
+            .collect();
+        self.write_units(units, clevel, jobs)
+    }
+
+    /// `pack_dir` with every file deduplicated through the chunk store
+    pub fn pack_dir_chunked(&mut self, root: &Path, clevel: u8, jobs: usize) -> io::Result<Vec<String>> {
+        let items = walk_parallel(root, jobs)?
+            .into_iter()
+            .map(|(path, source)| PackItem::file(path, source))
+            .collect();
+        self.write_files_chunked(items, clevel, jobs)
+    }
+
+    /// how many entry references each stored chunk has, unreferenced chunks show up with 0
+    pub fn chunk_refcounts(&self) -> HashMap<ChunkHash, usize> {
+        let mut counts: HashMap<ChunkHash, usize> = self.chunks.keys().map(|h| (*h, 0)).collect();
+        for hash in self.entries.iter().filter_map(|e| e.chunks.as_ref()).flatten() {
+            *counts.entry(*hash).or_default() += 1;
+        }
+        counts
+    }
+
+    /// drops chunks nothing references anymore from the store and returns how many went.
+    /// the bytes are only given back once they're at the tail of the file or after a `defrag`
+    pub fn collect_garbage(&mut self) -> io::Result<usize> {
+        let dead = self.drop_dead_chunks();
+        if dead > 0 {
+            self.rewrite_index()?;
+        }
+        Ok(dead)
+    }
+
+    /// `collect_garbage` without writing the index
+    pub(crate) fn drop_dead_chunks(&mut self) -> usize {
+        let dead: Vec<ChunkHash> = self.chunk_refcounts()
+            .into_iter()
+            .filter(|(_, refs)| *refs == 0)
+            .map(|(h, _)| h)
+            .collect();
+        for hash in &dead {
+            self.chunks.remove(hash);
+        }
+        dead.len()
+    }
+
+    pub(crate) fn read_chunked(&mut self, hashes: &[ChunkHash]) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        for hash in hashes {
+            let chunk = self.chunks.get(hash).cloned().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("Missing chunk {}", to_hex(hash)))
+            })?;
+            let cipher = self.data_cipher(chunk.data_key.as_deref())?;
+            data.extend(self.read_blob_with(chunk.blob, BlobBinding::Chunk(hash), cipher)?);
+            // a short list can name the same chunk over and over, check as it grows
+            if let Some(max) = self.limits.max_entry_size {
+                if data.len() as u64 > max {
+                    return Err(LimitExceeded::new(LimitKind::EntrySize, max).into());
+                }
+            }
+        }
+        Ok(data)
+    }
+}
@@ -0,0 +1,96 @@
+// the compression_level byte doubles as the codec id: 0 is stored, 1-9 is the RLE engine,
+// and LZ_FLAG | level is the dictionary-capable LZ codec
+use std::io;
+
+use crate::compress;
+use crate::decompress;
+use crate::dict::Dictionary;
+use crate::limits::{LimitExceeded, LimitKind};
+use crate::lz;
+
+pub const LZ_FLAG: u8 = 0x40;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Stored,
+    Rle,
+    Lz,
+}
+
+impl Codec {
+    pub fn of(clevel: u8) -> Self {
+        if clevel & LZ_FLAG != 0 {
+            Codec::Lz
+        } else if clevel > 0 {
+            Codec::Rle
+        } else {
+            Codec::Stored
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Codec::Stored => "stored",
+            Codec::Rle => "rle",
+            Codec::Lz => "lz",
+        }
+    }
+}
+
+/// the compression_level byte that selects the LZ codec at `level` (1-9)
+pub fn lz(level: u8) -> u8 {
+    LZ_FLAG | level.clamp(1, 9)
+}
+
+/// LZ payloads start with the id of the dictionary they were made with, 0 for none
+pub fn dictionary_id(payload: &[u8], clevel: u8) -> Option<u32> {
+    if Codec::of(clevel) != Codec::Lz || payload.len() < 4 {
+        return None;
+    }
+    match u32::from_le_bytes(payload[..4].try_into().unwrap()) {
+        0 => None,
+        id => Some(id),
+    }
+}
+
+pub fn encode(data: &[u8], clevel: u8, dict: Option<&Dictionary>) -> Vec<u8> {
+    match Codec::of(clevel) {
+        Codec::Stored => data.to_vec(),
+        Codec::Rle => compress::compress(data, clevel),
+        Codec::Lz => {
+            let (id, bytes) = dict.map(|d| (d.id, d.data.as_slice())).unwrap_or((0, &[]));
+            let mut out = id.to_le_bytes().to_vec();
+            out.extend(lz::compress(data, bytes, clevel & !LZ_FLAG));
+            out
+        }
+    }
+}
+
+/// `dict` has to be the dictionary named by `dictionary_id`, if there is one
+pub fn decode(payload: &[u8], clevel: u8, dict: Option<&Dictionary>) -> io::Result<Vec<u8>> {
+    decode_limited(payload, clevel, dict, u64::MAX)
+}
+
+/// `decode` that fails with `LimitExceeded` instead of producing more than `max_out` bytes
+pub fn decode_limited(payload: &[u8], clevel: u8, dict: Option<&Dictionary>, max_out: u64) -> io::Result<Vec<u8>> {
+    match Codec::of(clevel) {
+        Codec::Stored if payload.len() as u64 > max_out => {
+            Err(LimitExceeded::new(LimitKind::EntrySize, max_out).into())
+        }
+        Codec::Stored => Ok(payload.to_vec()),
+        Codec::Rle => decompress::decompress_limited(payload, max_out),
+        Codec::Lz => {
+            if payload.len() < 4 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "Malformed Shoko LZ stream: missing header"));
+            }
+            let dict_bytes = match (dictionary_id(payload, clevel), dict) {
+                (None, _) => &[][..],
+                (Some(id), Some(d)) if d.id == id => d.data.as_slice(),
+                (Some(id), _) => {
+                    return Err(io::Error::new(io::ErrorKind::NotFound, format!("Compression dictionary {} not available", id)));
+                }
+            };
+            lz::decompress_limited(&payload[4..], dict_bytes, max_out)
+        }
+    }
+}
@@ -1,52 +1,183 @@
+use std::io::{self, Write};
+
+/// the furthest `encode_step` looks past its start position, keep at least this much buffered
+/// and a streamed encode makes exactly the same decisions as a one-shot `compress`
+const MAX_LOOKAHEAD: usize = 256;
+
+/// how much input `RleEncoder` holds before encoding, on top of the lookahead tail
+const STREAM_CHUNK: usize = 64 * 1024;
+
 pub fn compress(data: &[u8], clevel: u8) -> Vec<u8> {
     if data.is_empty() {
         return Vec::new();
     }
 
-    let mut compressed = Vec::new();
+    // room for the worst case of incompressible input plus a little, so the hot loop
+    // almost never has to grow the buffer
+    let mut compressed = Vec::with_capacity(data.len() + data.len() / 127 + 16);
     let mut i = 0;
+    let threshold = run_threshold(clevel);
+
+    while i < data.len() {
+        i = encode_step(data, i, threshold, &mut compressed);
+    }
+    compressed
+}
 
-    let threshold = match clevel {
+fn run_threshold(clevel: u8) -> usize {
+    match clevel {
         1..=3 => 4,
         4..=6 => 3,
         7..=9 => 2,
         _ => 3,
-    };
+    }
+}
 
-    while i < data.len() {
-        let mut run_len = 1;
-        while i + run_len < data.len() && 
-              data[i + run_len] == data[i] && 
-              run_len < 255 {
-            run_len += 1;
-        }
-
-        if run_len >= threshold {
-            compressed.push(0x00); 
-            compressed.push(run_len as u8);
-            compressed.push(data[i]);
-            i += run_len;
-        } else {
-            let mut literal_end = i;
-            while literal_end < data.len() && 
-                  (literal_end + 1 >= data.len() || data[literal_end] != data[literal_end + 1]) &&
-                  (literal_end - i) < 254 {
-                literal_end += 1;
-            }
-            
-            let lit_len = literal_end - i;
-            if lit_len > 0 {
-                compressed.push(0x01);
-                compressed.push(lit_len as u8);
-                compressed.extend_from_slice(&data[i..literal_end]);
-                i = literal_end;
-            } else {
-                compressed.push(0x01);
-                compressed.push(1);
-                compressed.push(data[i]);
-                i += 1;
-            }
+const LO_BITS: u64 = 0x0101_0101_0101_0101;
+const HI_BITS: u64 = 0x8080_8080_8080_8080;
+
+fn load_word(data: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(data[at..at + 8].try_into().unwrap())
+}
+
+/// index of the lowest zero byte in `v`, if there is one. the borrow trick can flag bytes above
+/// a real zero too but never below one, so the lowest flag is exact
+fn first_zero_byte(v: u64) -> Option<usize> {
+    let found = v.wrapping_sub(LO_BITS) & !v & HI_BITS;
+    (found != 0).then(|| found.trailing_zeros() as usize / 8)
+}
+
+/// how many bytes starting at `i` equal data[i], capped at 255. compares 8 bytes at a time
+fn run_length(data: &[u8], i: usize) -> usize {
+    let end = data.len().min(i + 255);
+    let pattern = LO_BITS * data[i] as u64;
+    let mut p = i + 1;
+    while p + 8 <= end {
+        let diff = load_word(data, p) ^ pattern;
+        if diff != 0 {
+            return p + diff.trailing_zeros() as usize / 8 - i;
+        }
+        p += 8;
+    }
+    while p < end && data[p] == data[i] {
+        p += 1;
+    }
+    p - i
+}
+
+/// where a literal starting at `i` ends: at the first byte that equals the one after it,
+/// after 254 bytes, or at the end of the data, whichever comes first
+fn literal_end(data: &[u8], i: usize) -> usize {
+    let limit = data.len().min(i + 254);
+    // a pair needs its second byte, so the last byte of the data can never start one
+    let scan_end = limit.min(data.len() - 1);
+    let mut p = i;
+    // each word compares bytes p..p+8 with p+1..p+9, a zero byte in the xor marks a pair
+    while p + 8 <= scan_end {
+        if let Some(at) = first_zero_byte(load_word(data, p) ^ load_word(data, p + 1)) {
+            return p + at;
+        }
+        p += 8;
+    }
+    while p < scan_end {
+        if data[p] == data[p + 1] {
+            return p;
+        }
+        p += 1;
+    }
+    limit
+}
+
+/// emits one run or literal record starting at `i` and returns where the next one starts
+fn encode_step(data: &[u8], i: usize, threshold: usize, compressed: &mut Vec<u8>) -> usize {
+    let run_len = run_length(data, i);
+
+    if run_len >= threshold {
+        compressed.extend_from_slice(&[0x00, run_len as u8, data[i]]);
+        return i + run_len;
+    }
+
+    // a run that's too short to encode still stops a literal, it goes out as a single byte
+    let end = literal_end(data, i).max(i + 1);
+    compressed.push(0x01);
+    compressed.push((end - i) as u8);
+    compressed.extend_from_slice(&data[i..end]);
+    end
+}
+
+/// streaming counterpart of `compress`, produces the same byte stream in bounded memory.
+/// call `finish` when done, dropping the encoder flushes on a best-effort basis and eats errors
+pub struct RleEncoder<W: Write> {
+    inner: Option<W>,
+    pending: Vec<u8>,
+    out: Vec<u8>,
+    threshold: usize,
+}
+
+impl<W: Write> RleEncoder<W> {
+    pub fn new(inner: W, clevel: u8) -> Self {
+        Self {
+            inner: Some(inner),
+            pending: Vec::with_capacity(STREAM_CHUNK + MAX_LOOKAHEAD),
+            out: Vec::new(),
+            threshold: run_threshold(clevel),
+        }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        self.inner.as_ref().expect("encoder already finished")
+    }
+
+    /// encodes whatever is still buffered and hands back the inner writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.encode_pending(true)?;
+        let mut inner = self.inner.take().expect("encoder already finished");
+        inner.flush()?;
+        Ok(inner)
+    }
+
+    fn encode_pending(&mut self, last: bool) -> io::Result<()> {
+        let keep = if last { 0 } else { MAX_LOOKAHEAD };
+        let mut i = 0;
+        while i < self.pending.len() && self.pending.len() - i > keep {
+            i = encode_step(&self.pending, i, self.threshold, &mut self.out);
+        }
+        self.pending.drain(..i);
+
+        if let Some(inner) = self.inner.as_mut() {
+            inner.write_all(&self.out)?;
+        }
+        self.out.clear();
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for RleEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let room = STREAM_CHUNK + MAX_LOOKAHEAD - self.pending.len();
+        let take = buf.len().min(room);
+        self.pending.extend_from_slice(&buf[..take]);
+
+        if self.pending.len() == STREAM_CHUNK + MAX_LOOKAHEAD {
+            self.encode_pending(false)?;
+        }
+        Ok(take)
+    }
+
+    /// only pushes out records that are already final, a run can't be cut short by a flush
+    fn flush(&mut self) -> io::Result<()> {
+        self.encode_pending(false)?;
+        match self.inner.as_mut() {
+            Some(inner) => inner.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<W: Write> Drop for RleEncoder<W> {
+    fn drop(&mut self) {
+        if self.inner.is_some() {
+            let _ = self.encode_pending(true);
         }
     }
-    compressed
 }
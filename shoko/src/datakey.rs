@@ -0,0 +1,85 @@
+// per entry data keys, for crypto-shredding. every entry is sealed under a random key of its
+// own, which is wrapped by the archive key and kept in the entry's index record. deleting the
+// entry throws away the only copy of that key, so its ciphertext (still in the file until the
+// next defrag) can't be opened any more, not even by someone holding the archive key.
+//
+// a new delta version stays under its chain's key since the chain is read back as one. solid
+// members share their block's key, so deleting one seals the rest into a new block under a new
+// key. chunks have a key each, kept in the chunk store and dropped with the last entry that has
+// the chunk
+use std::io;
+
+use hkdf::Hkdf;
+use rand::RngCore;
+use sha2::Sha256;
+use zeroize::Zeroizing;
+
+use crate::archive::ShokoArchive;
+use crate::encrypt::{ArchiveId, BlobBinding, Cipher};
+use crate::shadow::SecretKey;
+use crate::write::Sealer;
+
+const REKEY_INFO: &[u8] = b"shoko rekeyed data key v1";
+
+/// a fresh random data key under the same scheme as `cipher` (the archive's), and the key
+/// wrapped by it. None for unencrypted archives, there's nothing to shred
+pub(crate) fn new_data_key(cipher: &Cipher, archive_id: Option<&ArchiveId>) -> io::Result<Option<(Cipher, Vec<u8>)>> {
+    if matches!(cipher, Cipher::Plain) {
+        return Ok(None);
+    }
+    let key = SecretKey::new_with(|key| {
+        rand::rng().fill_bytes(key);
+        Ok(())
+    })?;
+    let data = cipher.with_key(key);
+    Ok(Some((data.clone(), wrap_data_key(cipher, &data, archive_id)?)))
+}
+
+/// `data`'s key sealed under `cipher`, what goes in the index
+pub(crate) fn wrap_data_key(cipher: &Cipher, data: &Cipher, archive_id: Option<&ArchiveId>) -> io::Result<Vec<u8>> {
+    let key = data.key().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "An unencrypted entry has no data key"))?;
+    cipher.seal(&key[..], &BlobBinding::DataKey.aad(archive_id))
+}
+
+/// the data key an entry gets when the archive is rekeyed under `new`. fresh, since the old one
+/// could be unwrapped by whoever had the old key, but the same on every run of one rekey so an
+/// interrupted one can carry on. `salt` is random per rekey and only kept sealed in its journal
+pub(crate) fn rekeyed_data_key(new: &Cipher, salt: &[u8], old_wrapped: &[u8]) -> io::Result<Cipher> {
+    let key = new.key().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Unencrypted archives have no data keys"))?;
+    let mut info = REKEY_INFO.to_vec();
+    info.extend_from_slice(old_wrapped);
+    let data_key = SecretKey::new_with(|data_key| {
+        Hkdf::<Sha256>::new(Some(salt), &key[..])
+            .expand(&info, data_key)
+            .map_err(|e| io::Error::other(e.to_string()))
+    })?;
+    Ok(new.with_key(data_key))
+}
+
+pub(crate) fn unwrap_data_key(cipher: &Cipher, wrapped: &[u8], archive_id: Option<&ArchiveId>) -> io::Result<Cipher> {
+    let key = Zeroizing::new(cipher.unseal(wrapped, &BlobBinding::DataKey.aad(archive_id)).map_err(|e| {
+        io::Error::new(e.kind(), format!("Entry data key doesn't open: {}", e))
+    })?);
+    Ok(cipher.with_key(SecretKey::from_slice(&key)?))
+}
+
+impl Sealer {
+    /// this sealer under a new data key, with the wrapped key for the entries it seals
+    pub(crate) fn with_data_key(&self) -> io::Result<(Sealer, Option<Vec<u8>>)> {
+        Ok(match new_data_key(&self.cipher, self.archive_id.as_ref())? {
+            Some((cipher, wrapped)) => (Sealer { cipher, ..self.clone() }, Some(wrapped)),
+            None => (self.clone(), None),
+        })
+    }
+}
+
+impl ShokoArchive {
+    /// what an entry with this wrapped data key is sealed under, the archive key when it has none
+    pub(crate) fn data_cipher(&mut self, data_key: Option<&[u8]>) -> io::Result<Cipher> {
+        let cipher = self.cipher()?;
+        match data_key {
+            Some(wrapped) => unwrap_data_key(&cipher, wrapped, self.archive_id.as_ref()),
+            None => Ok(cipher),
+        }
+    }
+}
@@ -1,48 +1,188 @@
+use std::io::{self, Read};
+
+use crate::limits::{LimitExceeded, LimitKind};
+
 pub fn decompress(data: &[u8]) -> Result<Vec<u8>, String> {
-    if data.is_empty() {
-        return Ok(Vec::new());
-    }
+    expand(data, u64::MAX).map_err(|e| e.to_string())
+}
+
+/// `decompress` that refuses to produce more than `max_out` bytes. runs are checked before
+/// they're expanded, so an oversized stream never gets more than `max_out` allocated
+pub fn decompress_limited(data: &[u8], max_out: u64) -> io::Result<Vec<u8>> {
+    expand(data, max_out)
+}
 
+/// decodes and validates in one pass over the records. sizing the output up front took a second
+/// pass (and a fresh allocation every call) that cost literal-heavy streams more than it saved
+fn expand(data: &[u8], max_out: u64) -> io::Result<Vec<u8>> {
+    let malformed = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+    let max = usize::try_from(max_out).unwrap_or(usize::MAX);
     let mut decompressed = Vec::new();
-    let mut i = 0;
+    let mut rest = data;
+
+    loop {
+        match rest {
+            [] => break,
+            [0x01, len, tail @ ..] => {
+                let (literal, tail) = tail.split_at_checked(*len as usize)
+                    .ok_or_else(|| malformed("Malformed Shoko stream: literal length exceeds data"))?;
+                check_len(decompressed.len(), literal.len(), max, max_out)?;
+                decompressed.extend_from_slice(literal);
+                rest = tail;
+            }
+            [0x00, len, value, tail @ ..] => {
+                check_len(decompressed.len(), *len as usize, max, max_out)?;
+                decompressed.resize(decompressed.len() + *len as usize, *value);
+                rest = tail;
+            }
+            [0x00, ..] => return Err(malformed("Malformed Shoko stream: unexpected end of run")),
+            [0x01] => return Err(malformed("Malformed Shoko stream: missing literal length")),
+            [control, ..] => {
+                return Err(malformed(&format!("Invalid Shoko control byte: {:#04x}", control)));
+            }
+        }
+    }
+
+    Ok(decompressed)
+}
+
+fn check_len(have: usize, adding: usize, max: usize, max_out: u64) -> io::Result<()> {
+    if have + adding > max {
+        return Err(LimitExceeded::new(LimitKind::EntrySize, max_out).into());
+    }
+    Ok(())
+}
+
+enum DecodeState {
+    Control,
+    Run { remaining: usize, value: u8 },
+    Literal { remaining: usize },
+}
+
+/// streaming counterpart of `decompress`, reads records lazily and fills runs in bulk
+pub struct RleDecoder<R: Read> {
+    inner: R,
+    buf: Box<[u8]>,
+    pos: usize,
+    filled: usize,
+    state: DecodeState,
+    limit: Option<u64>,
+    produced: u64,
+}
+
+impl<R: Read> RleDecoder<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            buf: vec![0u8; 8 * 1024].into_boxed_slice(),
+            pos: 0,
+            filled: 0,
+            state: DecodeState::Control,
+            limit: None,
+            produced: 0,
+        }
+    }
+
+    /// a decoder that errors out instead of producing more than `max_out` bytes
+    pub fn with_limit(inner: R, max_out: u64) -> Self {
+        Self { limit: Some(max_out), ..Self::new(inner) }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /// makes sure at least one unread input byte is buffered, false on a clean EOF
+    fn fill(&mut self) -> io::Result<bool> {
+        if self.pos < self.filled {
+            return Ok(true);
+        }
+        self.pos = 0;
+        self.filled = loop {
+            match self.inner.read(&mut self.buf) {
+                Ok(n) => break n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        };
+        Ok(self.filled > 0)
+    }
+
+    fn count(&mut self, n: usize) -> io::Result<()> {
+        self.produced += n as u64;
+        match self.limit {
+            Some(max) if self.produced > max => Err(LimitExceeded::new(LimitKind::EntrySize, max).into()),
+            _ => Ok(()),
+        }
+    }
 
-    while i < data.len() {
-        let control_byte = data[i];
-        i += 1;
+    fn next_byte(&mut self, missing: &str) -> io::Result<u8> {
+        if !self.fill()? {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, missing.to_string()));
+        }
+        self.pos += 1;
+        Ok(self.buf[self.pos - 1])
+    }
+}
+
+impl<R: Read> Read for RleDecoder<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if out.is_empty() {
+            return Ok(0);
+        }
+
+        loop {
+            match self.state {
+                DecodeState::Control => {
+                    if !self.fill()? {
+                        return Ok(0);
+                    }
+                    let control_byte = self.buf[self.pos];
+                    self.pos += 1;
 
-        match control_byte {
-            0x00 => {
-                if i + 1 >= data.len() {
-                    return Err("Malformed Shoko stream: unexpected end of run".to_string());
+                    self.state = match control_byte {
+                        0x00 => {
+                            let run_len = self.next_byte("Malformed Shoko stream: unexpected end of run")?;
+                            let value = self.next_byte("Malformed Shoko stream: unexpected end of run")?;
+                            DecodeState::Run { remaining: run_len as usize, value }
+                        }
+                        0x01 => {
+                            let lit_len = self.next_byte("Malformed Shoko stream: missing literal length")?;
+                            DecodeState::Literal { remaining: lit_len as usize }
+                        }
+                        _ => {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                format!("Invalid Shoko control byte: {:#04x}", control_byte),
+                            ));
+                        }
+                    };
                 }
-                let run_len = data[i] as usize;
-                let value = data[i + 1];
-                
-                for _ in 0..run_len {
-                    decompressed.push(value);
+                DecodeState::Run { remaining: 0, .. } | DecodeState::Literal { remaining: 0 } => {
+                    self.state = DecodeState::Control;
                 }
-                
-                i += 2;
-            }
-            0x01 => {
-                if i >= data.len() {
-                    return Err("Malformed Shoko stream: missing literal length".to_string());
+                DecodeState::Run { remaining, value } => {
+                    let n = remaining.min(out.len());
+                    self.count(n)?;
+                    out[..n].fill(value);
+                    self.state = DecodeState::Run { remaining: remaining - n, value };
+                    return Ok(n);
                 }
-                let lit_len = data[i] as usize;
-                i += 1;
-
-                if i + lit_len > data.len() {
-                    return Err("Malformed Shoko stream: literal length exceeds data".to_string());
+                DecodeState::Literal { remaining } => {
+                    if !self.fill()? {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "Malformed Shoko stream: literal length exceeds data",
+                        ));
+                    }
+                    let n = remaining.min(out.len()).min(self.filled - self.pos);
+                    self.count(n)?;
+                    out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+                    self.pos += n;
+                    self.state = DecodeState::Literal { remaining: remaining - n };
+                    return Ok(n);
                 }
-
-                decompressed.extend_from_slice(&data[i..i + lit_len]);
-                i += lit_len;
-            }
-            _ => {
-                return Err(format!("Invalid Shoko control byte: {:#04x}", control_byte));
             }
         }
     }
-
-    Ok(decompressed)
 }
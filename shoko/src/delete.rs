@@ -1,19 +1,44 @@
 use std::io;
 use crate::archive::ShokoArchive;
+use crate::pipeline::{PackItem, PackUnit, UnitKind};
 
 impl ShokoArchive {
     /// removes a file from the archive index, (well, duh why did i make a comment for this)
-    /// note that this does not immediately reclaim disk space so call defrag() to optimize
+    /// note that this does not immediately reclaim disk space so call defrag() to optimize.
+    /// its data key goes with it though: the rest of a solid block is sealed again into a new
+    /// block under a new key, and chunks no other entry has are dropped from the store
     pub fn delete_file(&mut self, internal_path: &str) -> io::Result<()> {
-        let original_len = self.entries.len();
-        self.entries.retain(|e| e.path != internal_path);
-
-        if self.entries.len() == original_len {
+        self.ensure_index()?;
+        let Some(gone) = self.entries.iter().find(|e| e.path == internal_path).cloned() else {
             return Err(io::Error::new(
                 io::ErrorKind::NotFound,
                 format!("File '{}' not found in archive", internal_path),
             ));
+        };
+        // the cached block may well be this entry's
+        self.block_cache = None;
+
+        // the other members hold the block's key too, they move out before it's forgotten.
+        // blocks from before data keys are under the archive key, moving wouldn't help those
+        let mut survivors = Vec::new();
+        if gone.is_solid() && gone.data_key.is_some() {
+            let paths: Vec<String> = self.entries.iter()
+                .filter(|e| e.path != gone.path && e.is_solid() && e.blob() == gone.blob())
+                .map(|e| e.path.clone())
+                .collect();
+            for path in paths {
+                let data = self.read_entry(&path)?;
+                survivors.push(PackItem::bytes(path, data));
+            }
+        }
+
+        self.entries.retain(|e| e.path != internal_path);
+        self.drop_dead_chunks();
+        if survivors.is_empty() {
+            return self.rewrite_index();
         }
-        self.rewrite_index()
+        // replaces the survivors' entries and writes the index
+        let unit = PackUnit { members: survivors, kind: UnitKind::Solid };
+        self.write_units(vec![unit], gone.compression_level, 1).map(|_| ())
     }
 }
@@ -0,0 +1,192 @@
+// binary deltas between two versions of the same entry, used when delta mode is on and a
+// path gets overwritten. the delta is just another blob payload, so it still goes through
+// the RLE + encryption like everything else
+use std::collections::HashMap;
+use std::io;
+
+use crate::archive::{BlobRef, ShokoArchive};
+use crate::limits::{LimitExceeded, LimitKind};
+
+const OP_COPY: u8 = 0x00;
+const OP_INSERT: u8 = 0x01;
+
+/// shortest match worth a copy op, also the width of the hashed window
+const MIN_MATCH: usize = 8;
+
+/// old positions we are willing to index, past this we only hash every n-th offset
+const MAX_INDEXED: usize = 1 << 22;
+
+/// what `prepare_delta` hands back: the delta payload and the chain it goes on top of
+pub(crate) struct PreparedDelta {
+    pub payload: Vec<u8>,
+    pub chain: Vec<BlobRef>,
+    /// the generation each chain link was sealed under
+    pub generations: Vec<u64>,
+    /// the chain's wrapped data key, the new link is sealed under it too
+    pub data_key: Option<Vec<u8>>,
+}
+
+/// encodes `new` as copy/insert ops against `old`
+pub fn encode(old: &[u8], new: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    put_varint(&mut out, new.len() as u64);
+
+    let mut index = HashMap::new();
+    if old.len() >= MIN_MATCH {
+        let stride = (old.len() / MAX_INDEXED).max(1);
+        for pos in (0..=old.len() - MIN_MATCH).step_by(stride) {
+            index.entry(window(old, pos)).or_insert(pos);
+        }
+    }
+
+    let mut pos = 0;
+    let mut literal_start = 0;
+    while pos + MIN_MATCH <= new.len() {
+        let Some(&found) = index.get(&window(new, pos)) else {
+            pos += 1;
+            continue;
+        };
+
+        let mut len = MIN_MATCH;
+        while found + len < old.len() && pos + len < new.len() && old[found + len] == new[pos + len] {
+            len += 1;
+        }
+        let mut back = 0;
+        while pos - back > literal_start && found - back > 0 && old[found - back - 1] == new[pos - back - 1] {
+            back += 1;
+        }
+
+        put_insert(&mut out, &new[literal_start..pos - back]);
+        out.push(OP_COPY);
+        put_varint(&mut out, (found - back) as u64);
+        put_varint(&mut out, (len + back) as u64);
+
+        pos += len;
+        literal_start = pos;
+    }
+    put_insert(&mut out, &new[literal_start..]);
+    out
+}
+
+/// rebuilds the new version from `old` and a delta made by `encode`
+pub fn apply(old: &[u8], delta: &[u8]) -> io::Result<Vec<u8>> {
+    apply_limited(old, delta, u64::MAX)
+}
+
+/// `apply` that turns down deltas declaring more than `max_out` bytes of output
+pub fn apply_limited(old: &[u8], delta: &[u8], max_out: u64) -> io::Result<Vec<u8>> {
+    let mut pos = 0;
+    let target_len = get_varint(delta, &mut pos)?;
+    if target_len > max_out {
+        return Err(LimitExceeded::new(LimitKind::EntrySize, max_out).into());
+    }
+    let target_len = target_len as usize;
+    // the declared length is only a claim, start from what the inputs can back up and grow
+    let mut out = Vec::with_capacity(target_len.min(old.len().saturating_add(delta.len())));
+
+    while pos < delta.len() {
+        let op = delta[pos];
+        pos += 1;
+        match op {
+            OP_COPY => {
+                let from = get_varint(delta, &mut pos)? as usize;
+                let len = get_varint(delta, &mut pos)? as usize;
+                let src = from.checked_add(len)
+                    .and_then(|end| old.get(from..end))
+                    .ok_or_else(|| malformed("copy out of range"))?;
+                out.extend_from_slice(src);
+            }
+            OP_INSERT => {
+                let len = get_varint(delta, &mut pos)? as usize;
+                let src = pos.checked_add(len)
+                    .and_then(|end| delta.get(pos..end))
+                    .ok_or_else(|| malformed("insert exceeds data"))?;
+                out.extend_from_slice(src);
+                pos += len;
+            }
+            _ => return Err(malformed("unknown op")),
+        }
+        if out.len() > target_len {
+            return Err(malformed("output longer than declared"));
+        }
+    }
+
+    if out.len() != target_len {
+        return Err(malformed("output shorter than declared"));
+    }
+    Ok(out)
+}
+
+impl ShokoArchive {
+    /// turns delta mode on for overwrites through `write_file_direct`. `max_chain` caps how many
+    /// deltas may stack on top of a full copy before the next write stores the content in full
+    /// again, 0 turns it back off. `defrag` always collapses chains into full copies
+    pub fn set_delta_mode(&mut self, max_chain: usize) {
+        self.max_delta_chain = max_chain;
+    }
+
+    /// payload + chain to store `content` as a delta on top of the current version of
+    /// `internal_path`, or None when there's nothing to diff against or a full copy is cheaper
+    pub(crate) fn prepare_delta(&mut self, internal_path: &str, content: &[u8]) -> io::Result<Option<PreparedDelta>> {
+        let Some(entry) = self.entries.iter().find(|e| e.path == internal_path) else {
+            return Ok(None);
+        };
+        // a solid member's blob is the whole shared block and a chunked entry has none,
+        // neither can be a chain base
+        if entry.is_solid() || entry.is_chunked() || entry.delta_chain.len() + 1 > self.max_delta_chain {
+            return Ok(None);
+        }
+
+        let mut chain = entry.delta_chain.clone();
+        chain.push(entry.blob());
+        let mut generations = entry.delta_generations.clone();
+        generations.push(entry.generation);
+        let data_key = entry.data_key.clone();
+        let previous = self.read_entry(internal_path)?;
+
+        let payload = encode(&previous, content);
+        // the delta header and op framing cost a few bytes, don't bother unless it clearly wins
+        if payload.len() + 16 >= content.len() {
+            return Ok(None);
+        }
+        Ok(Some(PreparedDelta { payload, chain, generations, data_key }))
+    }
+}
+
+fn window(data: &[u8], pos: usize) -> u64 {
+    u64::from_le_bytes(data[pos..pos + MIN_MATCH].try_into().unwrap())
+}
+
+fn put_insert(out: &mut Vec<u8>, literal: &[u8]) {
+    if literal.is_empty() {
+        return;
+    }
+    out.push(OP_INSERT);
+    put_varint(out, literal.len() as u64);
+    out.extend_from_slice(literal);
+}
+
+fn put_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn get_varint(data: &[u8], pos: &mut usize) -> io::Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = *data.get(*pos).ok_or_else(|| malformed("truncated varint"))?;
+        *pos += 1;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(malformed("varint too long"))
+}
+
+fn malformed(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Malformed Shoko delta: {}", what))
+}
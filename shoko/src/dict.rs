@@ -0,0 +1,196 @@
+// archive-level compression dictionaries for the LZ codec. a dictionary is stored once as a
+// normal (encrypted) blob and LZ payloads name it by id, so lots of small similar files can
+// share the redundancy a per-file compressor never gets to see
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::archive::{BlobRef, ShokoArchive};
+use crate::encrypt::BlobBinding;
+use crate::pipeline::walk_parallel;
+
+/// k-mer width used to score candidate segments while training
+const TRAIN_K: usize = 8;
+/// size of the pieces a trained dictionary is assembled from
+const TRAIN_SEGMENT: usize = 64;
+/// we never look at more sample data than this
+const TRAIN_MAX_INPUT: usize = 16 * 1024 * 1024;
+/// files bigger than this don't make useful samples, they compress fine on their own
+const SAMPLE_MAX_FILE: u64 = 64 * 1024;
+/// how many files `train_from_dir` reads at most
+const SAMPLE_MAX_FILES: usize = 4096;
+
+pub const DEFAULT_DICT_SIZE: usize = 32 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct Dictionary {
+    pub id: u32,
+    pub data: Arc<Vec<u8>>,
+}
+
+/// a dictionary as recorded in the archive header, the bytes are only read when needed
+pub(crate) struct DictionarySlot {
+    pub id: u32,
+    pub blob: BlobRef,
+    pub loaded: Option<Dictionary>,
+}
+
+/// builds a dictionary of at most `max_size` bytes out of the parts of `samples` that show up
+/// in the most samples. it's a greedy cover: pick the best scoring segment, forget the k-mers it
+/// covers, repeat. the best segments end up last so they sit closest to the data
+pub fn train(samples: &[&[u8]], max_size: usize) -> Vec<u8> {
+    let mut budget = TRAIN_MAX_INPUT;
+    let samples: Vec<&[u8]> = samples.iter()
+        .take_while(|s| {
+            let keep = budget > 0;
+            budget = budget.saturating_sub(s.len());
+            keep
+        })
+        .copied()
+        .collect();
+
+    let mut freq: HashMap<u64, u32> = HashMap::new();
+    for sample in &samples {
+        let mut seen = HashSet::new();
+        for w in sample.windows(TRAIN_K) {
+            let kmer = kmer(w);
+            if seen.insert(kmer) {
+                *freq.entry(kmer).or_default() += 1;
+            }
+        }
+    }
+
+    let score = |freq: &HashMap<u64, u32>, seg: &[u8]| -> u64 {
+        let mut seen = HashSet::new();
+        seg.windows(TRAIN_K)
+            .map(kmer)
+            .filter(|k| seen.insert(*k))
+            .map(|k| freq.get(&k).copied().unwrap_or(0))
+            .filter(|&f| f > 1)
+            .map(u64::from)
+            .sum()
+    };
+
+    let mut heap = BinaryHeap::new();
+    for (si, sample) in samples.iter().enumerate() {
+        for start in (0..sample.len()).step_by(TRAIN_SEGMENT / 2) {
+            let seg = &sample[start..(start + TRAIN_SEGMENT).min(sample.len())];
+            let s = score(&freq, seg);
+            if s > 0 {
+                heap.push((s, si, start));
+            }
+        }
+    }
+
+    let mut chosen: Vec<&[u8]> = Vec::new();
+    let mut total = 0;
+    while total < max_size {
+        let Some((_, si, start)) = heap.pop() else {
+            break;
+        };
+        let sample = samples[si];
+        let seg = &sample[start..(start + TRAIN_SEGMENT).min(sample.len())];
+
+        // scores only ever go down, so a stale entry that is still on top after a rescore wins
+        let fresh = score(&freq, seg);
+        if fresh == 0 {
+            continue;
+        }
+        if heap.peek().is_some_and(|(next, _, _)| *next > fresh) {
+            heap.push((fresh, si, start));
+            continue;
+        }
+
+        for w in seg.windows(TRAIN_K) {
+            freq.remove(&kmer(w));
+        }
+        chosen.push(seg);
+        total += seg.len();
+    }
+
+    let mut dict: Vec<u8> = chosen.into_iter().rev().flatten().copied().collect();
+    if dict.len() > max_size {
+        dict.drain(..dict.len() - max_size);
+    }
+    dict
+}
+
+/// trains a dictionary from an even spread of the small files under `root`
+pub fn train_from_dir(root: &Path, max_size: usize, jobs: usize) -> io::Result<Vec<u8>> {
+    let mut candidates = Vec::new();
+    for (_, path) in walk_parallel(root, jobs)? {
+        if fs::metadata(&path)?.len() <= SAMPLE_MAX_FILE {
+            candidates.push(path);
+        }
+    }
+
+    let step = candidates.len().div_ceil(SAMPLE_MAX_FILES).max(1);
+    let mut samples = Vec::new();
+    for path in candidates.iter().step_by(step) {
+        samples.push(fs::read(path)?);
+    }
+    let refs: Vec<&[u8]> = samples.iter().map(|s| s.as_slice()).collect();
+    Ok(train(&refs, max_size))
+}
+
+fn kmer(w: &[u8]) -> u64 {
+    u64::from_le_bytes(w[..TRAIN_K].try_into().unwrap())
+}
+
+impl ShokoArchive {
+    /// stores a pre-trained dictionary in the archive and makes it the one new LZ entries use
+    pub fn add_dictionary(&mut self, data: &[u8]) -> io::Result<u32> {
+        let id = self.dictionaries.iter().map(|d| d.id).max().unwrap_or(0) + 1;
+        let blob = self.append_blob(data, 0, BlobBinding::Dictionary(id))?;
+        self.dictionaries.push(DictionarySlot {
+            id,
+            blob,
+            loaded: Some(Dictionary { id, data: Arc::new(data.to_vec()) }),
+        });
+        self.active_dictionary = Some(id);
+        self.rewrite_index()?;
+        Ok(id)
+    }
+
+    /// `train` + `add_dictionary`
+    pub fn train_dictionary(&mut self, samples: &[&[u8]], max_size: usize) -> io::Result<u32> {
+        let dict = train(samples, max_size);
+        self.add_dictionary(&dict)
+    }
+
+    /// picks the dictionary new LZ entries get compressed with, None compresses without one
+    pub fn use_dictionary(&mut self, id: Option<u32>) -> io::Result<()> {
+        if let Some(id) = id {
+            if !self.dictionaries.iter().any(|d| d.id == id) {
+                return Err(io::Error::new(io::ErrorKind::NotFound, format!("No dictionary {} in archive", id)));
+            }
+        }
+        self.active_dictionary = id;
+        Ok(())
+    }
+
+    pub fn dictionary_ids(&self) -> Vec<u32> {
+        self.dictionaries.iter().map(|d| d.id).collect()
+    }
+
+    pub fn active_dictionary(&self) -> Option<u32> {
+        self.active_dictionary
+    }
+
+    pub fn dictionary(&mut self, id: u32) -> io::Result<Dictionary> {
+        let slot = self.dictionaries.iter()
+            .position(|d| d.id == id)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("No dictionary {} in archive", id)))?;
+
+        if let Some(dict) = &self.dictionaries[slot].loaded {
+            return Ok(dict.clone());
+        }
+        let blob = self.dictionaries[slot].blob;
+        let data = self.read_blob_ref(blob, BlobBinding::Dictionary(id))?;
+        let dict = Dictionary { id, data: Arc::new(data) };
+        self.dictionaries[slot].loaded = Some(dict.clone());
+        Ok(dict)
+    }
+}
@@ -1,70 +1,431 @@
 use aes_gcm::{
-    aead::{Aead, KeyInit},
-    Aes256Gcm, Nonce
+    aead::{self, generic_array::typenum::Unsigned, Aead, AeadCore, KeyInit, Payload},
+    Aes256Gcm
 };
+use argon2::{Algorithm, Argon2, Params, Version};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use chacha20poly1305::{ChaCha20Poly1305, XChaCha20Poly1305};
 use rand::{RngCore, rng};
+use sha2::Sha256;
 use std::io;
-use std::env;
-
-fn get_encryption_key() -> io::Result<[u8; 32]> {
-    let key_str = env::var("SHOKO_KEY").map_err(|_| {
-        io::Error::new(
-            io::ErrorKind::PermissionDenied,
-            "Encryption key not found. Please set the 'SHOKO_KEY' environment variable.",
-        )
-    })?;
-
-    let key_bytes = key_str.as_bytes();
-    if key_bytes.len() != 32 {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidInput,
-            format!(
-                "Invalid SHOKO_KEY length: expected 32 bytes, got {}. Use a 32-character string.",
-                key_bytes.len()
-            ),
-        ));
-    }
-
-    let mut key = [0u8; 32];
-    key.copy_from_slice(key_bytes);
-    Ok(key)
-}
-
-pub fn encrypt_data(data: &[u8]) -> io::Result<Vec<u8>> {
-    let raw_key = get_encryption_key()?;
-    let key = Aes256Gcm::new_from_slice(&raw_key)
-        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
-    
-    let mut nonce_bytes = [0u8; 12];
-    rng().fill_bytes(&mut nonce_bytes);
-    let nonce = Nonce::from_slice(&nonce_bytes);
+use zeroize::Zeroizing;
+
+use crate::chunk::ChunkHash;
+use crate::keys::{DefaultKey, KeyProvider};
+use crate::shadow::SecretKey;
+use crate::stream;
+
+/// the environment variable the secret comes from when nothing else supplies one
+pub const KEY_ENV: &str = "SHOKO_KEY";
+
+pub const SALT_LEN: usize = 16;
+
+/// random per-archive id, every blob's associated data starts with it
+pub type ArchiveId = [u8; 16];
+
+/// short fingerprint of an archive key, see `Cipher::key_id`
+pub type KeyId = [u8; 8];
+
+const KEY_ID_INFO: &[u8] = b"shoko key id v1";
+
+/// HMAC-SHA256 over the header's key records, see `Cipher::header_mac`
+pub type HeaderMac = [u8; 32];
+
+const HEADER_MAC_INFO: &[u8] = b"shoko header mac v1";
+
+/// how an archive's blobs are protected, recorded in the header by id so readers can tell
+/// which cipher to open them with. archives from before the header said so are all AES-256-GCM
+/// under SHOKO_KEY
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encryption {
+    /// blobs are stored as they come out of the codec, no key needed
+    None,
+    /// every blob is one AES-GCM message, it has to be in memory whole to open
+    Aes256Gcm,
+    /// AES-GCM in 64 KiB STREAM chunks, see `stream`. blobs can be sealed and opened in
+    /// constant memory and single chunks opened on their own
+    #[default]
+    Aes256GcmStream,
+    /// one ChaCha20-Poly1305 message per blob, quicker than AES where there's no AES-NI
+    ChaCha20Poly1305,
+    /// ChaCha20-Poly1305 with a 24 byte nonce. random 12 byte nonces start to risk a
+    /// collision after a few billion blobs under one key, 24 bytes never get there
+    XChaCha20Poly1305,
+}
+
+impl Encryption {
+    pub fn id(&self) -> u8 {
+        match self {
+            Encryption::None => 0,
+            Encryption::Aes256Gcm => 1,
+            Encryption::Aes256GcmStream => 2,
+            Encryption::ChaCha20Poly1305 => 3,
+            Encryption::XChaCha20Poly1305 => 4,
+        }
+    }
+
+    pub fn from_id(id: u8) -> io::Result<Self> {
+        match id {
+            0 => Ok(Encryption::None),
+            1 => Ok(Encryption::Aes256Gcm),
+            2 => Ok(Encryption::Aes256GcmStream),
+            3 => Ok(Encryption::ChaCha20Poly1305),
+            4 => Ok(Encryption::XChaCha20Poly1305),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unknown encryption scheme {}", id))),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encryption::None => "none",
+            Encryption::Aes256Gcm => "aes-256-gcm",
+            Encryption::Aes256GcmStream => "aes-256-gcm-stream",
+            Encryption::ChaCha20Poly1305 => "chacha20-poly1305",
+            Encryption::XChaCha20Poly1305 => "xchacha20-poly1305",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        (0..=4).filter_map(|id| Self::from_id(id).ok()).find(|e| e.name() == name)
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        *self != Encryption::None
+    }
+
+    /// bytes a sealed blob of `sealed_len` bytes carries on top of its payload
+    pub fn overhead(&self, sealed_len: u64) -> u64 {
+        match self {
+            Encryption::None => 0,
+            Encryption::Aes256Gcm | Encryption::ChaCha20Poly1305 => SEAL_OVERHEAD,
+            Encryption::XChaCha20Poly1305 => XSEAL_OVERHEAD,
+            Encryption::Aes256GcmStream => {
+                let chunks = stream::chunk_count(sealed_len, stream::CHUNK_LEN);
+                stream::STREAM_HEADER_LEN as u64 + chunks * stream::TAG_LEN as u64
+            }
+        }
+    }
+}
+
+/// Argon2id cost settings, the defaults are the OWASP baseline (19 MiB, 2 passes, 1 lane)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Argon2Params {
+    /// memory in KiB
+    pub m_cost: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
+}
+
+impl Default for Argon2Params {
+    fn default() -> Self {
+        Self { m_cost: 19 * 1024, t_cost: 2, p_cost: 1 }
+    }
+}
+
+impl Argon2Params {
+    /// the most a header gets to ask for (4 GiB, 16 passes, 16 lanes). the costs are read before
+    /// anything is authenticated, and every key slot is tried in turn on a wrong secret
+    pub const MAX: Argon2Params = Argon2Params { m_cost: 4 * 1024 * 1024, t_cost: 16, p_cost: 16 };
+
+    pub fn within_max(&self) -> bool {
+        self.m_cost <= Self::MAX.m_cost && self.t_cost <= Self::MAX.t_cost && self.p_cost <= Self::MAX.p_cost
+    }
+}
+
+/// how the archive key comes out of the secret the user supplies, recorded in the header
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KeyDerivation {
+    /// the secret is the key, and has to be exactly 32 bytes. what archives without a
+    /// derivation record use
+    #[default]
+    Raw,
+    /// any passphrase, stretched with Argon2id under a per-archive salt
+    Argon2id { params: Argon2Params, salt: [u8; SALT_LEN] },
+    /// no secret derives the key, it's random and only key slots and recipient stanzas unwrap it.
+    /// what every new encrypted archive uses, the creator's secret is just slot 0
+    Wrapped,
+}
+
+impl KeyDerivation {
+    /// Argon2id with a fresh random salt
+    pub fn argon2id(params: Argon2Params) -> Self {
+        let mut salt = [0u8; SALT_LEN];
+        rng().fill_bytes(&mut salt);
+        KeyDerivation::Argon2id { params, salt }
+    }
+
+    /// the key `secret` stands for, worked out straight into locked memory
+    pub fn derive(&self, secret: &[u8]) -> io::Result<SecretKey> {
+        match self {
+            KeyDerivation::Raw => {
+                if secret.len() != 32 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "Invalid key length: expected 32 bytes, got {}. Raw-key archives need a 32-character key.",
+                            secret.len()
+                        ),
+                    ));
+                }
+                SecretKey::from_slice(secret)
+            }
+            KeyDerivation::Argon2id { params, salt } => {
+                let params = Params::new(params.m_cost, params.t_cost, params.p_cost, Some(32))
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("Bad Argon2 parameters: {}", e)))?;
+                SecretKey::new_with(|key| {
+                    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+                        .hash_password_into(secret, salt, key)
+                        .map_err(|e| io::Error::other(format!("Key derivation failed: {}", e)))
+                })
+            }
+            KeyDerivation::Wrapped => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "This archive's key is random, open it with a key slot's secret or a recipient's identity",
+            )),
+        }
+    }
+
+    /// header record payload: kind u8, then for argon2id m/t/p u32 and the salt
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            KeyDerivation::Raw => vec![0],
+            KeyDerivation::Argon2id { params, salt } => {
+                let mut out = vec![1];
+                out.extend_from_slice(&params.m_cost.to_le_bytes());
+                out.extend_from_slice(&params.t_cost.to_le_bytes());
+                out.extend_from_slice(&params.p_cost.to_le_bytes());
+                out.extend_from_slice(salt);
+                out
+            }
+            KeyDerivation::Wrapped => vec![2],
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        let bad = || io::Error::new(io::ErrorKind::InvalidData, "Malformed key derivation record");
+        match bytes.first() {
+            Some(0) => Ok(KeyDerivation::Raw),
+            Some(2) => Ok(KeyDerivation::Wrapped),
+            Some(1) if bytes.len() == 13 + SALT_LEN => {
+                let u32_at = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+                let params = Argon2Params { m_cost: u32_at(1), t_cost: u32_at(5), p_cost: u32_at(9) };
+                if !params.within_max() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                        "Argon2 costs in the header are too high (m={} KiB, t={}, p={})", params.m_cost, params.t_cost, params.p_cost
+                    )));
+                }
+                Ok(KeyDerivation::Argon2id { params, salt: bytes[13..].try_into().unwrap() })
+            }
+            _ => Err(bad()),
+        }
+    }
+}
+
+/// what a sealed blob belongs to. it goes into the AEAD associated data, so a blob moved to
+/// another entry, another version of the same entry or another archive fails to open
+#[derive(Debug, Clone, Copy)]
+pub enum BlobBinding<'a> {
+    /// a plain entry blob or one link of its delta chain, `generation` is the write that sealed it
+    Entry { path: &'a str, generation: u64 },
+    /// a solid block, shared by every member of the unit that wrote it so the paths stay out
+    Solid { generation: u64 },
+    /// a deduplicated chunk, bound to the hash it's stored under
+    Chunk(&'a ChunkHash),
+    Dictionary(u32),
+    /// the sealed index, see `ArchiveOptions::encrypt_index`
+    Index,
+    /// an entry's own key wrapped by the archive key, see `datakey`
+    DataKey,
+    /// blobs written outside an archive's index, see `ShokoWriter::write_blob`
+    Unbound,
+}
+
+impl BlobBinding<'_> {
+    /// `archive id | kind u8 | number u64 | name`. archives from before the id existed seal
+    /// with no associated data at all, which is what the empty result is
+    pub fn aad(&self, archive_id: Option<&ArchiveId>) -> Vec<u8> {
+        let Some(id) = archive_id else {
+            return Vec::new();
+        };
+        let (kind, number, name): (u8, u64, &[u8]) = match *self {
+            BlobBinding::Entry { path, generation } => (1, generation, path.as_bytes()),
+            BlobBinding::Solid { generation } => (2, generation, &[]),
+            BlobBinding::Chunk(hash) => (3, 0, hash),
+            BlobBinding::Dictionary(dict) => (4, dict as u64, &[]),
+            BlobBinding::Index => (5, 0, &[]),
+            BlobBinding::DataKey => (6, 0, &[]),
+            BlobBinding::Unbound => (0, 0, &[]),
+        };
+        let mut aad = id.to_vec();
+        aad.push(kind);
+        aad.extend_from_slice(&number.to_le_bytes());
+        aad.extend_from_slice(name);
+        aad
+    }
+}
+
+/// an encryption scheme with its key, what blobs actually get sealed and opened with
+#[derive(Clone)]
+pub enum Cipher {
+    Plain,
+    Aes256Gcm(SecretKey),
+    Aes256GcmStream(SecretKey),
+    ChaCha20Poly1305(SecretKey),
+    XChaCha20Poly1305(SecretKey),
+}
+
+impl Cipher {
+    pub fn new(encryption: Encryption, key: SecretKey) -> Self {
+        match encryption {
+            Encryption::None => Cipher::Plain,
+            Encryption::Aes256Gcm => Cipher::Aes256Gcm(key),
+            Encryption::Aes256GcmStream => Cipher::Aes256GcmStream(key),
+            Encryption::ChaCha20Poly1305 => Cipher::ChaCha20Poly1305(key),
+            Encryption::XChaCha20Poly1305 => Cipher::XChaCha20Poly1305(key),
+        }
+    }
+
+    /// the legacy setup: AES-256-GCM keyed with SHOKO_KEY (or SHOKO_KEY_FILE) as-is
+    pub fn from_env() -> io::Result<Self> {
+        let key = KeyDerivation::Raw.derive(&DefaultKey.secret()?)?;
+        Ok(Cipher::Aes256Gcm(key))
+    }
+
+    /// the same scheme under another key, `Plain` stays plain
+    pub(crate) fn with_key(&self, key: SecretKey) -> Cipher {
+        match self {
+            Cipher::Plain => Cipher::Plain,
+            Cipher::Aes256Gcm(_) => Cipher::Aes256Gcm(key),
+            Cipher::Aes256GcmStream(_) => Cipher::Aes256GcmStream(key),
+            Cipher::ChaCha20Poly1305(_) => Cipher::ChaCha20Poly1305(key),
+            Cipher::XChaCha20Poly1305(_) => Cipher::XChaCha20Poly1305(key),
+        }
+    }
+
+    /// the key itself, None for `Plain`
+    pub(crate) fn key(&self) -> Option<SecretKey> {
+        match self {
+            Cipher::Plain => None,
+            Cipher::Aes256Gcm(key)
+            | Cipher::Aes256GcmStream(key)
+            | Cipher::ChaCha20Poly1305(key)
+            | Cipher::XChaCha20Poly1305(key) => Some(key.clone()),
+        }
+    }
+
+    /// what the header records so a wrong key shows up as one: HKDF of the key, cut to 8 bytes.
+    /// it goes one way, knowing it doesn't get anyone closer to the key than a sealed blob does
+    pub fn key_id(&self) -> Option<KeyId> {
+        let key = self.key()?;
+        let mut id = [0u8; 8];
+        Hkdf::<Sha256>::new(None, &key[..]).expand(KEY_ID_INFO, &mut id).ok()?;
+        Some(id)
+    }
+
+    /// what keeps the clear header records honest: an HMAC under a key of its own, HKDF'd off
+    /// the archive key. None for `Plain`
+    pub(crate) fn header_mac(&self, records: &[u8]) -> Option<HeaderMac> {
+        Some(self.header_hmac(records)?.finalize().into_bytes().into())
+    }
+
+    /// checks `mac` in constant time
+    pub(crate) fn header_mac_matches(&self, records: &[u8], mac: &HeaderMac) -> bool {
+        self.header_hmac(records).is_some_and(|hmac| hmac.verify_slice(mac).is_ok())
+    }
+
+    fn header_hmac(&self, records: &[u8]) -> Option<Hmac<Sha256>> {
+        let key = self.key()?;
+        let mut mac_key = Zeroizing::new([0u8; 32]);
+        Hkdf::<Sha256>::new(None, &key[..]).expand(HEADER_MAC_INFO, &mut mac_key[..]).ok()?;
+        let mut hmac = <Hmac<Sha256> as Mac>::new_from_slice(&mac_key[..]).ok()?;
+        hmac.update(records);
+        Some(hmac)
+    }
+
+    /// `aad` is authenticated but not stored, `unseal` needs the exact same bytes
+    pub fn seal(&self, data: &[u8], aad: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Cipher::Plain => Ok(data.to_vec()),
+            Cipher::Aes256Gcm(key) => aes_seal(key, data, aad),
+            Cipher::Aes256GcmStream(key) => stream::seal(key, data, aad),
+            Cipher::ChaCha20Poly1305(key) => aead_seal::<ChaCha20Poly1305>(key, data, aad),
+            Cipher::XChaCha20Poly1305(key) => aead_seal::<XChaCha20Poly1305>(key, data, aad),
+        }
+    }
+
+    pub fn unseal(&self, data: &[u8], aad: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Cipher::Plain => Ok(data.to_vec()),
+            Cipher::Aes256Gcm(key) => aes_unseal(key, data, aad),
+            Cipher::Aes256GcmStream(key) => stream::unseal(key, data, aad),
+            Cipher::ChaCha20Poly1305(key) => aead_unseal::<ChaCha20Poly1305>(key, data, aad),
+            Cipher::XChaCha20Poly1305(key) => aead_unseal::<XChaCha20Poly1305>(key, data, aad),
+        }
+    }
+}
+
+impl std::fmt::Debug for Cipher {
+    // never print key bytes
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Cipher::Plain => write!(f, "Cipher::Plain"),
+            Cipher::Aes256Gcm(_) => write!(f, "Cipher::Aes256Gcm(..)"),
+            Cipher::Aes256GcmStream(_) => write!(f, "Cipher::Aes256GcmStream(..)"),
+            Cipher::ChaCha20Poly1305(_) => write!(f, "Cipher::ChaCha20Poly1305(..)"),
+            Cipher::XChaCha20Poly1305(_) => write!(f, "Cipher::XChaCha20Poly1305(..)"),
+        }
+    }
+}
+
+/// what AES-256-GCM (and ChaCha20-Poly1305) adds to every blob: the 12 byte nonce and the 16 byte tag
+pub const SEAL_OVERHEAD: u64 = 12 + 16;
+/// the same for XChaCha20-Poly1305, whose nonce is 24 bytes
+pub const XSEAL_OVERHEAD: u64 = 24 + 16;
+
+pub(crate) fn aes_seal(raw_key: &[u8; 32], data: &[u8], aad: &[u8]) -> io::Result<Vec<u8>> {
+    aead_seal::<Aes256Gcm>(raw_key, data, aad)
+}
+
+pub(crate) fn aes_unseal(raw_key: &[u8; 32], data: &[u8], aad: &[u8]) -> io::Result<Vec<u8>> {
+    aead_unseal::<Aes256Gcm>(raw_key, data, aad)
+}
+
+/// one AEAD message with a fresh random nonce in front, the nonce is as long as `A` wants
+fn aead_seal<A: Aead + KeyInit>(raw_key: &[u8; 32], data: &[u8], aad: &[u8]) -> io::Result<Vec<u8>> {
+    let key = A::new_from_slice(raw_key)
+        .map_err(|e| io::Error::other(e.to_string()))?;
+
+    let mut nonce = aead::Nonce::<A>::default();
+    rng().fill_bytes(&mut nonce);
 
     let ciphertext = key
-        .encrypt(nonce, data)
+        .encrypt(&nonce, Payload { msg: data, aad })
         .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Encryption failed: {}", e)))?;
-    let mut out = nonce_bytes.to_vec();
+    let mut out = nonce.to_vec();
     out.extend(ciphertext);
     Ok(out)
 }
 
-pub fn decrypt_data(data: &[u8]) -> io::Result<Vec<u8>> {
-    if data.len() < 12 {
+fn aead_unseal<A: Aead + KeyInit>(raw_key: &[u8; 32], data: &[u8], aad: &[u8]) -> io::Result<Vec<u8>> {
+    let nonce_len = <A as AeadCore>::NonceSize::USIZE;
+    if data.len() < nonce_len {
         return Err(io::Error::new(io::ErrorKind::InvalidData, "Data too short for decryption"));
     }
 
-    let raw_key = get_encryption_key()?;
-    let key = Aes256Gcm::new_from_slice(&raw_key)
-        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+    let key = A::new_from_slice(raw_key)
+        .map_err(|e| io::Error::other(e.to_string()))?;
 
-    let (nonce_bytes, ciphertext) = data.split_at(12);
-    let nonce = Nonce::from_slice(nonce_bytes);
+    let (nonce_bytes, ciphertext) = data.split_at(nonce_len);
+    let nonce = aead::Nonce::<A>::from_slice(nonce_bytes);
 
     let plaintext = key
-        .decrypt(nonce, ciphertext)
+        .decrypt(nonce, Payload { msg: ciphertext, aad })
         .map_err(|e| {
             io::Error::new(
-                io::ErrorKind::PermissionDenied,
-                format!("Decryption failed (Invalid key or tampered data): {}", e),
+                // a wrong key is normally caught by the key id before it gets this far
+                io::ErrorKind::InvalidData,
+                format!("Decryption failed (tampered or misplaced data, or a wrong key): {}", e),
             )
         })?;
 
@@ -0,0 +1,131 @@
+// on-disk constants and the tagged record blocks of the index: v2 added an extension block per
+// entry, v3 put an archive header block (same record layout) in front of the entries
+use std::io;
+
+pub const MAGIC_V1: &[u8; 8] = b"SHOKO001";
+pub const MAGIC_V2: &[u8; 8] = b"SHOKO002";
+pub const MAGIC_V3: &[u8; 8] = b"SHOKO003";
+pub const CURRENT_VERSION: u8 = 3;
+
+/// bytes before the first blob
+pub const HEADER_LEN: u64 = 8;
+
+/// v2 entry extension tags, unknown tags are skipped on read so older readers don't choke
+pub const EXT_DELTA_CHAIN: u8 = 0x01;
+pub const EXT_SOLID: u8 = 0x02;
+pub const EXT_CHUNKS: u8 = 0x03;
+pub const EXT_ORIGINAL_SIZE: u8 = 0x04;
+pub const EXT_GENERATION: u8 = 0x05;
+/// the entry's own data key, wrapped by the archive key, see `datakey`
+pub const EXT_DATA_KEY: u8 = 0x06;
+
+/// v3 archive header tags, same record layout as the entry extensions
+pub const HDR_DICTIONARY: u8 = 0x01;
+pub const HDR_CHUNK: u8 = 0x02;
+pub const HDR_ENCRYPTION: u8 = 0x03;
+pub const HDR_KEY_DERIVATION: u8 = 0x04;
+pub const HDR_ARCHIVE_ID: u8 = 0x05;
+/// the real header and entries, sealed. when it's there the footer's entry count is 0
+pub const HDR_SEALED_INDEX: u8 = 0x06;
+pub const HDR_KEY_SLOT: u8 = 0x07;
+/// public key + ed25519 signature, see `sign`
+pub const HDR_SIGNATURE: u8 = 0x08;
+/// the archive key wrapped for one X25519 recipient, see `recipient`
+pub const HDR_RECIPIENT: u8 = 0x09;
+/// fingerprint of the archive key, so a wrong key is caught before any blob is opened
+pub const HDR_KEY_ID: u8 = 0x0a;
+/// the padding policy every blob is sealed with, see `padding`
+pub const HDR_PADDING: u8 = 0x0b;
+/// MAC over the other key records under the archive key, so they can't be swapped unnoticed
+pub const HDR_HEADER_MAC: u8 = 0x0c;
+
+pub fn version_from_magic(magic: &[u8; 8]) -> io::Result<u8> {
+    match magic {
+        MAGIC_V1 => Ok(1),
+        MAGIC_V2 => Ok(2),
+        MAGIC_V3 => Ok(3),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "Not a Shoko archive (bad magic)")),
+    }
+}
+
+/// builds the `tag u8 | len u32 | payload` records of an extension block
+#[derive(Default)]
+pub struct ExtWriter {
+    buf: Vec<u8>,
+}
+
+impl ExtWriter {
+    pub fn record(&mut self, tag: u8, payload: &[u8]) {
+        self.buf.push(tag);
+        self.buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        self.buf.extend_from_slice(payload);
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+/// walks the records written by `ExtWriter`
+pub struct ExtReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> ExtReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    pub fn next_record(&mut self) -> io::Result<Option<(u8, &'a [u8])>> {
+        if self.data.is_empty() {
+            return Ok(None);
+        }
+        if self.data.len() < 5 {
+            return Err(truncated());
+        }
+        let tag = self.data[0];
+        let len = u32::from_le_bytes(self.data[1..5].try_into().unwrap()) as usize;
+        if self.data.len() - 5 < len {
+            return Err(truncated());
+        }
+        let payload = &self.data[5..5 + len];
+        self.data = &self.data[5 + len..];
+        Ok(Some((tag, payload)))
+    }
+}
+
+/// little cursor for decoding fixed-width fields out of a record payload
+pub struct FieldReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> FieldReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    pub fn bytes(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.data.len() < n {
+            return Err(truncated());
+        }
+        let (head, tail) = self.data.split_at(n);
+        self.data = tail;
+        Ok(head)
+    }
+
+    pub fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+}
+
+fn truncated() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "Truncated Shoko index extension")
+}
@@ -0,0 +1,409 @@
+// where an archive's secret comes from. the secret is a passphrase (or the raw key, for archives
+// without a KDF) and only gets asked for once per handle, the derived key is kept from then on.
+//
+// key slots, LUKS style: the archive key is random and wrapped once per member under a key
+// derived from that member's own secret, so people can be added and dropped without touching any
+// blob. the creator is slot 0 and no different from the rest. archives from before that derive
+// the archive key straight from the creator's secret, which keeps working for them
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use base64ct::{Base64, Encoding};
+use rand::RngCore;
+
+use crate::archive::ShokoArchive;
+use crate::encrypt::{self, Cipher, KeyDerivation};
+use crate::format::{FieldReader, HEADER_LEN};
+use crate::shadow::{SecretKey, SecureBuf};
+use crate::sign::{from_hex, to_hex};
+use zeroize::Zeroizing;
+
+/// hands out the secret in locked memory, see `shadow::SecureBuf`
+pub trait KeyProvider {
+    fn secret(&self) -> io::Result<SecureBuf>;
+}
+
+/// a secret the caller already has in hand, e.g. out of its own secret store. wiped on drop
+pub struct RawKey(Zeroizing<Vec<u8>>);
+
+impl RawKey {
+    pub fn new(secret: impl Into<Vec<u8>>) -> Self {
+        Self(Zeroizing::new(secret.into()))
+    }
+}
+
+impl KeyProvider for RawKey {
+    fn secret(&self) -> io::Result<SecureBuf> {
+        SecureBuf::from_slice(&self.0)
+    }
+}
+
+/// reads the secret from an environment variable, SHOKO_KEY unless told otherwise
+pub struct EnvKey {
+    var: String,
+}
+
+impl EnvKey {
+    pub fn new(var: impl Into<String>) -> Self {
+        Self { var: var.into() }
+    }
+}
+
+impl Default for EnvKey {
+    fn default() -> Self {
+        Self::new(encrypt::KEY_ENV)
+    }
+}
+
+impl KeyProvider for EnvKey {
+    fn secret(&self) -> io::Result<SecureBuf> {
+        let secret = env::var_os(&self.var)
+            .map(|v| v.into_encoded_bytes())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("Encryption key not found. Please set the '{}' environment variable.", self.var),
+                )
+            })?;
+        SecureBuf::from_vec(secret)
+    }
+}
+
+/// what SHOKO_KEY_FILE points at when SHOKO_KEY isn't set
+pub const KEY_FILE_ENV: &str = "SHOKO_KEY_FILE";
+/// how the SHOKO_KEY_FILE file is read, see `KeyEncoding::from_name`. hex if unset
+pub const KEY_ENCODING_ENV: &str = "SHOKO_KEY_ENCODING";
+
+/// how a key file's bytes become the secret. hex unless told otherwise, that's what
+/// `generate_key_file` and skgenkey write by default
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KeyEncoding {
+    /// the whole file, minus one trailing newline so `echo pass > file` works
+    Text,
+    /// the whole file exactly as it is
+    Binary,
+    #[default]
+    Hex,
+    Base64,
+}
+
+impl KeyEncoding {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "text" => Some(KeyEncoding::Text),
+            "binary" | "raw" => Some(KeyEncoding::Binary),
+            "hex" => Some(KeyEncoding::Hex),
+            "base64" => Some(KeyEncoding::Base64),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            KeyEncoding::Text => "text",
+            KeyEncoding::Binary => "binary",
+            KeyEncoding::Hex => "hex",
+            KeyEncoding::Base64 => "base64",
+        }
+    }
+
+    /// how a key gets written out, the text ones end in a newline
+    pub fn encode(&self, key: &[u8]) -> Zeroizing<Vec<u8>> {
+        let mut out = Zeroizing::new(match self {
+            KeyEncoding::Text | KeyEncoding::Binary => return Zeroizing::new(key.to_vec()),
+            KeyEncoding::Hex => to_hex(key).into_bytes(),
+            KeyEncoding::Base64 => Base64::encode_string(key).into_bytes(),
+        });
+        out.push(b'\n');
+        out
+    }
+
+    pub fn decode(&self, contents: &[u8]) -> io::Result<Zeroizing<Vec<u8>>> {
+        let text = || std::str::from_utf8(contents).map(str::trim).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("A {} key file has to be text", self.name()))
+        });
+        Ok(Zeroizing::new(match self {
+            KeyEncoding::Binary => contents.to_vec(),
+            KeyEncoding::Text => {
+                let mut secret = contents.strip_suffix(b"\n").unwrap_or(contents);
+                secret = secret.strip_suffix(b"\r").unwrap_or(secret);
+                secret.to_vec()
+            }
+            KeyEncoding::Hex => from_hex(text()?)?,
+            KeyEncoding::Base64 => Base64::decode_vec(text()?)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Not a base64 string"))?,
+        }))
+    }
+}
+
+/// the secret is read from a file, as hex unless `with_encoding` says otherwise. a passphrase
+/// file needs `KeyEncoding::Text`
+pub struct KeyFile {
+    path: PathBuf,
+    encoding: KeyEncoding,
+}
+
+impl KeyFile {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into(), encoding: KeyEncoding::default() }
+    }
+
+    pub fn with_encoding(mut self, encoding: KeyEncoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+}
+
+impl KeyProvider for KeyFile {
+    fn secret(&self) -> io::Result<SecureBuf> {
+        let contents = Zeroizing::new(fs::read(&self.path).map_err(|e| {
+            io::Error::new(e.kind(), format!("Can't read key file {}: {}", self.path.display(), e))
+        })?);
+        let secret = self.encoding.decode(&contents).map_err(|e| {
+            io::Error::new(e.kind(), format!("Can't read key file {}: {}", self.path.display(), e))
+        })?;
+        SecureBuf::from_slice(&secret)
+    }
+}
+
+/// what a handle falls back to when it wasn't given a key: SHOKO_KEY, or else the file
+/// SHOKO_KEY_FILE names, read the way SHOKO_KEY_ENCODING says
+#[derive(Default)]
+pub struct DefaultKey;
+
+impl KeyProvider for DefaultKey {
+    fn secret(&self) -> io::Result<SecureBuf> {
+        if env::var_os(encrypt::KEY_ENV).is_some() {
+            return EnvKey::default().secret();
+        }
+        let Some(path) = env::var_os(KEY_FILE_ENV) else {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, format!(
+                "Encryption key not found. Please set '{}' or point '{}' at a key file.", encrypt::KEY_ENV, KEY_FILE_ENV
+            )));
+        };
+        let encoding = match env::var(KEY_ENCODING_ENV) {
+            Ok(name) => KeyEncoding::from_name(&name).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, format!("Unknown {} '{}', use text, binary, hex or base64", KEY_ENCODING_ENV, name))
+            })?,
+            Err(_) => KeyEncoding::default(),
+        };
+        KeyFile::new(path).with_encoding(encoding).secret()
+    }
+}
+
+/// writes a new random 256 bit key to `path`, readable by its owner only. never over an
+/// existing file, whatever that key opened would be lost for good
+pub fn generate_key_file(path: impl AsRef<Path>, encoding: KeyEncoding) -> io::Result<()> {
+    let mut key = Zeroizing::new([0u8; 32]);
+    rand::rng().fill_bytes(&mut key[..]);
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    io::Write::write_all(&mut options.open(path)?, &encoding.encode(&key[..]))
+}
+
+/// the slot `create_with` and `rekey` put the creator's secret in
+pub const CREATOR_SLOT: u32 = 0;
+
+/// one wrapped copy of the archive key, stored in the clear in the header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeySlot {
+    pub id: u32,
+    /// who or what the slot is for, only for people to tell slots apart
+    pub label: String,
+    /// how the slot's secret becomes the key that unwraps it, with its own salt
+    pub kdf: KeyDerivation,
+    /// the archive key sealed with AES-GCM under the derived key
+    wrapped: Vec<u8>,
+}
+
+impl KeySlot {
+    pub(crate) fn wrap(id: u32, label: &str, kdf: KeyDerivation, key: &SecretKey, secret: &[u8]) -> io::Result<Self> {
+        let mut slot = KeySlot { id, label: label.to_string(), kdf, wrapped: Vec::new() };
+        let kek = slot.kdf.derive(secret)?;
+        slot.wrapped = encrypt::aes_seal(&kek, &key[..], &slot.aad())?;
+        Ok(slot)
+    }
+
+    /// header record payload: id u32, label (u16 len), kdf (u16 len), wrapped key.
+    /// add_key_slot turns away longer labels, a slot read back can't have one either
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let kdf = self.kdf.to_bytes();
+        let label_len = u16::try_from(self.label.len()).expect("key slot label over 64 KiB");
+        let kdf_len = u16::try_from(kdf.len()).expect("key derivation record over 64 KiB");
+        let mut out = self.id.to_le_bytes().to_vec();
+        out.extend_from_slice(&label_len.to_le_bytes());
+        out.extend_from_slice(self.label.as_bytes());
+        out.extend_from_slice(&kdf_len.to_le_bytes());
+        out.extend_from_slice(&kdf);
+        out.extend_from_slice(&self.wrapped);
+        out
+    }
+
+    pub(crate) fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        let mut fields = FieldReader::new(bytes);
+        let id = fields.u32()?;
+        let label_len = u16::from_le_bytes(fields.bytes(2)?.try_into().unwrap()) as usize;
+        let label = String::from_utf8_lossy(fields.bytes(label_len)?).into_owned();
+        let kdf_len = u16::from_le_bytes(fields.bytes(2)?.try_into().unwrap()) as usize;
+        let kdf = KeyDerivation::from_bytes(fields.bytes(kdf_len)?)?;
+        let used = 4 + 2 + label_len + 2 + kdf_len;
+        Ok(Self { id, label, kdf, wrapped: bytes[used..].to_vec() })
+    }
+
+    /// the slot id goes into the associated data, so wrapped keys can't be moved between slots.
+    /// the archive id doesn't, an old archive gets its first one on defrag and slots have to survive that
+    fn aad(&self) -> Vec<u8> {
+        let mut aad = b"shoko key slot".to_vec();
+        aad.extend_from_slice(&self.id.to_le_bytes());
+        aad
+    }
+
+    pub(crate) fn unwrap(&self, secret: &[u8]) -> Option<SecretKey> {
+        let kek = self.kdf.derive(secret).ok()?;
+        let key = Zeroizing::new(encrypt::aes_unseal(&kek, &self.wrapped, &self.aad()).ok()?);
+        SecretKey::from_slice(&key).ok()
+    }
+}
+
+impl ShokoArchive {
+    /// opens an archive and resolves its key right away, so a missing secret shows up here
+    /// and not on the first read. an archive whose header says it's unencrypted is an error,
+    /// open those with `open`
+    pub fn open_with(path: &str, provider: &dyn KeyProvider) -> io::Result<Self> {
+        let mut archive = Self::open(path)?;
+        archive.unlock_with(provider)?;
+        Ok(archive)
+    }
+
+    /// takes the secret from `provider` and keeps the archive key one of the key slots unwraps
+    /// with it for this handle. on a handle fresh from `create_with` the secret becomes slot 0
+    pub fn unlock_with(&mut self, provider: &dyn KeyProvider) -> io::Result<()> {
+        self.expect_encrypted()?;
+        let secret = provider.secret()?;
+        if let Some((kdf, key)) = self.new_key.take() {
+            return self.add_creator_slot(kdf, key, &secret);
+        }
+        let slot_key = self.key_slots.iter().find_map(|slot| slot.unwrap(&secret));
+        let key = match slot_key {
+            Some(key) => key,
+            // archives from before the random key, the secret derives the archive key itself
+            None if self.kdf != KeyDerivation::Wrapped => self.kdf.derive(&secret)?,
+            None => return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "Wrong key: none of the archive's key slots opens with it",
+            )),
+        };
+        self.use_key(key)
+    }
+
+    fn add_creator_slot(&mut self, kdf: KeyDerivation, key: SecretKey, secret: &[u8]) -> io::Result<()> {
+        let slot = match KeySlot::wrap(CREATOR_SLOT, "creator", kdf, &key, secret) {
+            Ok(slot) => slot,
+            Err(e) => {
+                // e.g. a raw key of the wrong length, the next try gets the same key
+                self.new_key = Some((kdf, key));
+                return Err(e);
+            }
+        };
+        self.use_key(key)?;
+        self.key_slots.push(slot);
+        // the slot is the only way back in, it goes to disk right away
+        self.rewrite_index()
+    }
+
+    /// how the creator's secret is stretched: slot 0's derivation, or the archive's own for
+    /// archives from before the random key
+    pub(crate) fn creator_kdf(&self) -> KeyDerivation {
+        if let Some((kdf, _)) = &self.new_key {
+            return *kdf;
+        }
+        self.key_slots.iter().find(|s| s.id == CREATOR_SLOT).map_or(self.kdf, |s| s.kdf)
+    }
+
+    /// the encryption record isn't secret, anyone can flip it to none. whoever has a key for the
+    /// archive expects it to be encrypted, so a plaintext header is never opened as if it were fine
+    pub(crate) fn expect_encrypted(&self) -> io::Result<()> {
+        if self.encryption.is_encrypted() {
+            return Ok(());
+        }
+        Err(io::Error::new(io::ErrorKind::InvalidInput, format!(
+            "{} says it isn't encrypted but a key was given, open it without one if that's expected", self.path.display()
+        )))
+    }
+
+    /// keeps `key` for this handle, whichever way it was worked out
+    pub(crate) fn use_key(&mut self, key: SecretKey) -> io::Result<()> {
+        let cipher = Cipher::new(self.encryption, key);
+        let id = cipher.key_id();
+        if let (Some(expected), Some(id)) = (self.key_id, id) {
+            if expected != id {
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, format!(
+                    "Wrong key: the archive expects key id {}, this one is {}", to_hex(&expected), to_hex(&id)
+                )));
+            }
+        }
+        let sealed = self.is_locked();
+        self.cipher = Some(cipher);
+        // older archives don't record the key id, there a sealed index is where a wrong key shows up
+        if let Err(e) = self.check_header_mac().and_then(|_| self.unseal_index()) {
+            self.cipher = None;
+            return Err(e);
+        }
+        // they get one once the key is known to be right, i.e. it opened the index or nothing
+        // was sealed under any key yet
+        if self.key_id.is_none() && (sealed || self.data_end() == HEADER_LEN) {
+            self.key_id = id;
+        }
+        Ok(())
+    }
+
+    pub fn key_slots(&self) -> &[KeySlot] {
+        &self.key_slots
+    }
+
+    /// wraps the archive key for one more secret and returns the new slot's id. the archive
+    /// has to be unlocked already, the slot uses the creator's Argon2 costs. only the header
+    /// is rewritten
+    pub fn add_key_slot(&mut self, label: &str, provider: &dyn KeyProvider) -> io::Result<u32> {
+        // the header stores its length in a u16
+        if label.len() > u16::MAX as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!(
+                "Key slot label is {} bytes, at most {} fit", label.len(), u16::MAX
+            )));
+        }
+        let key = self.cipher()?.key().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::Unsupported, "Unencrypted archives have no key to put in a slot")
+        })?;
+        let id = self.key_slots.iter().map(|s| s.id + 1).max().unwrap_or(CREATOR_SLOT + 1);
+        let kdf = KeyDerivation::argon2id(self.options().kdf.unwrap_or_default());
+        let slot = KeySlot::wrap(id, label, kdf, &key, &provider.secret()?)?;
+        self.key_slots.push(slot);
+        self.rewrite_index()?;
+        Ok(id)
+    }
+
+    /// drops a slot, its secret no longer unlocks the archive. the creator's slot 0 included,
+    /// only the last way in can't go. whoever held it may still know the archive key itself,
+    /// only a rekey takes that away
+    pub fn remove_key_slot(&mut self, id: u32) -> io::Result<()> {
+        // same as any other index change, and it keeps a locked handle from dropping slots blind
+        self.cipher()?;
+        if !self.key_slots.iter().any(|s| s.id == id) {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("No key slot {}", id)));
+        }
+        if self.kdf == KeyDerivation::Wrapped && self.key_slots.len() == 1 && self.recipients.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!(
+                "Key slot {} is the only way into the archive, add another one first", id
+            )));
+        }
+        self.key_slots.retain(|s| s.id != id);
+        self.rewrite_index()
+    }
+}
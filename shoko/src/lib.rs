@@ -10,5 +10,22 @@ pub mod glob;
 pub mod encrypt;
 pub mod mmem;
 pub mod shadow;
+pub mod pipeline;
+pub mod format;
+pub mod delta;
+pub mod solid;
+pub mod codec;
+pub mod lz;
+pub mod dict;
+pub mod chunk;
+pub mod stats;
+pub mod limits;
+pub mod keys;
+pub mod stream;
+pub mod rekey;
+pub mod sign;
+pub mod recipient;
+pub mod datakey;
+pub mod padding;
 pub use archive::ShokoArchive;
 // why are there so many of yall :sob:
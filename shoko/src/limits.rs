@@ -0,0 +1,121 @@
+// caps on how much an archive may expand to, so a tiny hostile stream (255 bytes out of every
+// 3 byte RLE record, or an LZ/delta copy loop) can't eat all memory or disk on extraction
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use crate::archive::ShokoArchive;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitKind {
+    /// one entry (or one decoded blob) got bigger than `max_entry_size`
+    EntrySize,
+    /// everything extracted through this handle got bigger than `max_total_size`
+    TotalSize,
+    /// a blob decoded to more than `max_ratio` times its stored size
+    Ratio,
+}
+
+/// the error a limit trips with, it travels inside an `io::Error` of kind `FileTooLarge`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LimitExceeded {
+    pub kind: LimitKind,
+    /// the limit in bytes, for `Ratio` the ratio itself
+    pub limit: u64,
+}
+
+impl LimitExceeded {
+    pub fn new(kind: LimitKind, limit: u64) -> Self {
+        Self { kind, limit }
+    }
+
+    /// digs a `LimitExceeded` back out of an `io::Error`, if that's what it is
+    pub fn from_io(err: &io::Error) -> Option<&LimitExceeded> {
+        err.get_ref().and_then(|e| e.downcast_ref::<LimitExceeded>())
+    }
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            LimitKind::EntrySize => write!(f, "Entry exceeds the extraction limit of {} bytes", self.limit),
+            LimitKind::TotalSize => write!(f, "Extraction exceeds the total limit of {} bytes", self.limit),
+            LimitKind::Ratio => write!(f, "Blob expands more than {}x its stored size", self.limit),
+        }
+    }
+}
+
+impl Error for LimitExceeded {}
+
+impl From<LimitExceeded> for io::Error {
+    fn from(err: LimitExceeded) -> Self {
+        io::Error::new(io::ErrorKind::FileTooLarge, err)
+    }
+}
+
+/// extraction limits, None means no limit. the default has none so trusted archives behave
+/// exactly as before, `Limits::untrusted()` is a sane starting point for everything else
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limits {
+    pub max_entry_size: Option<u64>,
+    pub max_total_size: Option<u64>,
+    pub max_ratio: Option<u64>,
+}
+
+impl Limits {
+    pub fn untrusted() -> Self {
+        Self {
+            max_entry_size: Some(1024 * 1024 * 1024),
+            max_total_size: Some(16 * 1024 * 1024 * 1024),
+            // RLE tops out at 85x, anything past this is an LZ or delta copy loop
+            max_ratio: Some(1024),
+        }
+    }
+}
+
+impl ShokoArchive {
+    /// sets the extraction limits and restarts the count `max_total_size` is checked against
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+        self.extracted = 0;
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    /// how many bytes `extract_file` has handed out since the limits were last set
+    pub fn extracted_bytes(&self) -> u64 {
+        self.extracted
+    }
+
+    /// the most a single decoded blob of `stored` bytes may expand to, and which limit that is
+    pub(crate) fn blob_bound(&self, stored: u64) -> (u64, LimitKind) {
+        let mut bound = (u64::MAX, LimitKind::EntrySize);
+        if let Some(max) = self.limits.max_entry_size {
+            bound = (max, LimitKind::EntrySize);
+        }
+        if let Some(ratio) = self.limits.max_ratio {
+            let max = stored.saturating_mul(ratio);
+            if max < bound.0 {
+                bound = (max, LimitKind::Ratio);
+            }
+        }
+        bound
+    }
+
+    /// fails if an entry that is `len` bytes so far can't be handed out anymore
+    pub(crate) fn check_entry_len(&self, len: u64) -> io::Result<()> {
+        if let Some(max) = self.limits.max_entry_size {
+            if len > max {
+                return Err(LimitExceeded::new(LimitKind::EntrySize, max).into());
+            }
+        }
+        if let Some(max) = self.limits.max_total_size {
+            if self.extracted.saturating_add(len) > max {
+                return Err(LimitExceeded::new(LimitKind::TotalSize, max).into());
+            }
+        }
+        Ok(())
+    }
+}
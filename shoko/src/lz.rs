@@ -0,0 +1,181 @@
+// small LZ77 codec that can be primed with a preset dictionary, the RLE engine can't make use
+// of one. sequences are lz4-ish: token (literal len hi nibble, match len lo nibble), literals,
+// then a varint back-reference offset. the dictionary acts as history in front of the data
+use std::io;
+
+use crate::limits::{LimitExceeded, LimitKind};
+
+const MIN_MATCH: usize = 4;
+const HASH_BITS: u32 = 16;
+
+pub fn compress(data: &[u8], dict: &[u8], level: u8) -> Vec<u8> {
+    let mut history = Vec::with_capacity(dict.len() + data.len());
+    history.extend_from_slice(dict);
+    history.extend_from_slice(data);
+
+    let depth = 1usize << level.clamp(1, 9).min(7);
+    let mut head = vec![u32::MAX; 1 << HASH_BITS];
+    let mut prev = vec![u32::MAX; history.len()];
+    let insert = |head: &mut Vec<u32>, prev: &mut Vec<u32>, pos: usize| {
+        if pos + MIN_MATCH <= history.len() {
+            let h = hash(&history[pos..]);
+            prev[pos] = head[h];
+            head[h] = pos as u32;
+        }
+    };
+
+    for pos in 0..dict.len() {
+        insert(&mut head, &mut prev, pos);
+    }
+
+    let mut out = Vec::with_capacity(data.len() / 2 + 16);
+    let mut pos = dict.len();
+    let mut literal_start = pos;
+    while pos + MIN_MATCH <= history.len() {
+        let mut best_len = 0;
+        let mut best_from = 0;
+        let mut candidate = head[hash(&history[pos..])];
+        let mut tries = depth;
+        while candidate != u32::MAX && tries > 0 {
+            let from = candidate as usize;
+            let len = common_len(&history, from, pos);
+            if len > best_len {
+                best_len = len;
+                best_from = from;
+            }
+            candidate = prev[from];
+            tries -= 1;
+        }
+
+        if best_len < MIN_MATCH {
+            insert(&mut head, &mut prev, pos);
+            pos += 1;
+            continue;
+        }
+
+        put_sequence(&mut out, &history[literal_start..pos], Some((pos - best_from, best_len)));
+        for p in pos..pos + best_len {
+            insert(&mut head, &mut prev, p);
+        }
+        pos += best_len;
+        literal_start = pos;
+    }
+    put_sequence(&mut out, &history[literal_start..], None);
+    out
+}
+
+pub fn decompress(data: &[u8], dict: &[u8]) -> io::Result<Vec<u8>> {
+    decompress_limited(data, dict, u64::MAX)
+}
+
+/// `decompress` that errors out before its output grows past `max_out` bytes
+pub fn decompress_limited(data: &[u8], dict: &[u8], max_out: u64) -> io::Result<Vec<u8>> {
+    // don't trust the ratio for the initial allocation, a bomb would get it all up front
+    let guess = (data.len() as u64 * 2).min(max_out) as usize;
+    let mut out = Vec::with_capacity(dict.len() + guess);
+    let over = |out: &Vec<u8>, more: usize| (out.len() - dict.len() + more) as u64 > max_out;
+    out.extend_from_slice(dict);
+    let mut pos = 0;
+
+    while pos < data.len() {
+        let token = data[pos];
+        pos += 1;
+
+        let mut lit_len = (token >> 4) as usize;
+        if lit_len == 15 {
+            lit_len += get_varint(data, &mut pos)?;
+        }
+        let literals = pos.checked_add(lit_len)
+            .and_then(|end| data.get(pos..end))
+            .ok_or_else(|| malformed("literal run exceeds data"))?;
+        if over(&out, lit_len) {
+            return Err(LimitExceeded::new(LimitKind::EntrySize, max_out).into());
+        }
+        out.extend_from_slice(literals);
+        pos += lit_len;
+
+        if pos == data.len() {
+            break;
+        }
+
+        let offset = get_varint(data, &mut pos)?;
+        let mut match_len = (token & 0x0f) as usize;
+        if match_len == 15 {
+            match_len += get_varint(data, &mut pos)?;
+        }
+        match_len += MIN_MATCH;
+
+        if offset == 0 || offset > out.len() {
+            return Err(malformed("back-reference out of range"));
+        }
+        if over(&out, match_len) {
+            return Err(LimitExceeded::new(LimitKind::EntrySize, max_out).into());
+        }
+        let from = out.len() - offset;
+        if offset >= match_len {
+            out.extend_from_within(from..from + match_len);
+        } else {
+            // overlapping copy, the match repeats its own output
+            for i in 0..match_len {
+                out.push(out[from + i]);
+            }
+        }
+    }
+
+    out.drain(..dict.len());
+    Ok(out)
+}
+
+fn hash(data: &[u8]) -> usize {
+    let v = u32::from_le_bytes(data[..4].try_into().unwrap());
+    (v.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize
+}
+
+fn common_len(history: &[u8], a: usize, b: usize) -> usize {
+    history[b..].iter()
+        .zip(&history[a..])
+        .take_while(|(x, y)| x == y)
+        .count()
+}
+
+fn put_sequence(out: &mut Vec<u8>, literals: &[u8], matched: Option<(usize, usize)>) {
+    let lit_nibble = literals.len().min(15) as u8;
+    let match_extra = matched.map(|(_, len)| len - MIN_MATCH).unwrap_or(0);
+    out.push((lit_nibble << 4) | match_extra.min(15) as u8);
+    if literals.len() >= 15 {
+        put_varint(out, literals.len() - 15);
+    }
+    out.extend_from_slice(literals);
+
+    if let Some((offset, _)) = matched {
+        put_varint(out, offset);
+        if match_extra >= 15 {
+            put_varint(out, match_extra - 15);
+        }
+    }
+}
+
+fn put_varint(out: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn get_varint(data: &[u8], pos: &mut usize) -> io::Result<usize> {
+    let mut value = 0usize;
+    for shift in (0..64).step_by(7) {
+        let byte = *data.get(*pos).ok_or_else(|| malformed("truncated varint"))?;
+        *pos += 1;
+        value |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(malformed("varint too long"))
+}
+
+fn malformed(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Malformed Shoko LZ stream: {}", what))
+}
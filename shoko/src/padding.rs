@@ -0,0 +1,121 @@
+// length hiding. sealed blobs are exactly as long as what's in them, which is plenty to tell a
+// known document apart from others of a different size. with a padding policy every blob is
+// padded up to a coarser size before it's sealed, and the real length goes inside with it:
+//
+//   len u64 | payload | zeros
+//
+// the policy is picked when the archive is created and recorded in its header, it covers
+// every blob the archive seals from then on (entries, solid blocks, chunks, dictionaries)
+use std::io;
+
+use crate::format::FieldReader;
+
+const LEN_PREFIX: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Padding {
+    #[default]
+    None,
+    /// up to the next power of two, hides the most but can double a blob
+    PowerOfTwo,
+    /// Padmé: at most ~12% bigger, the padded size only gives away O(log log n) bits
+    Padme,
+    /// up to a multiple of this many bytes
+    Block(u32),
+}
+
+impl Padding {
+    /// `none`, `pow2`, `padme` or `block:N`
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "none" => Some(Padding::None),
+            "pow2" => Some(Padding::PowerOfTwo),
+            "padme" => Some(Padding::Padme),
+            _ => name.strip_prefix("block:")
+                .and_then(|n| n.parse().ok())
+                .filter(|n| *n > 0)
+                .map(Padding::Block),
+        }
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            Padding::None => "none".to_string(),
+            Padding::PowerOfTwo => "pow2".to_string(),
+            Padding::Padme => "padme".to_string(),
+            Padding::Block(n) => format!("block:{}", n),
+        }
+    }
+
+    /// how long a padded plaintext of `len` bytes ends up
+    pub fn padded_len(&self, len: u64) -> u64 {
+        match *self {
+            Padding::None => len,
+            Padding::PowerOfTwo => len.max(1).next_power_of_two(),
+            Padding::Padme => padme(len),
+            Padding::Block(n) => len.div_ceil(n as u64).max(1) * n as u64,
+        }
+    }
+
+    /// frames `payload` with its length and pads it, what gets sealed
+    pub fn pad(&self, payload: Vec<u8>) -> Vec<u8> {
+        if *self == Padding::None {
+            return payload;
+        }
+        let framed = (LEN_PREFIX + payload.len()) as u64;
+        let mut out = Vec::with_capacity(self.padded_len(framed) as usize);
+        out.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+        out.extend_from_slice(&payload);
+        out.resize(self.padded_len(framed) as usize, 0);
+        out
+    }
+
+    /// the payload `pad` framed, without the padding
+    pub fn unpad<'a>(&self, padded: &'a [u8]) -> io::Result<&'a [u8]> {
+        if *self == Padding::None {
+            return Ok(padded);
+        }
+        let malformed = || io::Error::new(io::ErrorKind::InvalidData, "Malformed padded blob");
+        let len = padded.get(..LEN_PREFIX).ok_or_else(malformed)?;
+        let len = u64::from_le_bytes(len.try_into().unwrap());
+        let end = (LEN_PREFIX as u64).checked_add(len).filter(|end| *end <= padded.len() as u64).ok_or_else(malformed)?;
+        Ok(&padded[LEN_PREFIX..end as usize])
+    }
+
+    /// header record payload: kind u8, block size u32
+    pub(crate) fn to_bytes(self) -> Vec<u8> {
+        let (kind, block) = match self {
+            Padding::None => (0u8, 0),
+            Padding::PowerOfTwo => (1, 0),
+            Padding::Padme => (2, 0),
+            Padding::Block(n) => (3, n),
+        };
+        let mut out = vec![kind];
+        out.extend_from_slice(&block.to_le_bytes());
+        out
+    }
+
+    pub(crate) fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        let mut fields = FieldReader::new(bytes);
+        let (kind, block) = (fields.u8()?, fields.u32()?);
+        match (kind, block) {
+            (0, _) => Ok(Padding::None),
+            (1, _) => Ok(Padding::PowerOfTwo),
+            (2, _) => Ok(Padding::Padme),
+            (3, n) if n > 0 => Ok(Padding::Block(n)),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unknown padding policy {}", kind))),
+        }
+    }
+}
+
+/// Padmé (Nikitin et al., "Reducing Metadata Leakage from Encrypted Files and Communication
+/// with PURBs"): keep the top bits of the length, round the low ones up
+fn padme(len: u64) -> u64 {
+    if len < 2 {
+        return len;
+    }
+    let e = 63 - len.leading_zeros() as u64;
+    let s = 64 - e.leading_zeros() as u64;
+    let mask = (1u64 << (e - s)) - 1;
+    (len + mask) & !mask
+}
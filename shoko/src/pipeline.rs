@@ -0,0 +1,382 @@
+// parallel pack pipeline: walk the tree on a few threads, compress + encrypt on a worker pool,
+// and hand the sealed blobs to a single writer that appends them in a stable order
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io::{self, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Condvar, Mutex};
+use std::thread;
+
+use crate::archive::{BlobRef, ShokoArchive, ShokoEntry, SolidSlice};
+use crate::chunk::{self, ChunkHash, StoredChunk};
+use crate::encrypt::BlobBinding;
+use crate::write::{Sealer, ShokoWriter};
+
+/// where the bytes of a bulk insert come from, paths are only read by the worker that seals them
+pub enum PackSource {
+    File(PathBuf),
+    Bytes(Vec<u8>),
+}
+
+pub struct PackItem {
+    pub path: String,
+    pub source: PackSource,
+}
+
+impl PackItem {
+    pub fn file(path: impl Into<String>, source: impl Into<PathBuf>) -> Self {
+        Self { path: path.into(), source: PackSource::File(source.into()) }
+    }
+
+    pub fn bytes(path: impl Into<String>, data: Vec<u8>) -> Self {
+        Self { path: path.into(), source: PackSource::Bytes(data) }
+    }
+}
+
+/// falls back to 1 if the platform can't tell us
+pub fn default_jobs() -> usize {
+    thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum UnitKind {
+    /// one member, one blob
+    Plain,
+    /// all members concatenated into one shared block
+    Solid,
+    /// one member, split into deduplicated chunks
+    Chunked,
+}
+
+/// one job for the worker pool
+pub(crate) struct PackUnit {
+    pub members: Vec<PackItem>,
+    pub kind: UnitKind,
+}
+
+/// what a worker hands back to the writer for one unit
+#[derive(Default)]
+struct SealedUnit {
+    /// the unit's own blob, plain and solid units only
+    blob: Option<Vec<u8>>,
+    /// per member, how many bytes it had before compression
+    original_sizes: Vec<u64>,
+    /// per member, where it sits in the solid block
+    slices: Vec<Option<SolidSlice>>,
+    /// per member, the chunks it is made of if it's a chunked entry
+    chunk_lists: Vec<Option<Vec<ChunkHash>>>,
+    /// chunks this unit was the first to see, still to be appended, each with its own wrapped data key
+    new_chunks: Vec<(ChunkHash, Vec<u8>, Option<Vec<u8>>)>,
+    /// the unit's own wrapped data key, every member gets it
+    data_key: Option<Vec<u8>>,
+}
+
+/// state the workers share while sealing
+struct SealContext<'a> {
+    sealer: &'a Sealer,
+    clevel: u8,
+    /// unit i is sealed under generation `first_generation + i`
+    first_generation: u64,
+    /// chunks that are stored already or that some worker has promised to write
+    claimed: Mutex<HashSet<ChunkHash>>,
+}
+
+impl PackUnit {
+    fn load(source: PackSource) -> io::Result<Vec<u8>> {
+        match source {
+            PackSource::File(p) => fs::read(p),
+            PackSource::Bytes(d) => Ok(d),
+        }
+    }
+
+    fn single(self) -> io::Result<Vec<u8>> {
+        let member = self.members.into_iter().next().ok_or_else(|| io::Error::other("empty pack unit"))?;
+        Self::load(member.source)
+    }
+
+    fn seal(self, ctx: &SealContext, generation: u64) -> io::Result<SealedUnit> {
+        let mut out = SealedUnit::default();
+        match self.kind {
+            UnitKind::Plain => {
+                let path = self.members.first().map(|m| m.path.clone()).unwrap_or_default();
+                let data = self.single()?;
+                out.original_sizes.push(data.len() as u64);
+                let (sealer, data_key) = ctx.sealer.with_data_key()?;
+                out.blob = Some(sealer.seal(&data, ctx.clevel, BlobBinding::Entry { path: &path, generation })?);
+                out.data_key = data_key;
+                out.slices.push(None);
+                out.chunk_lists.push(None);
+            }
+            UnitKind::Solid => {
+                let mut block = Vec::new();
+                for member in self.members {
+                    let data = Self::load(member.source)?;
+                    out.original_sizes.push(data.len() as u64);
+                    out.slices.push(Some(SolidSlice { offset: block.len() as u64, len: data.len() as u64 }));
+                    out.chunk_lists.push(None);
+                    block.extend_from_slice(&data);
+                }
+                let (sealer, data_key) = ctx.sealer.with_data_key()?;
+                out.blob = Some(sealer.seal(&block, ctx.clevel, BlobBinding::Solid { generation })?);
+                out.data_key = data_key;
+            }
+            UnitKind::Chunked => {
+                let data = self.single()?;
+                out.original_sizes.push(data.len() as u64);
+                let mut hashes = Vec::new();
+                for piece in chunk::split(&data) {
+                    let hash = chunk::hash(piece);
+                    if ctx.claimed.lock().unwrap().insert(hash) {
+                        let (sealer, data_key) = ctx.sealer.with_data_key()?;
+                        out.new_chunks.push((hash, sealer.seal(piece, ctx.clevel, BlobBinding::Chunk(&hash))?, data_key));
+                    }
+                    hashes.push(hash);
+                }
+                out.slices.push(None);
+                out.chunk_lists.push(Some(hashes));
+            }
+        }
+        Ok(out)
+    }
+}
+
+impl ShokoArchive {
+    /// packs every file under `root` using `jobs` threads, entries are named relative to `root`
+    /// with `/` separators and written in sorted order so the output doesn't depend on scheduling
+    pub fn pack_dir(&mut self, root: &Path, clevel: u8, jobs: usize) -> io::Result<Vec<String>> {
+        let items = walk_parallel(root, jobs)?
+            .into_iter()
+            .map(|(path, source)| PackItem::file(path, source))
+            .collect();
+        self.write_files_parallel(items, clevel, jobs)
+    }
+
+    /// bulk insert: blobs are sealed on `jobs` workers and appended in the order given,
+    /// the index is only rewritten once at the end. returns the paths that were written
+    pub fn write_files_parallel(&mut self, items: Vec<PackItem>, clevel: u8, jobs: usize) -> io::Result<Vec<String>> {
+        let units = items.into_iter()
+            .map(|item| PackUnit { members: vec![item], kind: UnitKind::Plain })
+            .collect();
+        self.write_units(units, clevel, jobs)
+    }
+
+    pub(crate) fn write_units(&mut self, units: Vec<PackUnit>, clevel: u8, jobs: usize) -> io::Result<Vec<String>> {
+        // a path given twice is written once, the last one wins like any other overwrite
+        let mut seen = HashSet::new();
+        let mut units: Vec<PackUnit> = units.into_iter().rev().filter_map(|mut unit| {
+            unit.members.reverse();
+            unit.members.retain(|m| seen.insert(m.path.clone()));
+            unit.members.reverse();
+            (!unit.members.is_empty()).then_some(unit)
+        }).collect();
+        units.reverse();
+
+        let jobs = jobs.max(1);
+        // how far workers may run ahead of the writer, keeps memory bounded on huge trees
+        let window = jobs * 4;
+
+        let sealer = self.sealer()?;
+        let first_generation = self.generation + 1;
+        // every unit gets its number up front, even ones that end up failing, so none is reused
+        self.generation += units.len() as u64;
+        let ctx = SealContext {
+            sealer: &sealer,
+            clevel,
+            first_generation,
+            claimed: Mutex::new(self.chunks.keys().copied().collect()),
+        };
+        let mut offset = self.data_end();
+        let paths: Vec<Vec<String>> = units.iter()
+            .map(|u| u.members.iter().map(|m| m.path.clone()).collect())
+            .collect();
+        let slots: Vec<Mutex<Option<PackUnit>>> = units.into_iter()
+            .map(|u| Mutex::new(Some(u)))
+            .collect();
+
+        let next = AtomicUsize::new(0);
+        let written = Mutex::new(0usize);
+        let progress = Condvar::new();
+        let abort = AtomicBool::new(false);
+        let mut added = Vec::new();
+
+        let result = thread::scope(|scope| -> io::Result<()> {
+            let (tx, rx) = mpsc::sync_channel::<(usize, io::Result<SealedUnit>)>(window);
+
+            for _ in 0..jobs {
+                let tx = tx.clone();
+                let (slots, next, written, progress, abort, ctx) = (&slots, &next, &written, &progress, &abort, &ctx);
+                scope.spawn(move || loop {
+                    let idx = next.fetch_add(1, Ordering::SeqCst);
+                    if idx >= slots.len() {
+                        break;
+                    }
+
+                    {
+                        let mut done = written.lock().unwrap();
+                        while idx >= *done + window && !abort.load(Ordering::SeqCst) {
+                            done = progress.wait(done).unwrap();
+                        }
+                    }
+                    if abort.load(Ordering::SeqCst) {
+                        break;
+                    }
+
+                    let sealed = match slots[idx].lock().unwrap().take() {
+                        Some(unit) => unit.seal(ctx, ctx.first_generation + idx as u64),
+                        None => Err(io::Error::other("pack unit taken twice")),
+                    };
+                    if tx.send((idx, sealed)).is_err() {
+                        break;
+                    }
+                });
+            }
+            drop(tx);
+
+            let stop = |e: io::Error| {
+                abort.store(true, Ordering::SeqCst);
+                progress.notify_all();
+                e
+            };
+
+            let mut pending = BTreeMap::new();
+            let mut expected = 0;
+            self.file.seek(SeekFrom::Start(offset)).map_err(stop)?;
+
+            for (idx, sealed) in rx {
+                pending.insert(idx, sealed);
+                while let Some(sealed) = pending.remove(&expected) {
+                    let sealed = sealed.map_err(|e| {
+                        stop(io::Error::new(e.kind(), format!("{}: {}", paths[expected].join(", "), e)))
+                    })?;
+
+                    for (hash, chunk_blob, data_key) in sealed.new_chunks {
+                        let size = ShokoWriter::new(&mut self.file).write_raw(&chunk_blob).map_err(stop)?;
+                        let blob = BlobRef { offset, size, compression_level: clevel };
+                        self.chunks.insert(hash, StoredChunk { blob, data_key });
+                        offset += size;
+                    }
+
+                    let (blob_offset, size) = match sealed.blob {
+                        Some(blob) => (offset, ShokoWriter::new(&mut self.file).write_raw(&blob).map_err(stop)?),
+                        None => (0, 0),
+                    };
+                    offset += size;
+
+                    let members = paths[expected].iter()
+                        .zip(sealed.original_sizes)
+                        .zip(sealed.slices)
+                        .zip(sealed.chunk_lists);
+                    for (((path, original_size), solid), chunks) in members {
+                        added.push(ShokoEntry {
+                            path: path.clone(),
+                            size,
+                            offset: blob_offset,
+                            compression_level: clevel,
+                            original_size: Some(original_size),
+                            solid,
+                            chunks,
+                            generation: first_generation + expected as u64,
+                            data_key: sealed.data_key.clone(),
+                            ..Default::default()
+                        });
+                    }
+                    expected += 1;
+
+                    *written.lock().unwrap() = expected;
+                    progress.notify_all();
+                }
+            }
+            Ok(())
+        });
+
+        if result.is_err() {
+            // a chunk claimed by a unit that never got written can't be referenced
+            added.retain(|e| e.chunks.iter().flatten().all(|h| self.chunks.contains_key(h)));
+        }
+
+        // even on failure the blobs that did make it are indexed, same as a partial sequential pack
+        let replaced: HashSet<&str> = added.iter().map(|e: &ShokoEntry| e.path.as_str()).collect();
+        self.entries.retain(|e| !replaced.contains(e.path.as_str()));
+        self.entries.extend(added);
+        self.rewrite_index()?;
+        result?;
+        Ok(paths.into_iter().flatten().collect())
+    }
+}
+
+/// lists every regular file under `root` as (internal path, source path), sorted by internal path.
+/// directories are handed out from a shared queue so big subtrees get spread over the threads
+pub fn walk_parallel(root: &Path, jobs: usize) -> io::Result<Vec<(String, PathBuf)>> {
+    struct WalkState {
+        queue: Vec<(PathBuf, String)>,
+        active: usize,
+        failed: Option<io::Error>,
+    }
+
+    let state = Mutex::new(WalkState {
+        queue: vec![(root.to_path_buf(), String::new())],
+        active: 0,
+        failed: None,
+    });
+    let wake = Condvar::new();
+    let found = Mutex::new(Vec::new());
+
+    thread::scope(|scope| {
+        for _ in 0..jobs.max(1) {
+            scope.spawn(|| loop {
+                let (dir, prefix) = {
+                    let mut st = state.lock().unwrap();
+                    loop {
+                        if st.failed.is_some() {
+                            return;
+                        }
+                        if let Some(job) = st.queue.pop() {
+                            st.active += 1;
+                            break job;
+                        }
+                        if st.active == 0 {
+                            return;
+                        }
+                        st = wake.wait(st).unwrap();
+                    }
+                };
+
+                let mut subdirs = Vec::new();
+                let mut files = Vec::new();
+                let listed = fs::read_dir(&dir).and_then(|rd| {
+                    for entry in rd {
+                        let path = entry?.path();
+                        let name = path.file_name()
+                            .and_then(|n| n.to_str())
+                            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("Non UTF-8 file name: {}", path.display())))?;
+                        let internal_name = if prefix.is_empty() { name.to_string() } else { format!("{}/{}", prefix, name) };
+
+                        if path.is_dir() {
+                            subdirs.push((path, internal_name));
+                        } else {
+                            files.push((internal_name, path));
+                        }
+                    }
+                    Ok(())
+                });
+
+                found.lock().unwrap().extend(files);
+                let mut st = state.lock().unwrap();
+                st.active -= 1;
+                match listed {
+                    Ok(()) => st.queue.extend(subdirs),
+                    Err(e) => st.failed = Some(e),
+                }
+                wake.notify_all();
+            });
+        }
+    });
+
+    if let Some(e) = state.into_inner().unwrap().failed {
+        return Err(e);
+    }
+    let mut files = found.into_inner().unwrap();
+    files.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(files)
+}
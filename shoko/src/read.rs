@@ -1,54 +1,54 @@
 use std::io::{self, Read, Seek, SeekFrom};
 use std::fs::File;
-use crate::decompress::decompress;
-use crate::encrypt;
+use crate::archive::{BlobRef, ShokoEntry, SolidSlice};
+use crate::codec;
+use crate::encrypt::Cipher;
+use crate::format::{self, ExtReader, FieldReader};
 
 pub struct ShokoReader<'a> {
     handle: &'a mut File,
+    cipher: Option<Cipher>,
 }
 
 impl<'a> ShokoReader<'a> {
     pub fn new(handle: &'a mut File) -> Self {
-        Self { handle }
+        Self { handle, cipher: None }
     }
 
+    /// what blobs read through this reader are opened with, `Cipher::from_env` for archives
+    /// without an encryption record. there's no default, the key is worked out once by the caller
+    pub fn with_cipher(mut self, cipher: Cipher) -> Self {
+        self.cipher = Some(cipher);
+        self
+    }
+
+    /// reads and decodes a blob that doesn't need a dictionary, see `ShokoArchive::read_blob_ref` for the rest
     pub fn read_blob(&mut self, offset: u64, size: u64, clevel: u8) -> io::Result<Vec<u8>> {
+        let decrypted_buffer = self.read_sealed(offset, size, &[])?;
+        codec::decode(&decrypted_buffer, clevel, None)
+    }
+
+    /// reads and decrypts a blob but leaves it compressed, `aad` has to be what it was sealed with
+    pub fn read_sealed(&mut self, offset: u64, size: u64, aad: &[u8]) -> io::Result<Vec<u8>> {
         self.handle.seek(SeekFrom::Start(offset))?;
-        
+
         let mut buffer = vec![0u8; size as usize];
         self.handle.read_exact(&mut buffer)?;
 
-        let decrypted_buffer = encrypt::decrypt_data(&buffer)?;
-
-        if clevel > 0 {
-            decompress(&decrypted_buffer).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
-        } else {
-            Ok(decrypted_buffer)
-        }
+        let cipher = self.cipher.as_ref().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "No cipher to open the blob with, see ShokoReader::with_cipher")
+        })?;
+        cipher.unseal(&buffer, aad)
     }
 
-    pub fn read_index_entry(&mut self) -> io::Result<(String, u64, u64, u8)> {
-        let mut len_buf = [0u8; 4];
-        self.handle.read_exact(&mut len_buf)?;
-        let path_len = u32::from_le_bytes(len_buf) as usize;
-
-        let mut path_bytes = vec![0u8; path_len];
-        self.handle.read_exact(&mut path_bytes)?;
-        let path = String::from_utf8_lossy(&path_bytes).into_owned();
-
-        let mut size_buf = [0u8; 8];
-        self.handle.read_exact(&mut size_buf)?;
-        let size = u64::from_le_bytes(size_buf);
-
-        let mut offset_buf = [0u8; 8];
-        self.handle.read_exact(&mut offset_buf)?;
-        let offset = u64::from_le_bytes(offset_buf);
-
-        let mut clevel_buf = [0u8; 1];
-        self.handle.read_exact(&mut clevel_buf)?;
-        let clevel = clevel_buf[0];
+    /// the archive header block at the front of a v3+ index
+    pub fn read_header_block(&mut self) -> io::Result<Vec<u8>> {
+        read_header_block(self.handle)
+    }
 
-        Ok((path, size, offset, clevel))
+    /// reads one index entry, `version` is the archive format (v1 entries have no extension block)
+    pub fn read_index_entry(&mut self, version: u8) -> io::Result<ShokoEntry> {
+        read_index_entry(self.handle, version)
     }
 
     pub fn get_footer_info(&mut self) -> io::Result<(u64, u32)> {
@@ -76,3 +76,99 @@ impl<'a> ShokoReader<'a> {
         Ok((index_start, entry_count))
     }
 }
+
+/// `ShokoReader::read_header_block` over anything readable, the sealed index is parsed from memory
+pub(crate) fn read_header_block(handle: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut len_buf = [0u8; 4];
+    handle.read_exact(&mut len_buf)?;
+    let mut block = vec![0u8; u32::from_le_bytes(len_buf) as usize];
+    handle.read_exact(&mut block)?;
+    Ok(block)
+}
+
+pub(crate) fn read_index_entry(handle: &mut impl Read, version: u8) -> io::Result<ShokoEntry> {
+    let mut len_buf = [0u8; 4];
+    handle.read_exact(&mut len_buf)?;
+    let path_len = u32::from_le_bytes(len_buf) as usize;
+
+    let mut path_bytes = vec![0u8; path_len];
+    handle.read_exact(&mut path_bytes)?;
+    let path = String::from_utf8_lossy(&path_bytes).into_owned();
+
+    let mut size_buf = [0u8; 8];
+    handle.read_exact(&mut size_buf)?;
+    let size = u64::from_le_bytes(size_buf);
+
+    let mut offset_buf = [0u8; 8];
+    handle.read_exact(&mut offset_buf)?;
+    let offset = u64::from_le_bytes(offset_buf);
+
+    let mut clevel_buf = [0u8; 1];
+    handle.read_exact(&mut clevel_buf)?;
+    let clevel = clevel_buf[0];
+
+    let mut entry = ShokoEntry {
+        path,
+        size,
+        offset,
+        compression_level: clevel,
+        ..Default::default()
+    };
+
+    if version >= 2 {
+        handle.read_exact(&mut len_buf)?;
+        let mut ext = vec![0u8; u32::from_le_bytes(len_buf) as usize];
+        handle.read_exact(&mut ext)?;
+        apply_extensions(&mut entry, &ext)?;
+    }
+
+    Ok(entry)
+}
+
+fn apply_extensions(entry: &mut ShokoEntry, ext: &[u8]) -> io::Result<()> {
+    let mut records = ExtReader::new(ext);
+    while let Some((tag, payload)) = records.next_record()? {
+        let mut fields = FieldReader::new(payload);
+        match tag {
+            format::EXT_DELTA_CHAIN => {
+                let count = fields.u32()?;
+                for _ in 0..count {
+                    entry.delta_chain.push(BlobRef {
+                        offset: fields.u64()?,
+                        size: fields.u64()?,
+                        compression_level: fields.u8()?,
+                    });
+                }
+            }
+            format::EXT_SOLID => {
+                entry.solid = Some(SolidSlice {
+                    offset: fields.u64()?,
+                    len: fields.u64()?,
+                });
+            }
+            format::EXT_CHUNKS => {
+                let count = fields.u32()?;
+                let mut chunks = Vec::with_capacity(count as usize);
+                for _ in 0..count {
+                    chunks.push(fields.bytes(32)?.try_into().unwrap());
+                }
+                entry.chunks = Some(chunks);
+            }
+            format::EXT_ORIGINAL_SIZE => {
+                entry.original_size = Some(fields.u64()?);
+            }
+            format::EXT_DATA_KEY => {
+                entry.data_key = Some(payload.to_vec());
+            }
+            format::EXT_GENERATION => {
+                // the entry's own generation, then one per delta chain link
+                entry.generation = fields.u64()?;
+                for _ in 1..payload.len() / 8 {
+                    entry.delta_generations.push(fields.u64()?);
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,166 @@
+// public key recipients, the way age does it. the archive key is random and gets wrapped once
+// per recipient under a key only the matching X25519 identity can work out: every stanza has its
+// own ephemeral key pair, and the shared secret goes through HKDF. stanzas don't say who they're
+// for, so the header doesn't give away who an archive was sent to, an identity just tries each one
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use hkdf::Hkdf;
+use rand::RngCore;
+use sha2::Sha256;
+
+pub use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::archive::{ArchiveOptions, ShokoArchive};
+use crate::encrypt::{self, Cipher};
+use crate::shadow::SecretKey;
+use crate::sign::{from_hex, to_hex, write_private};
+use zeroize::Zeroizing;
+
+const HKDF_INFO: &[u8] = b"shoko x25519 recipient v1";
+const WRAP_AAD: &[u8] = b"shoko recipient";
+
+/// the archive key wrapped for one recipient, as stored in the header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecipientStanza {
+    ephemeral: [u8; 32],
+    wrapped: Vec<u8>,
+}
+
+impl RecipientStanza {
+    fn wrap(recipient: &PublicKey, key: &SecretKey) -> io::Result<Self> {
+        let ephemeral_secret = StaticSecret::from(*random_bytes());
+        let ephemeral = PublicKey::from(&ephemeral_secret);
+        let shared = ephemeral_secret.diffie_hellman(recipient);
+        if !shared.was_contributory() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Not a usable X25519 public key"));
+        }
+        let kek = wrapping_key(shared.as_bytes(), &ephemeral, recipient)?;
+        Ok(Self { ephemeral: ephemeral.to_bytes(), wrapped: encrypt::aes_seal(&kek, &key[..], WRAP_AAD)? })
+    }
+
+    fn unwrap(&self, identity: &StaticSecret) -> Option<SecretKey> {
+        let ephemeral = PublicKey::from(self.ephemeral);
+        let shared = identity.diffie_hellman(&ephemeral);
+        if !shared.was_contributory() {
+            return None;
+        }
+        let kek = wrapping_key(shared.as_bytes(), &ephemeral, &PublicKey::from(identity)).ok()?;
+        let key = Zeroizing::new(encrypt::aes_unseal(&kek, &self.wrapped, WRAP_AAD).ok()?);
+        SecretKey::from_slice(&key).ok()
+    }
+
+    /// header record payload: ephemeral public key, then the wrapped key
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let mut out = self.ephemeral.to_vec();
+        out.extend_from_slice(&self.wrapped);
+        out
+    }
+
+    pub(crate) fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        if bytes.len() < 32 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Malformed recipient record"));
+        }
+        Ok(Self { ephemeral: bytes[..32].try_into().unwrap(), wrapped: bytes[32..].to_vec() })
+    }
+}
+
+/// both public keys go in the salt, so the wrapping key belongs to this exact pair
+fn wrapping_key(shared: &[u8; 32], ephemeral: &PublicKey, recipient: &PublicKey) -> io::Result<SecretKey> {
+    let mut salt = ephemeral.to_bytes().to_vec();
+    salt.extend_from_slice(recipient.as_bytes());
+    SecretKey::new_with(|kek| {
+        Hkdf::<Sha256>::new(Some(&salt), shared)
+            .expand(HKDF_INFO, kek)
+            .map_err(|e| io::Error::other(e.to_string()))
+    })
+}
+
+fn random_bytes() -> Zeroizing<[u8; 32]> {
+    let mut bytes = Zeroizing::new([0u8; 32]);
+    rand::rng().fill_bytes(&mut bytes[..]);
+    bytes
+}
+
+pub fn generate_identity() -> StaticSecret {
+    StaticSecret::from(*random_bytes())
+}
+
+/// an identity file holds the X25519 secret as hex, readable by its owner only. an existing file is an error
+pub fn write_identity(path: impl AsRef<Path>, identity: &StaticSecret) -> io::Result<()> {
+    write_private(path.as_ref(), format!("{}\n", to_hex(identity.as_bytes())).as_bytes())
+}
+
+pub fn read_identity(path: impl AsRef<Path>) -> io::Result<StaticSecret> {
+    let text = Zeroizing::new(fs::read_to_string(path)?);
+    let bytes = Zeroizing::new(from_hex(&text)?);
+    let bytes: [u8; 32] = bytes[..].try_into()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "An identity is 32 bytes"))?;
+    Ok(StaticSecret::from(bytes))
+}
+
+pub fn write_recipient(path: impl AsRef<Path>, recipient: &PublicKey) -> io::Result<()> {
+    fs::write(path, format!("{}\n", to_hex(recipient.as_bytes())))
+}
+
+/// a recipient's public key as hex, either straight from the argument or from the file it names
+pub fn read_recipient(path_or_hex: &str) -> io::Result<PublicKey> {
+    let text = match from_hex(path_or_hex) {
+        Ok(_) => path_or_hex.to_string(),
+        Err(_) => fs::read_to_string(path_or_hex)?,
+    };
+    let bytes: [u8; 32] = from_hex(&text)?.try_into()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "A recipient key is 32 bytes"))?;
+    Ok(PublicKey::from(bytes))
+}
+
+impl ShokoArchive {
+    /// a new archive that only `recipients` can open, with their identities. there's no creator
+    /// slot, `options.kdf` is ignored
+    pub fn create_for(path: &str, options: ArchiveOptions, recipients: &[PublicKey]) -> io::Result<Self> {
+        if !options.encryption.is_encrypted() || recipients.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Recipients need an encrypted archive and at least one key"));
+        }
+        let mut archive = Self::create_with(path, ArchiveOptions { kdf: None, ..options })?;
+        // the recipients take the creator's place
+        let (_, key) = archive.new_key.take().unwrap();
+        for recipient in recipients {
+            archive.recipients.push(RecipientStanza::wrap(recipient, &key)?);
+        }
+        let cipher = Cipher::new(archive.encryption, key);
+        archive.key_id = cipher.key_id();
+        archive.cipher = Some(cipher);
+        archive.rewrite_index()?;
+        Ok(archive)
+    }
+
+    pub fn open_with_identity(path: &str, identity: &StaticSecret) -> io::Result<Self> {
+        let mut archive = Self::open(path)?;
+        archive.unlock_with_identity(identity)?;
+        Ok(archive)
+    }
+
+    pub fn unlock_with_identity(&mut self, identity: &StaticSecret) -> io::Result<()> {
+        self.expect_encrypted()?;
+        let key = self.recipients.iter().find_map(|r| r.unwrap(identity)).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::PermissionDenied, "This identity isn't one of the archive's recipients")
+        })?;
+        self.use_key(key)
+    }
+
+    /// lets one more public key open the archive, which has to be unlocked already. works for
+    /// passphrase archives too, only the header is rewritten
+    pub fn add_recipient(&mut self, recipient: &PublicKey) -> io::Result<()> {
+        let key = self.cipher()?.key().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::Unsupported, "Unencrypted archives have no key to share")
+        })?;
+        self.recipients.push(RecipientStanza::wrap(recipient, &key)?);
+        self.rewrite_index()
+    }
+
+    /// how many recipient stanzas the header has, who they're for isn't recorded
+    pub fn recipient_count(&self) -> usize {
+        self.recipients.len()
+    }
+}
@@ -0,0 +1,348 @@
+// rotating an archive's key. every blob is opened with the old key and sealed again under the
+// new one into a copy next to the archive, which is renamed over it once every entry has been
+// read back. a crash before that leaves the original alone, and the journal next to the copy
+// records which blobs are done so the next run carries on instead of starting over.
+//
+// the new key is random like any new archive's, and the new secret becomes its creator slot.
+// only the encryption changes: payloads stay compressed as they are and keep their associated
+// data, so solid blocks, chunks, deltas and dictionaries come across untouched. entries and
+// chunks with a data key get a fresh one, the old key could unwrap the old ones
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+
+use rand::RngCore;
+
+use crate::archive::{BlobRef, ShokoArchive};
+use crate::chunk::StoredChunk;
+use crate::datakey;
+use crate::dict::DictionarySlot;
+use crate::encrypt::{BlobBinding, Cipher, KeyDerivation};
+use crate::shadow::SecretKey;
+use crate::format::{FieldReader, CURRENT_VERSION, HEADER_LEN, MAGIC_V3};
+use crate::keys::{KeyProvider, KeySlot, CREATOR_SLOT};
+use crate::read::ShokoReader;
+use crate::write::ShokoWriter;
+
+const JOURNAL_MAGIC: &[u8; 8] = b"SKREKEY2";
+/// old offset, new offset, new size
+const RECORD_LEN: usize = 24;
+/// how much gets re-sealed between syncs, a crash loses at most this much work
+const BATCH_BYTES: u64 = 64 * 1024 * 1024;
+/// the new creator slot goes in the journal header, so a resumed run gets the same new key back.
+/// next to it a blob sealed under that key holds the salt the new data keys come from
+const CHECK_AAD: &[u8] = b"shoko rekey check";
+
+/// what a finished `rekey` did
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RekeySummary {
+    /// blobs sealed under the new key
+    pub blobs: usize,
+    /// how many of those an interrupted earlier run had already done
+    pub resumed: usize,
+    /// key slots and recipient stanzas wrap the old key, they're dropped and have to be added
+    /// again. the new creator slot isn't counted
+    pub slots_dropped: usize,
+}
+
+/// one blob to re-seal, with the associated data it was sealed under and the wrapped data key
+/// of the entry it belongs to, if it has one
+struct RekeyJob {
+    blob: BlobRef,
+    aad: Vec<u8>,
+    data_key: Option<Vec<u8>>,
+}
+
+/// the journal of a rekey in progress: the header ties it to the archive and the new key slot,
+/// then one record per blob that made it to disk in the copy
+struct Journal {
+    file: File,
+    done: BTreeMap<u64, (u64, u64)>,
+    pending: Vec<u8>,
+}
+
+impl Journal {
+    fn create(path: &str, source_len: u64, slot: &KeySlot, check: &[u8]) -> io::Result<Self> {
+        let mut file = File::create(path)?;
+        let slot = slot.to_bytes();
+        let mut header = JOURNAL_MAGIC.to_vec();
+        header.extend_from_slice(&source_len.to_le_bytes());
+        header.extend_from_slice(&(slot.len() as u32).to_le_bytes());
+        header.extend_from_slice(&slot);
+        header.extend_from_slice(&(check.len() as u32).to_le_bytes());
+        header.extend_from_slice(check);
+        file.write_all(&header)?;
+        file.sync_all()?;
+        Ok(Self { file, done: BTreeMap::new(), pending: Vec::new() })
+    }
+
+    /// the header fields and the finished blobs. a record cut short by a crash is ignored
+    fn open(path: &str) -> io::Result<(Self, u64, KeySlot, Vec<u8>)> {
+        let bytes = fs::read(path)?;
+        let mut fields = FieldReader::new(&bytes);
+        if fields.bytes(8)? != JOURNAL_MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Not a Shoko rekey journal"));
+        }
+        let source_len = fields.u64()?;
+        let slot_len = fields.u32()? as usize;
+        let slot = KeySlot::from_bytes(fields.bytes(slot_len)?)?;
+        let check_len = fields.u32()? as usize;
+        let check = fields.bytes(check_len)?.to_vec();
+        let header_len = 8 + 8 + 4 + slot_len + 4 + check_len;
+
+        let mut done = BTreeMap::new();
+        let records = &bytes[header_len..];
+        for record in records.chunks_exact(RECORD_LEN) {
+            let field = |i: usize| u64::from_le_bytes(record[i * 8..i * 8 + 8].try_into().unwrap());
+            done.insert(field(0), (field(1), field(2)));
+        }
+
+        let mut file = OpenOptions::new().write(true).open(path)?;
+        let whole = header_len + records.len() / RECORD_LEN * RECORD_LEN;
+        file.set_len(whole as u64)?;
+        file.seek(SeekFrom::End(0))?;
+        Ok((Self { file, done, pending: Vec::new() }, source_len, slot, check))
+    }
+
+    fn record(&mut self, old: u64, new: BlobRef) {
+        for value in [old, new.offset, new.size] {
+            self.pending.extend_from_slice(&value.to_le_bytes());
+        }
+        self.done.insert(old, (new.offset, new.size));
+    }
+
+    /// the copy has to be on disk before the journal says its blobs are
+    fn commit(&mut self, copy: &File) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        copy.sync_data()?;
+        self.file.write_all(&self.pending)?;
+        self.file.sync_data()?;
+        self.pending.clear();
+        Ok(())
+    }
+
+    /// where the next blob goes in the copy
+    fn end(&self) -> u64 {
+        self.done.values().map(|(offset, size)| offset + size).max().unwrap_or(HEADER_LEN)
+    }
+}
+
+impl ShokoArchive {
+    /// re-encrypts every blob under a new random key and replaces the archive with the result.
+    /// `new` becomes slot 0, stretched the way the creator's secret was with a fresh salt
+    /// (recipient archives get the default Argon2 costs). the other key slots and recipients are
+    /// dropped since they wrap the old key. `old` is only asked for if the handle isn't unlocked
+    /// yet. safe to interrupt: run it again with the same keys and it picks up from its journal
+    /// (`<archive>.rekey-journal`)
+    pub fn rekey(&mut self, old: &dyn KeyProvider, new: &dyn KeyProvider) -> io::Result<RekeySummary> {
+        if !self.encryption.is_encrypted() {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "Unencrypted archives have no key to rotate"));
+        }
+        if self.cipher.is_none() {
+            self.unlock_with(old)?;
+        }
+        self.cipher()?;
+
+        let path = self.path.to_string_lossy().into_owned();
+        let (copy_path, journal_path) = (format!("{}.rekey", path), format!("{}.rekey-journal", path));
+        let source_len = self.file.metadata()?.len();
+        let new_secret = new.secret()?;
+
+        // a journal without its copy is left over from a rekey that got as far as the rename
+        let resumable = fs::metadata(&journal_path).is_ok() && fs::metadata(&copy_path).is_ok();
+        let (mut journal, slot, new_cipher, salt) = if resumable {
+            let (journal, started_len, slot, check) = Journal::open(&journal_path)?;
+            if started_len != source_len {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                    "The archive changed since its rekey started, delete {} to start over", journal_path
+                )));
+            }
+            let wrong = || io::Error::new(io::ErrorKind::PermissionDenied, "Not the new key the interrupted rekey was started with");
+            let cipher = Cipher::new(self.encryption, slot.unwrap(&new_secret).ok_or_else(wrong)?);
+            let salt = cipher.unseal(&check, CHECK_AAD).map_err(|_| wrong())?;
+            (journal, slot, cipher, salt)
+        } else {
+            let kdf = match self.creator_kdf() {
+                KeyDerivation::Argon2id { params, .. } => KeyDerivation::argon2id(params),
+                KeyDerivation::Raw => KeyDerivation::Raw,
+                KeyDerivation::Wrapped => KeyDerivation::argon2id(Default::default()),
+            };
+            let key = SecretKey::new_with(|key| {
+                rand::rng().fill_bytes(key);
+                Ok(())
+            })?;
+            let slot = KeySlot::wrap(CREATOR_SLOT, "creator", kdf, &key, &new_secret)?;
+            let cipher = Cipher::new(self.encryption, key);
+            let mut salt = vec![0u8; 32];
+            rand::rng().fill_bytes(&mut salt);
+            let check = cipher.seal(&salt, CHECK_AAD)?;
+            let mut copy = File::create(&copy_path)?;
+            copy.write_all(MAGIC_V3)?;
+            (Journal::create(&journal_path, source_len, &slot, &check)?, slot, cipher, salt)
+        };
+
+        let mut copy = OpenOptions::new().read(true).write(true).open(&copy_path)?;
+        // anything past the last journaled blob may be half written
+        copy.set_len(journal.end())?;
+        let resumed = journal.done.len();
+
+        let blobs = self.rekey_jobs();
+        let copied = self.reseal_blobs(&blobs, &new_cipher, &salt, &mut journal, &mut copy);
+        // keep whatever got done, even when a blob failed
+        journal.commit(&copy)?;
+        copied?;
+
+        let remap = |blob: BlobRef| match journal.done.get(&blob.offset) {
+            Some(&(offset, size)) if blob.size > 0 => BlobRef { offset, size, ..blob },
+            _ => blob,
+        };
+        let mut rekeyed = ShokoArchive::from_file(copy, &copy_path, CURRENT_VERSION);
+        rekeyed.entries = self.entries.clone();
+        // solid members share one wrapped key, they keep sharing one
+        let mut rewrapped: HashMap<Vec<u8>, Vec<u8>> = HashMap::new();
+        let mut rewrap = |old: Vec<u8>| -> io::Result<Vec<u8>> {
+            if let Some(new) = rewrapped.get(&old) {
+                return Ok(new.clone());
+            }
+            let data = datakey::rekeyed_data_key(&new_cipher, &salt, &old)?;
+            let new = datakey::wrap_data_key(&new_cipher, &data, self.archive_id.as_ref())?;
+            rewrapped.insert(old, new.clone());
+            Ok(new)
+        };
+        for entry in &mut rekeyed.entries {
+            let blob = remap(entry.blob());
+            (entry.offset, entry.size) = (blob.offset, blob.size);
+            entry.data_key = entry.data_key.take().map(&mut rewrap).transpose()?;
+            for link in &mut entry.delta_chain {
+                *link = remap(*link);
+            }
+        }
+        rekeyed.dictionaries = self.dictionaries.iter()
+            .map(|d| DictionarySlot { id: d.id, blob: remap(d.blob), loaded: None })
+            .collect();
+        rekeyed.active_dictionary = self.active_dictionary;
+        for (hash, chunk) in &self.chunks {
+            let data_key = chunk.data_key.clone().map(&mut rewrap).transpose()?;
+            rekeyed.chunks.insert(*hash, StoredChunk { blob: remap(chunk.blob), data_key });
+        }
+        rekeyed.encryption = self.encryption;
+        rekeyed.kdf = KeyDerivation::Wrapped;
+        rekeyed.key_slots = vec![slot];
+        rekeyed.key_id = new_cipher.key_id();
+        rekeyed.cipher = Some(new_cipher.clone());
+        rekeyed.archive_id = self.archive_id;
+        rekeyed.generation = self.generation;
+        rekeyed.seal_index = self.seal_index;
+        rekeyed.padding = self.padding;
+        rekeyed.limits = self.limits;
+        rekeyed.rewrite_index()?;
+        rekeyed.file.sync_all()?;
+        // the original only goes away once everything opens under the new key
+        rekeyed.verify()?;
+        drop(rekeyed);
+
+        fs::rename(&copy_path, &self.path)?;
+        let _ = fs::remove_file(&journal_path);
+
+        let slots_dropped = self.key_slots.len() + self.recipients.len();
+        self.file = OpenOptions::new().read(true).write(true).open(&self.path)?;
+        self.version = CURRENT_VERSION;
+        self.kdf = KeyDerivation::Wrapped;
+        self.cipher = Some(new_cipher);
+        self.load_index()?;
+        Ok(RekeySummary { blobs: blobs.len(), resumed, slots_dropped })
+    }
+
+    /// opens every entry and dictionary and throws the bytes away, the first one that doesn't
+    /// open is the error. returns how many entries were checked. the per entry limits apply,
+    /// `max_total_size` doesn't since nothing is handed out
+    pub fn verify(&mut self) -> io::Result<usize> {
+        self.ensure_index()?;
+        let ids = self.dictionary_ids();
+        for id in ids {
+            self.dictionary(id).map_err(|e| io::Error::new(e.kind(), format!("dictionary {}: {}", id, e)))?;
+        }
+        let paths: Vec<String> = self.entries.iter().map(|e| e.path.clone()).collect();
+        let extracted = self.extracted;
+        for path in &paths {
+            self.extract_to(path, &mut io::sink()).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))?;
+            self.extracted = extracted;
+        }
+        Ok(paths.len())
+    }
+
+    /// every distinct blob with the associated data it was sealed under, in file order
+    fn rekey_jobs(&self) -> Vec<RekeyJob> {
+        let id = self.archive_id.as_ref();
+        let mut jobs = BTreeMap::new();
+        let job = |blob: BlobRef, binding: BlobBinding, data_key: Option<&Vec<u8>>| {
+            (blob.offset, RekeyJob { blob, aad: binding.aad(id), data_key: data_key.cloned() })
+        };
+        for entry in &self.entries {
+            if entry.is_chunked() {
+                continue;
+            }
+            let data_key = entry.data_key.as_ref();
+            if entry.is_solid() {
+                let (offset, rekey) = job(entry.blob(), BlobBinding::Solid { generation: entry.generation }, data_key);
+                jobs.insert(offset, rekey);
+                continue;
+            }
+            // same fallback as read_entry for entries from before generations were recorded
+            let mut generations = entry.delta_generations.clone();
+            generations.resize(entry.delta_chain.len(), 0);
+            for (link, generation) in entry.delta_chain.iter().zip(generations) {
+                let (offset, rekey) = job(*link, BlobBinding::Entry { path: &entry.path, generation }, data_key);
+                jobs.insert(offset, rekey);
+            }
+            let (offset, rekey) = job(entry.blob(), BlobBinding::Entry { path: &entry.path, generation: entry.generation }, data_key);
+            jobs.insert(offset, rekey);
+        }
+        for dict in &self.dictionaries {
+            let (offset, rekey) = job(dict.blob, BlobBinding::Dictionary(dict.id), None);
+            jobs.insert(offset, rekey);
+        }
+        for (hash, chunk) in &self.chunks {
+            let (offset, rekey) = job(chunk.blob, BlobBinding::Chunk(hash), chunk.data_key.as_ref());
+            jobs.insert(offset, rekey);
+        }
+        jobs.into_values().collect()
+    }
+
+    /// blobs with a data key move to the one `rekeyed_data_key` gives, the rest to `new` itself
+    fn reseal_blobs(
+        &mut self,
+        jobs: &[RekeyJob],
+        new: &Cipher,
+        salt: &[u8],
+        journal: &mut Journal,
+        copy: &mut File,
+    ) -> io::Result<()> {
+        let mut offset = journal.end();
+        let mut batch = 0;
+        for RekeyJob { blob, aad, data_key } in jobs {
+            if journal.done.contains_key(&blob.offset) {
+                continue;
+            }
+            let (old, new) = match data_key {
+                Some(wrapped) => (self.data_cipher(Some(wrapped))?, datakey::rekeyed_data_key(new, salt, wrapped)?),
+                None => (self.cipher()?, new.clone()),
+            };
+            let payload = ShokoReader::new(&mut self.file).with_cipher(old).read_sealed(blob.offset, blob.size, aad)?;
+            let sealed = new.seal(&payload, aad)?;
+            copy.seek(SeekFrom::Start(offset))?;
+            let size = ShokoWriter::new(copy).write_raw(&sealed)?;
+            journal.record(blob.offset, BlobRef { offset, size, ..*blob });
+            offset += size;
+
+            batch += size;
+            if batch >= BATCH_BYTES {
+                journal.commit(copy)?;
+                batch = 0;
+            }
+        }
+        Ok(())
+    }
+}
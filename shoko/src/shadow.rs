@@ -1,5 +1,13 @@
-use std::cell::UnsafeCell;
+use std::cell::Cell;
+use std::ffi::c_void;
+use std::io;
+use std::num::NonZeroUsize;
+use std::ops::{Deref, DerefMut};
 use std::ptr::NonNull;
+use std::sync::Arc;
+
+use nix::sys::mman::{self, MapFlags, ProtFlags};
+use zeroize::Zeroize;
 
 pub struct ShadowStack {
     storage: Vec<usize>,
@@ -27,38 +35,156 @@ impl ShadowStack {
     }
 }
 
+/// a bump allocator over its own anonymous mapping, for key material and other secrets. the
+/// pages are locked so they never hit swap (when RLIMIT_MEMLOCK allows it, `is_locked` says),
+/// left out of core dumps, and wiped on `reset` and on drop
 pub struct SecureArena {
-    buffer: UnsafeCell<Vec<u8>>,
-    offset: UnsafeCell<usize>,
+    base: NonNull<u8>,
+    len: usize,
+    offset: Cell<usize>,
+    locked: bool,
 }
 
 impl SecureArena {
-    pub fn with_capacity(size: usize) -> Self {
-        Self {
-            buffer: UnsafeCell::new(vec![0; size]),
-            offset: UnsafeCell::new(0),
+    pub fn with_capacity(size: usize) -> io::Result<Self> {
+        let len = size.max(1);
+        let base = unsafe {
+            mman::mmap_anonymous(
+                None,
+                NonZeroUsize::new(len).unwrap(),
+                ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+                MapFlags::MAP_PRIVATE | MapFlags::MAP_ANONYMOUS,
+            )?
+        };
+        // a locked page is nice to have, but a low memlock limit shouldn't stop anyone decrypting
+        let locked = unsafe { mman::mlock(base, len) }.is_ok();
+        // built before the madvise so a failure drops it, which unlocks and unmaps
+        let arena = Self { base: base.cast(), len, offset: Cell::new(0), locked };
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        unsafe {
+            mman::madvise(base, len, mman::MmapAdvise::MADV_DONTDUMP)?;
         }
+        Ok(arena)
     }
 
     pub fn alloc(&self, size: usize) -> Option<NonNull<u8>> {
-        unsafe {
-            let buffer = &mut *self.buffer.get();
-            let offset = &mut *self.offset.get();
-
-            if *offset + size > buffer.len() {
-                return None;
-            }
-
-            let ptr = buffer.as_mut_ptr().add(*offset);
-            *offset += size;
-            
-            NonNull::new(ptr)
+        let offset = self.offset.get();
+        if offset + size > self.len {
+            return None;
         }
+        self.offset.set(offset + size);
+        // in bounds of our own mapping, checked above
+        NonNull::new(unsafe { self.base.as_ptr().add(offset) })
     }
 
+    /// wipes everything handed out so far and starts over. anything `alloc` returned is dead after this
     pub fn reset(&self) {
+        unsafe { std::slice::from_raw_parts_mut(self.base.as_ptr(), self.offset.get()) }.zeroize();
+        self.offset.set(0);
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+}
+
+impl Drop for SecureArena {
+    fn drop(&mut self) {
         unsafe {
-            *self.offset.get() = 0;
+            std::slice::from_raw_parts_mut(self.base.as_ptr(), self.len).zeroize();
+            let base: NonNull<c_void> = self.base.cast();
+            if self.locked {
+                let _ = mman::munlock(base, self.len);
+            }
+            let _ = mman::munmap(base, self.len);
+        }
+    }
+}
+
+/// a fixed size byte buffer in a `SecureArena` of its own
+pub struct SecureBuf {
+    arena: SecureArena,
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+// the mapping belongs to this buffer alone and nothing hands out a second &mut to it
+unsafe impl Send for SecureBuf {}
+unsafe impl Sync for SecureBuf {}
+
+impl SecureBuf {
+    /// `len` zero bytes
+    pub fn new(len: usize) -> io::Result<Self> {
+        let arena = SecureArena::with_capacity(len)?;
+        let ptr = arena.alloc(len).unwrap();
+        Ok(Self { arena, ptr, len })
+    }
+
+    pub fn from_slice(data: &[u8]) -> io::Result<Self> {
+        let mut buf = Self::new(data.len())?;
+        buf.copy_from_slice(data);
+        Ok(buf)
+    }
+
+    /// takes over `data` and wipes the original
+    pub fn from_vec(mut data: Vec<u8>) -> io::Result<Self> {
+        let buf = Self::from_slice(&data);
+        data.zeroize();
+        buf
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.arena.is_locked()
+    }
+}
+
+impl Deref for SecureBuf {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl DerefMut for SecureBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl std::fmt::Debug for SecureBuf {
+    // never print the contents
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SecureBuf({} bytes)", self.len)
+    }
+}
+
+/// a 32 byte key in a `SecureBuf`. clones share the one allocation, so handing a cipher to
+/// worker threads doesn't copy the key around
+#[derive(Clone, Debug)]
+pub struct SecretKey(Arc<SecureBuf>);
+
+impl SecretKey {
+    /// lets `fill` write the key straight into locked memory, so it never sits on the stack
+    pub fn new_with(fill: impl FnOnce(&mut [u8; 32]) -> io::Result<()>) -> io::Result<Self> {
+        let mut buf = SecureBuf::new(32)?;
+        fill((&mut buf[..]).try_into().unwrap())?;
+        Ok(Self(Arc::new(buf)))
+    }
+
+    pub fn from_slice(bytes: &[u8]) -> io::Result<Self> {
+        if bytes.len() != 32 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "A key is 32 bytes"));
         }
+        Self::new_with(|key| {
+            key.copy_from_slice(bytes);
+            Ok(())
+        })
+    }
+}
+
+impl Deref for SecretKey {
+    type Target = [u8; 32];
+    fn deref(&self) -> &[u8; 32] {
+        (&self.0[..]).try_into().unwrap()
     }
 }
@@ -0,0 +1,180 @@
+// ed25519 signatures over a whole archive, so whoever receives one can check who made it and
+// that nothing changed since. the archive key can't do that, anyone able to read an archive
+// can also write one that opens under it.
+//
+// what gets signed is a SHA-256 digest of the header records, the index entries and the hash
+// of every blob the index points at. the signature itself sits in the clear part of the header,
+// so any change to the archive after signing (a write, a delete, a defrag) invalidates it
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use ed25519_dalek::{Signature, Signer, Verifier};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+pub use ed25519_dalek::{SigningKey, VerifyingKey};
+
+use crate::archive::ShokoArchive;
+use crate::write;
+
+/// keeps a signature over a shoko archive from meaning anything anywhere else
+const DOMAIN: &[u8] = b"shoko archive signature v1";
+
+/// a signature as stored in the header, with the public key that made it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArchiveSignature {
+    pub public_key: [u8; 32],
+    pub signature: [u8; 64],
+}
+
+impl ArchiveSignature {
+    pub(crate) fn to_bytes(self) -> Vec<u8> {
+        let mut out = self.public_key.to_vec();
+        out.extend_from_slice(&self.signature);
+        out
+    }
+
+    pub(crate) fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        if bytes.len() != 96 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Malformed archive signature record"));
+        }
+        Ok(Self {
+            public_key: bytes[..32].try_into().unwrap(),
+            signature: bytes[32..].try_into().unwrap(),
+        })
+    }
+}
+
+pub fn generate_signing_key() -> SigningKey {
+    let mut seed = [0u8; 32];
+    rand::rng().fill_bytes(&mut seed);
+    SigningKey::from_bytes(&seed)
+}
+
+/// a signing key file holds the 32 byte seed as hex. it's written readable by its owner only,
+/// and an existing file is an error
+pub fn write_signing_key(path: impl AsRef<Path>, key: &SigningKey) -> io::Result<()> {
+    write_private(path.as_ref(), format!("{}\n", to_hex(&key.to_bytes())).as_bytes())
+}
+
+pub fn read_signing_key(path: impl AsRef<Path>) -> io::Result<SigningKey> {
+    let seed = from_hex(&fs::read_to_string(path)?)?;
+    let seed: [u8; 32] = seed.try_into()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "A signing key is 32 bytes"))?;
+    Ok(SigningKey::from_bytes(&seed))
+}
+
+pub fn write_verifying_key(path: impl AsRef<Path>, key: &VerifyingKey) -> io::Result<()> {
+    fs::write(path, format!("{}\n", to_hex(key.as_bytes())))
+}
+
+/// a public key as hex, either straight from the argument or from the file it names
+pub fn read_verifying_key(path_or_hex: &str) -> io::Result<VerifyingKey> {
+    let text = match from_hex(path_or_hex) {
+        Ok(_) => path_or_hex.to_string(),
+        Err(_) => fs::read_to_string(path_or_hex)?,
+    };
+    let bytes: [u8; 32] = from_hex(&text)?.try_into()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "A public key is 32 bytes"))?;
+    VerifyingKey::from_bytes(&bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+}
+
+/// readable by its owner only, and never over an existing file: that would keep its
+/// permissions and lose the key that was in it
+pub(crate) fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    io::Write::write_all(&mut options.open(path)?, contents)
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn from_hex(text: &str) -> io::Result<Vec<u8>> {
+    let text = text.trim();
+    let bad = || io::Error::new(io::ErrorKind::InvalidData, "Not a hex string");
+    if !text.len().is_multiple_of(2) {
+        return Err(bad());
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| text.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()).ok_or_else(bad))
+        .collect()
+}
+
+impl ShokoArchive {
+    /// signs the archive as it is now and stores the signature in the header. an archive with a
+    /// sealed index has to be unlocked, since the digest covers the real index
+    pub fn sign(&mut self, key: &SigningKey) -> io::Result<()> {
+        let digest = self.signature_digest()?;
+        self.signature = Some(ArchiveSignature {
+            public_key: key.verifying_key().to_bytes(),
+            signature: key.sign(&digest).to_bytes(),
+        });
+        self.rewrite_index()
+    }
+
+    pub fn signature(&self) -> Option<ArchiveSignature> {
+        self.signature
+    }
+
+    /// checks the archive was signed by `trusted` and hasn't changed since
+    pub fn verify_signature(&mut self, trusted: &VerifyingKey) -> io::Result<()> {
+        let Some(stored) = self.signature else {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "Archive isn't signed"));
+        };
+        if stored.public_key != trusted.to_bytes() {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, format!(
+                "Archive is signed by a different key ({})", to_hex(&stored.public_key)
+            )));
+        }
+        let digest = self.signature_digest()?;
+        trusted.verify(&digest, &Signature::from_bytes(&stored.signature)).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidData, "Signature doesn't match, the archive changed after it was signed")
+        })
+    }
+
+    /// what the signature is over: the header records minus the signature, the index entries,
+    /// and every referenced blob's position and hash in file order
+    fn signature_digest(&mut self) -> io::Result<[u8; 32]> {
+        self.ensure_index()?;
+        let stored = self.signature.take();
+        let header = self.header_block();
+        self.signature = stored;
+
+        let mut hasher = Sha256::new();
+        hasher.update(DOMAIN);
+        hasher.update([self.seal_index as u8]);
+        hasher.update((header.len() as u32).to_le_bytes());
+        hasher.update(&header);
+        hasher.update((self.entries.len() as u32).to_le_bytes());
+        for entry in &self.entries {
+            hasher.update(write::index_entry_bytes(entry));
+        }
+
+        let mut blobs: Vec<_> = self.referenced_blobs().filter(|b| b.size > 0).collect();
+        blobs.sort_by_key(|b| b.offset);
+        blobs.dedup();
+        hasher.update((blobs.len() as u32).to_le_bytes());
+        for blob in blobs {
+            self.file.seek(SeekFrom::Start(blob.offset))?;
+            let mut blob_hasher = Sha256::new();
+            let copied = io::copy(&mut (&mut self.file).take(blob.size), &mut blob_hasher)?;
+            if copied != blob.size {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Blob runs past the end of the archive"));
+            }
+            hasher.update(blob.offset.to_le_bytes());
+            hasher.update(blob.size.to_le_bytes());
+            hasher.update([blob.compression_level]);
+            hasher.update(blob_hasher.finalize());
+        }
+        Ok(hasher.finalize().into())
+    }
+}
@@ -0,0 +1,136 @@
+// solid mode: small files get concatenated into shared blocks that are compressed and sealed once,
+// so they split one nonce + tag between them and the RLE gets to see across file boundaries
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use zeroize::Zeroizing;
+
+use crate::archive::{BlobRef, ShokoArchive, SolidSlice};
+use crate::encrypt::BlobBinding;
+use crate::pipeline::{walk_parallel, PackItem, PackSource, PackUnit, UnitKind};
+
+#[derive(Debug, Clone, Copy)]
+pub struct SolidOptions {
+    /// a block is closed once it holds at least this many bytes
+    pub block_size: usize,
+    /// files bigger than this are stored on their own as usual
+    pub max_member_size: usize,
+}
+
+impl Default for SolidOptions {
+    fn default() -> Self {
+        Self {
+            block_size: 1024 * 1024,
+            max_member_size: 64 * 1024,
+        }
+    }
+}
+
+impl ShokoArchive {
+    /// `pack_dir`, but small files are grouped into solid blocks
+    pub fn pack_dir_solid(&mut self, root: &Path, clevel: u8, jobs: usize, opts: SolidOptions) -> io::Result<Vec<String>> {
+        let items = walk_parallel(root, jobs)?
+            .into_iter()
+            .map(|(path, source)| PackItem::file(path, source))
+            .collect();
+        self.write_files_solid(items, clevel, jobs, opts)
+    }
+
+    /// bulk insert that packs small items into solid blocks and the rest as normal entries.
+    /// members are grouped by extension so similar files end up sharing a block
+    pub fn write_files_solid(&mut self, items: Vec<PackItem>, clevel: u8, jobs: usize, opts: SolidOptions) -> io::Result<Vec<String>> {
+        let mut small = Vec::new();
+        let mut units = Vec::new();
+        for item in items {
+            let len = match &item.source {
+                PackSource::File(p) => fs::metadata(p)?.len() as usize,
+                PackSource::Bytes(d) => d.len(),
+            };
+            if len <= opts.max_member_size {
+                small.push((len, item));
+            } else {
+                units.push(PackUnit { members: vec![item], kind: UnitKind::Plain });
+            }
+        }
+
+        small.sort_by(|(_, a), (_, b)| {
+            let ext = |p: &str| p.rsplit_once('.').map(|(_, e)| e.to_string()).unwrap_or_default();
+            ext(&a.path).cmp(&ext(&b.path)).then_with(|| a.path.cmp(&b.path))
+        });
+
+        let mut block = Vec::new();
+        let mut filled = 0;
+        for (len, item) in small {
+            block.push(item);
+            filled += len;
+            if filled >= opts.block_size {
+                units.push(PackUnit { members: std::mem::take(&mut block), kind: UnitKind::Solid });
+                filled = 0;
+            }
+        }
+        if !block.is_empty() {
+            units.push(PackUnit { members: block, kind: UnitKind::Solid });
+        }
+
+        self.write_units(units, clevel, jobs)
+    }
+
+    /// decodes (or reuses) a solid block and cuts one member out of it
+    pub(crate) fn read_solid(&mut self, block: BlobRef, slice: SolidSlice, generation: u64, data_key: Option<&[u8]>) -> io::Result<Vec<u8>> {
+        let cached = matches!(&self.block_cache, Some((b, _)) if *b == block);
+        if !cached {
+            let cipher = self.data_cipher(data_key)?;
+            let data = self.read_blob_with(block, BlobBinding::Solid { generation }, cipher)?;
+            self.block_cache = Some((block, Zeroizing::new(data)));
+        }
+
+        let data = &self.block_cache.as_ref().unwrap().1;
+        // both come straight from the index, so they can add up to anything
+        let bounds = usize::try_from(slice.offset).ok()
+            .zip(usize::try_from(slice.len).ok())
+            .and_then(|(start, len)| Some(start..start.checked_add(len)?));
+        bounds.and_then(|range| data.get(range))
+            .map(|d| d.to_vec())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Solid member out of block bounds"))
+    }
+
+    /// copies entries into `dest` for defrag, solid blocks are rebuilt with only their live
+    /// members instead of being exploded into one blob per file, and chunked entries are
+    /// re-chunked so only chunks something still references make it across
+    pub(crate) fn copy_entries_into(&mut self, dest: &mut ShokoArchive) -> io::Result<()> {
+        let entries: Vec<(String, BlobRef, bool)> = self.entries.iter()
+            .map(|e| (e.path.clone(), e.blob(), e.is_solid()))
+            .collect();
+        let mut blocks: HashMap<BlobRef, Vec<String>> = HashMap::new();
+        for (path, blob, _) in entries.iter().filter(|(_, _, solid)| *solid) {
+            blocks.entry(*blob).or_default().push(path.clone());
+        }
+
+        for (path, blob, solid) in entries {
+            if !solid {
+                let data = self.read_entry(&path)?;
+                let chunked = self.entries.iter().any(|e| e.path == path && e.is_chunked());
+                if chunked {
+                    dest.write_file_chunked(&path, &data, blob.compression_level)?;
+                } else {
+                    dest.write_file_direct(&path, &data, blob.compression_level)?;
+                }
+                continue;
+            }
+            // first member we run into brings the whole block along
+            let Some(member_paths) = blocks.remove(&blob) else {
+                continue;
+            };
+
+            let mut members = Vec::with_capacity(member_paths.len());
+            for member in member_paths {
+                let data = self.read_entry(&member)?;
+                members.push(PackItem::bytes(member, data));
+            }
+            dest.write_units(vec![PackUnit { members, kind: UnitKind::Solid }], blob.compression_level, 1)?;
+        }
+        Ok(())
+    }
+}
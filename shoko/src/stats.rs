@@ -0,0 +1,162 @@
+// compression numbers per entry and for the whole archive, so it's easy to see what a
+// --clevel or codec actually buys. shared storage (solid blocks, chunks) is split between
+// the entries using it so the per-entry numbers add up to roughly the archive total
+use std::collections::{HashMap, HashSet};
+use std::io;
+
+use crate::archive::{BlobRef, ShokoArchive};
+use crate::codec::Codec;
+use crate::format::HEADER_LEN;
+
+/// how an entry's bytes are laid out in the file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    Plain,
+    Delta,
+    Solid,
+    Chunked,
+}
+
+impl Layout {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Layout::Plain => "plain",
+            Layout::Delta => "delta",
+            Layout::Solid => "solid",
+            Layout::Chunked => "dedup",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct EntryStats {
+    pub path: String,
+    pub original_size: u64,
+    /// bytes on disk this entry accounts for, encryption included
+    pub stored_size: u64,
+    /// the part of `stored_size` that is nonces and auth tags
+    pub encryption_overhead: u64,
+    pub codec: Codec,
+    pub compression_level: u8,
+    pub layout: Layout,
+}
+
+impl EntryStats {
+    /// original / stored, so bigger is better and below 1.0 means the entry grew
+    pub fn ratio(&self) -> f64 {
+        ratio(self.original_size, self.stored_size)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ArchiveStats {
+    pub entries: usize,
+    pub original_size: u64,
+    /// every blob something still references, counted once
+    pub stored_size: u64,
+    pub encryption_overhead: u64,
+    /// header block, entry table and footer
+    pub index_size: u64,
+    /// space left behind by deletes and overwrites, `defrag` gets it back
+    pub dead_size: u64,
+    pub file_size: u64,
+}
+
+impl ArchiveStats {
+    pub fn ratio(&self) -> f64 {
+        ratio(self.original_size, self.stored_size)
+    }
+}
+
+fn ratio(original: u64, stored: u64) -> f64 {
+    if stored == 0 {
+        return 1.0;
+    }
+    original as f64 / stored as f64
+}
+
+/// `part`/`whole` of `amount`, rounded down
+fn share(amount: u64, part: u64, whole: u64) -> u64 {
+    if whole == 0 {
+        return 0;
+    }
+    (amount as u128 * part as u128 / whole as u128) as u64
+}
+
+impl ShokoArchive {
+    /// stats for every entry, in index order. entries from before original sizes were recorded
+    /// get decoded to find out, so on old archives this reads everything once
+    pub fn entry_stats(&mut self) -> io::Result<Vec<EntryStats>> {
+        self.ensure_index()?;
+        let encryption = self.encryption;
+        let overhead = |blob: BlobRef| encryption.overhead(blob.size);
+
+        let mut block_members: HashMap<BlobRef, u64> = HashMap::new();
+        for entry in self.entries.iter().filter(|e| e.is_solid()) {
+            *block_members.entry(entry.blob()).or_default() += entry.solid.unwrap().len;
+        }
+        let refcounts = self.chunk_refcounts();
+
+        let mut stats = Vec::with_capacity(self.entries.len());
+        for idx in 0..self.entries.len() {
+            let original_size = match self.entries[idx].original_size {
+                Some(size) => size,
+                None => {
+                    let path = self.entries[idx].path.clone();
+                    self.read_entry(&path)?.len() as u64
+                }
+            };
+
+            let entry = &self.entries[idx];
+            let (layout, stored_size, encryption_overhead) = if let Some(slice) = entry.solid {
+                let block = entry.blob();
+                let members = block_members[&block];
+                (Layout::Solid, share(block.size, slice.len, members), share(overhead(block), slice.len, members))
+            } else if let Some(hashes) = &entry.chunks {
+                let (mut stored, mut sealed) = (0, 0);
+                for hash in hashes {
+                    let refs = refcounts.get(hash).copied().unwrap_or(1).max(1) as u64;
+                    let blob = self.chunks.get(hash).map(|c| c.blob);
+                    stored += blob.map_or(0, |b| b.size) / refs;
+                    sealed += blob.map_or(0, overhead) / refs;
+                }
+                (Layout::Chunked, stored, sealed)
+            } else if entry.is_delta() {
+                let stored = entry.delta_chain.iter().map(|b| b.size).sum::<u64>() + entry.size;
+                let sealed = entry.delta_chain.iter().map(|b| overhead(*b)).sum::<u64>() + overhead(entry.blob());
+                (Layout::Delta, stored, sealed)
+            } else {
+                (Layout::Plain, entry.size, overhead(entry.blob()))
+            };
+
+            stats.push(EntryStats {
+                path: entry.path.clone(),
+                original_size,
+                stored_size,
+                encryption_overhead,
+                codec: Codec::of(entry.compression_level),
+                compression_level: entry.compression_level,
+                layout,
+            });
+        }
+        Ok(stats)
+    }
+
+    pub fn archive_stats(&mut self) -> io::Result<ArchiveStats> {
+        let entries = self.entry_stats()?;
+        let blobs: HashSet<BlobRef> = self.referenced_blobs().filter(|b| b.size > 0).collect();
+        let stored_size: u64 = blobs.iter().map(|b| b.size).sum();
+        let data_end = self.data_end();
+        let file_size = self.file.metadata()?.len();
+
+        Ok(ArchiveStats {
+            entries: entries.len(),
+            original_size: entries.iter().map(|e| e.original_size).sum(),
+            stored_size,
+            encryption_overhead: blobs.iter().map(|b| self.encryption.overhead(b.size)).sum(),
+            index_size: file_size.saturating_sub(data_end),
+            dead_size: (data_end - HEADER_LEN).saturating_sub(stored_size),
+            file_size,
+        })
+    }
+}
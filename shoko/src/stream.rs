@@ -0,0 +1,373 @@
+// chunked online AEAD, the STREAM construction (Hoang, Reyhanitabar, Rogaway, Vizár): a blob is
+// cut into fixed size chunks sealed on their own under `nonce prefix | chunk counter | last flag`.
+// the counter catches reordered chunks and the flag catches a stream cut off at a chunk boundary,
+// and since every chunk stands alone, both sides run in constant memory and any chunk can be
+// opened without the ones before it.
+//
+// layout: prefix [7] | log2 chunk size u8 | chunks, each `chunk_len + 16` bytes except the last
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256Gcm, Nonce
+};
+use rand::{RngCore, rng};
+
+use crate::archive::{ShokoArchive, ShokoEntry};
+use crate::codec::Codec;
+use crate::compress::RleEncoder;
+use crate::datakey;
+use crate::decompress::RleDecoder;
+use crate::encrypt::{BlobBinding, Cipher};
+use crate::limits::{LimitExceeded, LimitKind};
+use crate::padding::Padding;
+
+pub const CHUNK_LEN: usize = 64 * 1024;
+const PREFIX_LEN: usize = 7;
+pub const STREAM_HEADER_LEN: usize = PREFIX_LEN + 1;
+pub const TAG_LEN: usize = 16;
+
+fn nonce(prefix: &[u8; PREFIX_LEN], counter: u32, last: bool) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[..PREFIX_LEN].copy_from_slice(prefix);
+    nonce[PREFIX_LEN..11].copy_from_slice(&counter.to_be_bytes());
+    nonce[11] = last as u8;
+    nonce
+}
+
+fn aead(key: &[u8; 32]) -> io::Result<Aes256Gcm> {
+    Aes256Gcm::new_from_slice(key).map_err(|e| io::Error::other(e.to_string()))
+}
+
+fn open_failed(counter: u32) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Decryption failed at chunk {} (tampered, reordered or truncated data, or a wrong key)", counter),
+    )
+}
+
+/// seals everything written to it into `inner`, call `finish` to seal the last chunk.
+/// dropping it unfinished leaves a stream that won't open, which is the point
+pub struct StreamWriter<W: Write> {
+    inner: W,
+    aead: Aes256Gcm,
+    prefix: [u8; PREFIX_LEN],
+    aad: Vec<u8>,
+    buf: Vec<u8>,
+    counter: u32,
+}
+
+impl<W: Write> StreamWriter<W> {
+    pub fn new(mut inner: W, key: &[u8; 32], aad: &[u8]) -> io::Result<Self> {
+        let mut prefix = [0u8; PREFIX_LEN];
+        rng().fill_bytes(&mut prefix);
+        inner.write_all(&prefix)?;
+        inner.write_all(&[CHUNK_LEN.trailing_zeros() as u8])?;
+        Ok(Self { inner, aead: aead(key)?, prefix, aad: aad.to_vec(), buf: Vec::with_capacity(CHUNK_LEN), counter: 0 })
+    }
+
+    fn seal_chunk(&mut self, len: usize, last: bool) -> io::Result<()> {
+        let nonce = nonce(&self.prefix, self.counter, last);
+        let sealed = self.aead
+            .encrypt(Nonce::from_slice(&nonce), Payload { msg: &self.buf[..len], aad: &self.aad })
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Encryption failed: {}", e)))?;
+        self.inner.write_all(&sealed)?;
+        self.buf.drain(..len);
+        self.counter = self.counter.checked_add(1)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::FileTooLarge, "Too many chunks for one stream"))?;
+        Ok(())
+    }
+
+    /// seals what's left as the last chunk (empty if the input was) and hands back `inner`
+    pub fn finish(mut self) -> io::Result<W> {
+        self.seal_chunk(self.buf.len(), true)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for StreamWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        // a full chunk is only sealed once more input shows up, until then it might be the last
+        if self.buf.len() == CHUNK_LEN {
+            self.seal_chunk(CHUNK_LEN, false)?;
+        }
+        let take = data.len().min(CHUNK_LEN - self.buf.len());
+        self.buf.extend_from_slice(&data[..take]);
+        Ok(take)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// opens a stream chunk by chunk, nothing is handed out before its chunk authenticated.
+/// `inner` must end where the stream does, e.g. a `Take` over the blob
+pub struct StreamReader<R: Read> {
+    inner: R,
+    aead: Aes256Gcm,
+    prefix: [u8; PREFIX_LEN],
+    aad: Vec<u8>,
+    chunk_len: usize,
+    counter: u32,
+    /// sealed bytes read ahead, one past a full chunk tells it's not the last one
+    sealed: Vec<u8>,
+    plain: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+impl<R: Read> StreamReader<R> {
+    pub fn new(mut inner: R, key: &[u8; 32], aad: &[u8]) -> io::Result<Self> {
+        let mut header = [0u8; STREAM_HEADER_LEN];
+        inner.read_exact(&mut header)?;
+        let chunk_len = chunk_len(header[PREFIX_LEN])?;
+        Ok(Self {
+            inner,
+            aead: aead(key)?,
+            prefix: header[..PREFIX_LEN].try_into().unwrap(),
+            aad: aad.to_vec(),
+            chunk_len,
+            counter: 0,
+            sealed: Vec::with_capacity(chunk_len + TAG_LEN + 1),
+            plain: Vec::new(),
+            pos: 0,
+            done: false,
+        })
+    }
+
+    fn next_chunk(&mut self) -> io::Result<()> {
+        let want = self.chunk_len + TAG_LEN + 1;
+        while self.sealed.len() < want {
+            let have = self.sealed.len();
+            self.sealed.resize(want, 0);
+            match self.inner.read(&mut self.sealed[have..]) {
+                Ok(n) => self.sealed.truncate(have + n),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {
+                    self.sealed.truncate(have);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            }
+            if self.sealed.len() == have {
+                break;
+            }
+        }
+
+        let last = self.sealed.len() < want;
+        let len = if last { self.sealed.len() } else { want - 1 };
+        let nonce = nonce(&self.prefix, self.counter, last);
+        self.plain = self.aead
+            .decrypt(Nonce::from_slice(&nonce), Payload { msg: &self.sealed[..len], aad: &self.aad })
+            .map_err(|_| open_failed(self.counter))?;
+        self.sealed.drain(..len);
+        self.pos = 0;
+        self.done = last;
+        self.counter = self.counter.wrapping_add(1);
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for StreamReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.plain.len() {
+            if self.done {
+                return Ok(0);
+            }
+            self.next_chunk()?;
+        }
+        let n = out.len().min(self.plain.len() - self.pos);
+        out[..n].copy_from_slice(&self.plain[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+fn chunk_len(log2: u8) -> io::Result<usize> {
+    match log2 {
+        10..=24 => Ok(1 << log2),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Bad stream chunk size 2^{}", log2))),
+    }
+}
+
+pub fn seal(key: &[u8; 32], data: &[u8], aad: &[u8]) -> io::Result<Vec<u8>> {
+    let mut writer = StreamWriter::new(Vec::with_capacity(sealed_len(data.len() as u64) as usize), key, aad)?;
+    writer.write_all(data)?;
+    writer.finish()
+}
+
+pub fn unseal(key: &[u8; 32], sealed: &[u8], aad: &[u8]) -> io::Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(sealed.len());
+    StreamReader::new(sealed, key, aad)?.read_to_end(&mut out)?;
+    Ok(out)
+}
+
+/// how long `len` bytes of plaintext are once sealed
+pub fn sealed_len(len: u64) -> u64 {
+    let chunks = len.div_ceil(CHUNK_LEN as u64).max(1);
+    STREAM_HEADER_LEN as u64 + len + chunks * TAG_LEN as u64
+}
+
+/// how many chunks a sealed stream of `sealed_len` bytes has, at least 1
+pub fn chunk_count(sealed_len: u64, chunk_len: usize) -> u64 {
+    let body = sealed_len.saturating_sub(STREAM_HEADER_LEN as u64);
+    body.div_ceil((chunk_len + TAG_LEN) as u64).max(1)
+}
+
+/// opens chunk `index` of a sealed stream on its own. `sealed` is the whole blob, anything that
+/// reads and seeks (the archive file with the blob's offset as `start`, or a slice in a cursor)
+pub fn open_chunk<S: Read + Seek>(sealed: &mut S, start: u64, len: u64, key: &[u8; 32], aad: &[u8], index: u64) -> io::Result<Vec<u8>> {
+    sealed.seek(SeekFrom::Start(start))?;
+    let mut header = [0u8; STREAM_HEADER_LEN];
+    sealed.read_exact(&mut header)?;
+    let prefix: [u8; PREFIX_LEN] = header[..PREFIX_LEN].try_into().unwrap();
+    let chunk_len = chunk_len(header[PREFIX_LEN])?;
+
+    let chunks = chunk_count(len, chunk_len);
+    if index >= chunks {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Chunk {} past the end ({} chunks)", index, chunks)));
+    }
+    let at = STREAM_HEADER_LEN as u64 + index * (chunk_len + TAG_LEN) as u64;
+    // `len` is the caller's word for it, a stream shorter than its own header has no chunks
+    let size = len.checked_sub(at).ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, format!("A {} byte blob is too short to be a sealed stream", len))
+    })?.min((chunk_len + TAG_LEN) as u64);
+    let mut chunk = vec![0u8; size as usize];
+    sealed.seek(SeekFrom::Start(start + at))?;
+    sealed.read_exact(&mut chunk)?;
+
+    let counter = u32::try_from(index).map_err(|_| open_failed(u32::MAX))?;
+    let nonce = nonce(&prefix, counter, index + 1 == chunks);
+    aead(key)?
+        .decrypt(Nonce::from_slice(&nonce), Payload { msg: &chunk, aad })
+        .map_err(|_| open_failed(counter))
+}
+
+impl ShokoArchive {
+    /// `write_file_direct` from a reader in constant memory: the input is compressed and sealed
+    /// chunk by chunk straight into the archive. needs a stream-encrypted (or unencrypted)
+    /// archive without padding and the stored or RLE codec, anything else is read in whole and
+    /// written as usual
+    pub fn write_file_from(&mut self, internal_path: &str, src: &mut impl Read, clevel: u8) -> io::Result<u64> {
+        self.ensure_index()?;
+        let cipher = self.cipher()?;
+        let streamable = Codec::of(clevel) != Codec::Lz
+            && matches!(cipher, Cipher::Plain | Cipher::Aes256GcmStream(_))
+            // padding needs the whole length up front
+            && self.padding == Padding::None;
+        if !streamable {
+            let mut content = Vec::new();
+            src.read_to_end(&mut content)?;
+            self.write_file_direct(internal_path, &content, clevel)?;
+            return Ok(content.len() as u64);
+        }
+
+        let (cipher, data_key) = match datakey::new_data_key(&cipher, self.archive_id.as_ref())? {
+            Some((cipher, wrapped)) => (cipher, Some(wrapped)),
+            None => (cipher, None),
+        };
+        let generation = self.next_generation();
+        let aad = BlobBinding::Entry { path: internal_path, generation }.aad(self.archive_id.as_ref());
+        let offset = self.data_end();
+        self.block_cache = None;
+
+        // the blob goes over the live index, whatever fails on the way has to put it back
+        let streamed = (|| -> io::Result<(u64, u64)> {
+            self.file.seek(SeekFrom::Start(offset))?;
+            let read = match cipher {
+                Cipher::Aes256GcmStream(key) => {
+                    let (writer, read) = encode_into(src, StreamWriter::new(&mut self.file, &key, &aad)?, clevel)?;
+                    writer.finish()?;
+                    read
+                }
+                _ => encode_into(src, &mut self.file, clevel)?.1,
+            };
+            Ok((read, self.file.stream_position()? - offset))
+        })();
+        let (read, size) = match streamed {
+            Ok(done) => done,
+            Err(e) => {
+                self.rewrite_index()?;
+                return Err(e);
+            }
+        };
+
+        self.entries.retain(|e| e.path != internal_path);
+        self.entries.push(ShokoEntry {
+            path: internal_path.to_string(),
+            size,
+            offset,
+            compression_level: clevel,
+            original_size: Some(read),
+            generation,
+            data_key,
+            ..Default::default()
+        });
+        self.rewrite_index()?;
+        Ok(read)
+    }
+
+    /// `extract_file` into a writer, in constant memory for entries `write_file_from` could
+    /// have streamed. everything else is decoded in memory first. returns the bytes written
+    pub fn extract_to(&mut self, internal_path: &str, out: &mut impl Write) -> io::Result<u64> {
+        self.ensure_index()?;
+        let cipher = self.cipher()?;
+        let entry = self.entries.iter()
+            .find(|e| e.path == internal_path)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "File not in archive"))?;
+        let streamable = !entry.is_solid() && !entry.is_chunked() && !entry.is_delta()
+            && Codec::of(entry.compression_level) != Codec::Lz
+            && matches!(cipher, Cipher::Plain | Cipher::Aes256GcmStream(_))
+            && self.padding == Padding::None;
+        if !streamable {
+            let data = self.extract_file(internal_path)?;
+            out.write_all(&data)?;
+            return Ok(data.len() as u64);
+        }
+
+        if let Some(size) = entry.original_size {
+            self.check_entry_len(size)?;
+        }
+        let (blob, generation, data_key) = (entry.blob(), entry.generation, entry.data_key.clone());
+        let cipher = self.data_cipher(data_key.as_deref())?;
+        let aad = BlobBinding::Entry { path: internal_path, generation }.aad(self.archive_id.as_ref());
+        let (max_out, kind) = self.blob_bound(blob.size);
+        let max_ratio = self.limits.max_ratio.unwrap_or(0);
+
+        self.file.seek(SeekFrom::Start(blob.offset))?;
+        let written = {
+            let sealed = (&mut self.file).take(blob.size);
+            let plain: Box<dyn Read + '_> = match cipher {
+                Cipher::Aes256GcmStream(key) => Box::new(StreamReader::new(sealed, &key, &aad)?),
+                _ => Box::new(sealed),
+            };
+            let mut decoded: Box<dyn Read + '_> = match Codec::of(blob.compression_level) {
+                Codec::Rle => Box::new(RleDecoder::with_limit(plain, max_out)),
+                _ => Box::new(plain.take(max_out.saturating_add(1))),
+            };
+            io::copy(&mut decoded, out).map_err(|e| match (LimitExceeded::from_io(&e), kind) {
+                (Some(_), LimitKind::Ratio) => LimitExceeded::new(LimitKind::Ratio, max_ratio).into(),
+                _ => e,
+            })?
+        };
+        if written > max_out {
+            let limit = if kind == LimitKind::Ratio { max_ratio } else { max_out };
+            return Err(LimitExceeded::new(kind, limit).into());
+        }
+        self.check_entry_len(written)?;
+        self.extracted += written;
+        Ok(written)
+    }
+}
+
+/// runs `src` through the codec into `dest`, returns `dest` and how much was read
+fn encode_into<W: Write>(src: &mut impl Read, mut dest: W, clevel: u8) -> io::Result<(W, u64)> {
+    if Codec::of(clevel) == Codec::Rle {
+        let mut encoder = RleEncoder::new(dest, clevel);
+        let read = io::copy(src, &mut encoder)?;
+        return Ok((encoder.finish()?, read));
+    }
+    let read = io::copy(src, &mut dest)?;
+    Ok((dest, read))
+}
@@ -1,48 +1,85 @@
 #[cfg(test)]
 mod tests {
-    use crate::archive::ShokoArchive;
+    use crate::archive::{ArchiveOptions, ShokoArchive};
+    use crate::encrypt::Argon2Params;
+    use crate::keys::RawKey;
     use std::fs;
+    use std::path::PathBuf;
+
+    /// argon2 at its lowest costs, the tests aren't here to wait on the KDF
+    const CHEAP: Argon2Params = Argon2Params { m_cost: 64, t_cost: 1, p_cost: 1 };
+    const SECRET: &str = "test suite secret";
+
+    /// a directory only one test writes into, removed when the test ends whether it passed or not
+    struct Scratch(PathBuf);
+
+    impl Scratch {
+        fn new(test: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("shoko-{}-{}", std::process::id(), test));
+            fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+
+        fn path(&self, name: &str) -> String {
+            self.0.join(name).to_str().unwrap().to_string()
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// a fresh encrypted archive already unlocked with SECRET, so nothing falls back to SHOKO_KEY
+    fn create(path: &str) -> ShokoArchive {
+        let mut archive = ShokoArchive::create_with(path, ArchiveOptions { kdf: Some(CHEAP), ..Default::default() }).unwrap();
+        archive.unlock_with(&RawKey::new(SECRET)).unwrap();
+        archive
+    }
+
+    fn open(path: &str) -> ShokoArchive {
+        ShokoArchive::open_with(path, &RawKey::new(SECRET)).unwrap()
+    }
 
     #[test]
     fn test_create_and_write_direct() {
-        let test_path = "test_archive.sk1";
-        let _ = fs::remove_file(test_path);
-        let mut archive = ShokoArchive::create(test_path).unwrap();
+        let scratch = Scratch::new("test_archive");
+        let test_path = &scratch.path("test_archive.sk1");
+        let mut archive = create(test_path);
         let content = b"wsg shoko heres some repeats or shi: AAAAAAAAAAAAAAAAAAAAA";
         archive.write_file_direct("test.txt", content, 5).unwrap();
-        let mut reopened = ShokoArchive::open(test_path).unwrap();
+        let mut reopened = open(test_path);
         let extracted = reopened.extract_file("test.txt").unwrap();
         assert_eq!(content.to_vec(), extracted);
-        fs::remove_file(test_path).unwrap();
     }
 
     #[test]
     fn test_multi_file_append() {
-        let test_path = "multi_test.sk1";
-        let _ = fs::remove_file(test_path);
-        let mut archive = ShokoArchive::create(test_path).unwrap();
+        let scratch = Scratch::new("multi_test");
+        let test_path = &scratch.path("multi_test.sk1");
+        let mut archive = create(test_path);
         archive.write_file_direct("file1.bin", &[1, 2, 3], 0).unwrap();
         archive.write_file_direct("file2.bin", &[4, 5, 6], 9).unwrap();
-        let mut reopened = ShokoArchive::open(test_path).unwrap();
+        let mut reopened = open(test_path);
         assert_eq!(reopened.entries.len(), 2);
         let f1 = reopened.extract_file("file1.bin").unwrap();
         let f2 = reopened.extract_file("file2.bin").unwrap();
         assert_eq!(f1, vec![1, 2, 3]);
         assert_eq!(f2, vec![4, 5, 6]);
-        fs::remove_file(test_path).unwrap();
     }
 
     #[test]
     fn test_deletion_and_defrag() {
-        let test_path = "delete_test.sk1";
-        let _ = fs::remove_file(test_path);
-        let mut archive = ShokoArchive::create(test_path).unwrap();
+        let scratch = Scratch::new("delete_test");
+        let test_path = &scratch.path("delete_test.sk1");
+        let mut archive = create(test_path);
         archive.write_file_direct("file1.txt", b"some data", 0).unwrap();
         archive.write_file_direct("file2.txt", b"more data here", 0).unwrap();
         let size_full = fs::metadata(test_path).unwrap().len();
         archive.delete_file("file1.txt").unwrap();
         assert_eq!(archive.entries.len(), 1);
-        let reopened = ShokoArchive::open(test_path).unwrap();
+        let reopened = open(test_path);
         assert_eq!(reopened.entries.len(), 1);
         assert_eq!(reopened.entries[0].path, "file2.txt");
         archive.defrag().unwrap();
@@ -51,14 +88,13 @@ mod tests {
         assert!(size_defragged < size_full, "Archive should be smaller after deleting a file and defragging");
         let content = archive.extract_file("file2.txt").unwrap();
         assert_eq!(content, b"more data here");
-        fs::remove_file(test_path).unwrap();
     }
 
     #[test]
     fn test_glob_matching() {
-        let test_path = "glob_test.sk1";
-        let _ = fs::remove_file(test_path);
-        let mut archive = ShokoArchive::create(test_path).unwrap();
+        let scratch = Scratch::new("glob_test");
+        let test_path = &scratch.path("glob_test.sk1");
+        let mut archive = create(test_path);
         archive.write_file_direct("logs/today.log", b"test", 0).unwrap();
         archive.write_file_direct("logs/yesterday.log", b"test", 0).unwrap();
         archive.write_file_direct("data/db.sqlite", b"test", 0).unwrap();
@@ -69,20 +105,1243 @@ mod tests {
         let md_matches = archive.match_glob("*.md").unwrap();
         assert_eq!(md_matches.len(), 1);
         assert_eq!(md_matches[0], "README.md");
-        fs::remove_file(test_path).unwrap();
     }
 
     #[test]
     fn test_overwrite_integrity() {
-        let test_path = "overwrite_integrity.sk1";
-        let _ = fs::remove_file(test_path);
-        let mut archive = ShokoArchive::create(test_path).unwrap();
+        let scratch = Scratch::new("overwrite_integrity");
+        let test_path = &scratch.path("overwrite_integrity.sk1");
+        let mut archive = create(test_path);
         archive.write_file_direct("config.toml", b"key = value", 0).unwrap();
         archive.write_file_direct("config.toml", b"new_key = long_value_string", 0).unwrap();
-        let mut reopened = ShokoArchive::open(test_path).unwrap();
+        let mut reopened = open(test_path);
         let data = reopened.extract_file("config.toml").unwrap();
         assert_eq!(data, b"new_key = long_value_string");
         assert_eq!(reopened.entries.len(), 1);
-        fs::remove_file(test_path).unwrap();
+    }
+
+    #[test]
+    fn test_parallel_bulk_insert() {
+        use crate::pipeline::PackItem;
+        let scratch = Scratch::new("parallel_bulk");
+        let test_path = &scratch.path("parallel_bulk.sk1");
+        let mut archive = create(test_path);
+        archive.write_file_direct("keep.txt", b"old", 0).unwrap();
+        archive.write_file_direct("f3.txt", b"replaced", 0).unwrap();
+        let items = (0..32)
+            .map(|i| PackItem::bytes(format!("f{}.txt", i), vec![i as u8; 100 + i]))
+            .collect();
+        let written = archive.write_files_parallel(items, 5, 4).unwrap();
+        assert_eq!(written.len(), 32);
+        let mut reopened = open(test_path);
+        assert_eq!(reopened.entries.len(), 33);
+        assert_eq!(reopened.extract_file("keep.txt").unwrap(), b"old");
+        for i in 0..32 {
+            let data = reopened.extract_file(&format!("f{}.txt", i)).unwrap();
+            assert_eq!(data, vec![i as u8; 100 + i]);
+        }
+
+        // the same path twice in one batch is one entry, the later copy
+        let items = vec![
+            PackItem::bytes("twice.txt", b"first".to_vec()),
+            PackItem::bytes("once.txt", b"only".to_vec()),
+            PackItem::bytes("twice.txt", b"second".to_vec()),
+        ];
+        assert_eq!(reopened.write_files_parallel(items, 5, 2).unwrap(), ["once.txt", "twice.txt"]);
+        let mut reopened = open(test_path);
+        assert_eq!(reopened.entries.iter().filter(|e| e.path == "twice.txt").count(), 1);
+        assert_eq!(reopened.extract_file("twice.txt").unwrap(), b"second");
+        assert_eq!(reopened.extract_file("once.txt").unwrap(), b"only");
+    }
+
+    #[test]
+    fn test_pack_dir_parallel() {
+        let scratch = Scratch::new("pack_dir");
+        let src = &scratch.path("src");
+        let test_path = &scratch.path("pack_dir.sk1");
+        fs::create_dir_all(format!("{}/a/b", src)).unwrap();
+        fs::create_dir_all(format!("{}/c", src)).unwrap();
+        fs::write(format!("{}/root.txt", src), b"root").unwrap();
+        fs::write(format!("{}/a/b/deep.txt", src), b"deep deep deep").unwrap();
+        fs::write(format!("{}/c/other.bin", src), [7u8; 64]).unwrap();
+        let mut archive = create(test_path);
+        let packed = archive.pack_dir(std::path::Path::new(src), 3, 3).unwrap();
+        assert_eq!(packed, vec!["a/b/deep.txt", "c/other.bin", "root.txt"]);
+        let mut reopened = open(test_path);
+        assert_eq!(reopened.extract_file("a/b/deep.txt").unwrap(), b"deep deep deep");
+        assert_eq!(reopened.extract_file("c/other.bin").unwrap(), vec![7u8; 64]);
+    }
+
+    #[test]
+    fn test_rle_stream_roundtrip() {
+        use crate::compress::{compress, RleEncoder};
+        use crate::decompress::RleDecoder;
+        use std::io::{Read, Write};
+
+        let mut data = Vec::new();
+        for i in 0..5_000u32 {
+            let len = (i % 7) as usize * 60;
+            data.extend(std::iter::repeat_n((i % 251) as u8, len));
+            data.extend_from_slice(&i.to_le_bytes());
+        }
+
+        for clevel in [1, 5, 9] {
+            let mut encoder = RleEncoder::new(Vec::new(), clevel);
+            for piece in data.chunks(1000) {
+                encoder.write_all(piece).unwrap();
+            }
+            let encoded = encoder.finish().unwrap();
+            assert_eq!(encoded, compress(&data, clevel));
+
+            let mut decoder = RleDecoder::new(&encoded[..]);
+            let mut decoded = Vec::new();
+            let mut buf = [0u8; 77];
+            loop {
+                let n = decoder.read(&mut buf).unwrap();
+                if n == 0 {
+                    break;
+                }
+                decoded.extend_from_slice(&buf[..n]);
+            }
+            assert_eq!(decoded, data);
+        }
+
+        let truncated = [0x01, 10, b'a', b'b'];
+        let mut out = Vec::new();
+        let err = RleDecoder::new(&truncated[..]).read_to_end(&mut out).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_delta_overwrite_chain() {
+        let scratch = Scratch::new("delta_chain");
+        let test_path = &scratch.path("delta_chain.sk1");
+        let mut archive = create(test_path);
+        archive.set_delta_mode(3);
+
+        let mut config: Vec<u8> = (0..4000u32).flat_map(|i| format!("key{} = {}\n", i, i * 7).into_bytes()).collect();
+        archive.write_file_direct("config.toml", &config, 5).unwrap();
+        let full_size = archive.entries[0].size;
+
+        let mut chain_lens = Vec::new();
+        for edit in 0..5 {
+            let at = 1000 + edit * 3000;
+            config[at..at + 4].copy_from_slice(b"EDIT");
+            archive.write_file_direct("config.toml", &config, 5).unwrap();
+            let entry = &archive.entries[0];
+            chain_lens.push(entry.delta_chain.len());
+            if entry.is_delta() {
+                assert!(entry.size < full_size / 4);
+            }
+        }
+        assert_eq!(chain_lens, vec![1, 2, 3, 0, 1]);
+
+        let mut reopened = open(test_path);
+        assert_eq!(reopened.extract_file("config.toml").unwrap(), config);
+        reopened.defrag().unwrap();
+        assert!(!reopened.entries[0].is_delta());
+        assert_eq!(reopened.extract_file("config.toml").unwrap(), config);
+    }
+
+    #[test]
+    fn test_v1_archive_upgrade() {
+        use crate::encrypt::{Cipher, Encryption};
+        use crate::read::ShokoReader;
+        use crate::shadow::SecretKey;
+        use crate::write::prepare_blob;
+        use std::io::Write;
+
+        let scratch = Scratch::new("v1_upgrade");
+        let test_path = &scratch.path("v1_upgrade.sk1");
+        // v1 blobs are AES-256-GCM under the raw key, no AAD
+        let key = RawKey::new(vec![0x5a; 32]);
+        let cipher = Cipher::new(Encryption::Aes256Gcm, SecretKey::from_slice(&[0x5a; 32]).unwrap());
+        let blob = prepare_blob(b"legacy contents", 0, &cipher).unwrap();
+        let mut raw = b"SHOKO001".to_vec();
+        raw.extend_from_slice(&blob);
+        let index_start = raw.len() as u64;
+        raw.extend_from_slice(&(8u32).to_le_bytes());
+        raw.extend_from_slice(b"old.txt\0");
+        raw.extend_from_slice(&(blob.len() as u64).to_le_bytes());
+        raw.extend_from_slice(&8u64.to_le_bytes());
+        raw.push(0);
+        raw.extend_from_slice(&index_start.to_le_bytes());
+        raw.extend_from_slice(&1u32.to_le_bytes());
+        raw.extend_from_slice(b"SK");
+        fs::File::create(test_path).unwrap().write_all(&raw).unwrap();
+        // the reader never goes looking for a key on its own
+        let mut file = fs::File::open(test_path).unwrap();
+        let err = ShokoReader::new(&mut file).read_blob(8, blob.len() as u64, 0).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        let mut reader = ShokoReader::new(&mut file).with_cipher(cipher);
+        assert_eq!(reader.read_blob(8, blob.len() as u64, 0).unwrap(), b"legacy contents");
+
+        let mut archive = ShokoArchive::open_with(test_path, &key).unwrap();
+        assert_eq!(archive.extract_file("old.txt\0").unwrap(), b"legacy contents");
+        archive.write_file_direct("new.txt", b"fresh", 3).unwrap();
+        let mut reopened = ShokoArchive::open_with(test_path, &key).unwrap();
+        assert_eq!(&fs::read(test_path).unwrap()[..8], b"SHOKO003");
+        assert_eq!(reopened.extract_file("old.txt\0").unwrap(), b"legacy contents");
+        assert_eq!(reopened.extract_file("new.txt").unwrap(), b"fresh");
+    }
+
+    #[test]
+    fn test_solid_blocks() {
+        use crate::pipeline::PackItem;
+        use crate::solid::SolidOptions;
+        let scratch = Scratch::new("solid_blocks");
+        let test_path = &scratch.path("solid_blocks.sk1");
+        let mut archive = create(test_path);
+        let mut items: Vec<PackItem> = (0..40)
+            .map(|i| PackItem::bytes(format!("cfg/{}.json", i), format!("{{\"id\": {}, \"on\": true}}", i).into_bytes()))
+            .collect();
+        items.push(PackItem::bytes("big.bin", vec![9u8; 5000]));
+        let opts = SolidOptions { block_size: 300, max_member_size: 1024 };
+        archive.write_files_solid(items, 5, 2, opts).unwrap();
+
+        let solid: Vec<_> = archive.entries.iter().filter(|e| e.is_solid()).collect();
+        assert_eq!(solid.len(), 40);
+        assert!(!archive.entries.iter().find(|e| e.path == "big.bin").unwrap().is_solid());
+        let blocks: std::collections::HashSet<u64> = solid.iter().map(|e| e.offset).collect();
+        assert!(blocks.len() > 1 && blocks.len() < 40);
+
+        let mut reopened = open(test_path);
+        assert_eq!(reopened.extract_file("cfg/17.json").unwrap(), b"{\"id\": 17, \"on\": true}");
+        assert_eq!(reopened.extract_file("big.bin").unwrap(), vec![9u8; 5000]);
+
+        reopened.delete_file("cfg/3.json").unwrap();
+        reopened.defrag().unwrap();
+        assert_eq!(reopened.entries.iter().filter(|e| e.is_solid()).count(), 39);
+        for i in (0..40).filter(|i| *i != 3) {
+            let data = reopened.extract_file(&format!("cfg/{}.json", i)).unwrap();
+            assert_eq!(data, format!("{{\"id\": {}, \"on\": true}}", i).into_bytes());
+        }
+        // a slice from a hostile index that wraps around
+        let member = reopened.entries.iter_mut().find(|e| e.is_solid()).unwrap();
+        member.solid = Some(crate::archive::SolidSlice { offset: u64::MAX - 1, len: 4 });
+        let path = member.path.clone();
+        assert_eq!(reopened.extract_file(&path).unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_lz_codec_roundtrip() {
+        use crate::lz;
+        let dict = b"{\"name\": \"service\", \"enabled\": true, \"replicas\": ".to_vec();
+        let samples: Vec<Vec<u8>> = vec![
+            Vec::new(),
+            b"a".to_vec(),
+            b"abcabcabcabcabcabcabcabcabc".to_vec(),
+            vec![0u8; 10_000],
+            (0..5000u32).flat_map(|i| (i.wrapping_mul(2654435761) >> 7).to_le_bytes()).collect(),
+            b"{\"name\": \"service\", \"enabled\": true, \"replicas\": 3}".to_vec(),
+        ];
+        for data in &samples {
+            for level in [1, 9] {
+                assert_eq!(&lz::decompress(&lz::compress(data, &[], level), &[]).unwrap(), data);
+                assert_eq!(&lz::decompress(&lz::compress(data, &dict, level), &dict).unwrap(), data);
+            }
+        }
+        let with_dict = lz::compress(&samples[5], &dict, 5);
+        let without = lz::compress(&samples[5], &[], 5);
+        assert!(with_dict.len() * 3 < without.len());
+    }
+
+    #[test]
+    fn test_archive_dictionary() {
+        use crate::codec;
+        let scratch = Scratch::new("dictionary");
+        let test_path = &scratch.path("dictionary.sk1");
+        let docs: Vec<Vec<u8>> = (0..80)
+            .map(|i| format!("{{\"apiVersion\": \"apps/v1\", \"kind\": \"Deployment\", \"metadata\": {{\"name\": \"svc-{}\", \"namespace\": \"prod\"}}, \"replicas\": {}}}", i, i % 5).into_bytes())
+            .collect();
+        let samples: Vec<&[u8]> = docs.iter().map(|d| d.as_slice()).collect();
+
+        let mut plain = create(&scratch.path("dictionary_plain.sk1"));
+        let mut archive = create(test_path);
+        let id = archive.train_dictionary(&samples, 4096).unwrap();
+        assert_eq!(archive.active_dictionary(), Some(id));
+        for (i, doc) in docs.iter().enumerate() {
+            archive.write_file_direct(&format!("k8s/{}.json", i), doc, codec::lz(6)).unwrap();
+            plain.write_file_direct(&format!("k8s/{}.json", i), doc, codec::lz(6)).unwrap();
+        }
+        let with_dict: u64 = archive.entries.iter().map(|e| e.size).sum();
+        let without: u64 = plain.entries.iter().map(|e| e.size).sum();
+        assert!(with_dict * 2 < without, "{} vs {}", with_dict, without);
+
+        let mut reopened = open(test_path);
+        assert_eq!(reopened.dictionary_ids(), vec![id]);
+        assert_eq!(reopened.extract_file("k8s/42.json").unwrap(), docs[42]);
+        reopened.delete_file("k8s/0.json").unwrap();
+        reopened.defrag().unwrap();
+        assert_eq!(reopened.dictionary_ids().len(), 1);
+        assert_eq!(reopened.extract_file("k8s/79.json").unwrap(), docs[79]);
+    }
+
+    #[test]
+    fn test_chunk_dedup() {
+        use crate::chunk;
+        let scratch = Scratch::new("chunk_dedup");
+        let test_path = &scratch.path("chunk_dedup.sk1");
+        let mut archive = create(test_path);
+        let base: Vec<u8> = (0..200_000u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8).collect();
+        let mut shifted = b"a few new bytes up front".to_vec();
+        shifted.extend_from_slice(&base);
+
+        archive.write_file_chunked("snap1/out.bin", &base, 0).unwrap();
+        let stored = archive.chunks.len();
+        let end = archive.data_end();
+        archive.write_file_chunked("snap2/out.bin", &base, 0).unwrap();
+        assert_eq!(archive.chunks.len(), stored);
+        assert_eq!(archive.data_end(), end);
+
+        // only the chunks around the insert should be new
+        archive.write_file_chunked("snap3/out.bin", &shifted, 0).unwrap();
+        assert!(archive.chunks.len() - stored <= 2);
+        assert_eq!(chunk::split(&base).concat(), base);
+
+        let mut reopened = open(test_path);
+        assert_eq!(reopened.extract_file("snap2/out.bin").unwrap(), base);
+        assert_eq!(reopened.extract_file("snap3/out.bin").unwrap(), shifted);
+        assert!(reopened.chunk_refcounts().values().all(|refs| *refs >= 1));
+    }
+
+    #[test]
+    fn test_chunk_gc_and_defrag() {
+        let scratch = Scratch::new("chunk_gc");
+        let test_path = &scratch.path("chunk_gc.sk1");
+        let mut archive = create(test_path);
+        let a: Vec<u8> = (0..50_000u32).map(|i| (i.wrapping_mul(40503) >> 5) as u8).collect();
+        let b: Vec<u8> = (0..50_000u32).map(|i| (i.wrapping_mul(69069) >> 9) as u8).collect();
+        archive.write_file_chunked("a.bin", &a, 5).unwrap();
+        archive.write_file_chunked("b.bin", &b, 5).unwrap();
+        archive.write_file_chunked("empty", &[], 5).unwrap();
+        let total = archive.chunks.len();
+
+        // deleting drops a's chunks from the store right away. they sit in front of b's, so only
+        // defrag can hand their space back
+        archive.delete_file("a.bin").unwrap();
+        assert!(archive.chunks.len() < total);
+        assert!(archive.chunk_refcounts().values().all(|refs| *refs >= 1));
+        assert_eq!(archive.collect_garbage().unwrap(), 0);
+        // an overwrite leaves the old chunks for collect_garbage
+        let c: Vec<u8> = b.iter().rev().copied().collect();
+        archive.write_file_chunked("b.bin", &c, 5).unwrap();
+        let dead = archive.chunk_refcounts().values().filter(|refs| **refs == 0).count();
+        assert!(dead > 0);
+        assert_eq!(archive.collect_garbage().unwrap(), dead);
+        assert!(archive.chunk_refcounts().values().all(|refs| *refs >= 1));
+
+        let before = fs::metadata(test_path).unwrap().len();
+        archive.defrag().unwrap();
+        assert!(fs::metadata(test_path).unwrap().len() < before);
+        assert_eq!(archive.extract_file("b.bin").unwrap(), c);
+        assert_eq!(archive.extract_file("empty").unwrap(), Vec::<u8>::new());
+        assert!(archive.entries.iter().all(|e| e.is_chunked()));
+    }
+
+    /// the byte-by-byte encoder the word-at-a-time one replaced, its output is the format
+    fn reference_rle(data: &[u8], threshold: usize) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut i = 0;
+        while i < data.len() {
+            let mut run_len = 1;
+            while i + run_len < data.len() && data[i + run_len] == data[i] && run_len < 255 {
+                run_len += 1;
+            }
+            if run_len >= threshold {
+                out.extend_from_slice(&[0x00, run_len as u8, data[i]]);
+                i += run_len;
+                continue;
+            }
+            let mut end = i;
+            while end < data.len() && (end + 1 >= data.len() || data[end] != data[end + 1]) && end - i < 254 {
+                end += 1;
+            }
+            let end = end.max(i + 1);
+            out.extend_from_slice(&[0x01, (end - i) as u8]);
+            out.extend_from_slice(&data[i..end]);
+            i = end;
+        }
+        out
+    }
+
+    #[test]
+    fn test_rle_matches_reference() {
+        use crate::compress::compress;
+        use crate::decompress::decompress;
+        let mut state = 0x9e37_79b9u32;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state
+        };
+        let mut samples: Vec<Vec<u8>> = vec![
+            Vec::new(),
+            vec![7],
+            vec![7, 7],
+            vec![0u8; 1000],
+            b"aab".to_vec(),
+            (0..=255u8).cycle().take(3000).collect(),
+        ];
+        for max_run in [2, 3, 5, 12, 300] {
+            let mut data = Vec::new();
+            while data.len() < 20_000 {
+                let r = next();
+                data.extend(std::iter::repeat_n((r % 4) as u8, 1 + (r >> 8) as usize % max_run));
+            }
+            samples.push(data);
+        }
+
+        for data in &samples {
+            for (clevel, threshold) in [(1, 4), (5, 3), (9, 2), (0, 3)] {
+                let packed = compress(data, clevel);
+                assert_eq!(packed, reference_rle(data, threshold));
+                assert_eq!(&decompress(&packed).unwrap(), data);
+            }
+        }
+        assert!(decompress(&[0x01, 5, 1, 2]).is_err());
+        assert!(decompress(&[0x00, 5]).is_err());
+    }
+
+    #[test]
+    fn test_compression_stats() {
+        use crate::pipeline::PackItem;
+        use crate::solid::SolidOptions;
+        use crate::stats::Layout;
+        let scratch = Scratch::new("compression_stats");
+        let test_path = &scratch.path("compression_stats.sk1");
+        let mut archive = create(test_path);
+        archive.write_file_direct("zeros.bin", &[0u8; 4096], 9).unwrap();
+        let items = (0..4).map(|i| PackItem::bytes(format!("s/{}.txt", i), vec![b'a' + i as u8; 100])).collect();
+        archive.write_files_solid(items, 5, 1, SolidOptions::default()).unwrap();
+
+        let mut reopened = open(test_path);
+        let stats = reopened.entry_stats().unwrap();
+        let zeros = stats.iter().find(|e| e.path == "zeros.bin").unwrap();
+        assert_eq!(zeros.original_size, 4096);
+        assert_eq!(zeros.layout, Layout::Plain);
+        // single chunk STREAM blobs: the header and one tag
+        let per_blob = crate::stream::sealed_len(0);
+        assert_eq!(zeros.encryption_overhead, per_blob);
+        assert!(zeros.ratio() > 10.0);
+
+        let solid: Vec<_> = stats.iter().filter(|e| e.layout == Layout::Solid).collect();
+        assert_eq!(solid.len(), 4);
+        assert!(solid.iter().all(|e| e.original_size == 100));
+
+        let total = reopened.archive_stats().unwrap();
+        assert_eq!(total.entries, 5);
+        assert_eq!(total.original_size, 4096 + 400);
+        assert_eq!(total.encryption_overhead, 2 * per_blob);
+        assert_eq!(total.dead_size, 0);
+        assert_eq!(total.file_size, fs::metadata(test_path).unwrap().len());
+
+        reopened.delete_file("s/2.txt").unwrap();
+        reopened.write_file_direct("zeros.bin", &[1u8; 10], 0).unwrap();
+        assert!(reopened.archive_stats().unwrap().dead_size > 0);
+    }
+
+    #[test]
+    fn test_decompression_bomb_limits() {
+        use crate::decompress::{decompress_limited, RleDecoder};
+        use crate::limits::{LimitExceeded, LimitKind};
+        use crate::lz;
+        use std::io::Read;
+        let bomb: Vec<u8> = [0x00, 255, 0].repeat(1000);
+        let err = decompress_limited(&bomb, 100_000).unwrap_err();
+        assert_eq!(LimitExceeded::from_io(&err), Some(&LimitExceeded::new(LimitKind::EntrySize, 100_000)));
+        assert_eq!(decompress_limited(&bomb, 255_000).unwrap().len(), 255_000);
+
+        let mut sink = Vec::new();
+        let err = RleDecoder::with_limit(&bomb[..], 4096).read_to_end(&mut sink).unwrap_err();
+        assert!(LimitExceeded::from_io(&err).is_some());
+
+        let packed = lz::compress(&vec![7u8; 1_000_000], &[], 9);
+        let err = lz::decompress_limited(&packed, &[], 10_000).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::FileTooLarge);
+
+        // a delta claiming a petabyte of output, with nothing behind it
+        let claim = [0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x02];
+        let err = crate::delta::apply_limited(b"old", &claim, u64::MAX).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_archive_extraction_limits() {
+        use crate::limits::{LimitExceeded, LimitKind, Limits};
+        let scratch = Scratch::new("extraction_limits");
+        let test_path = &scratch.path("extraction_limits.sk1");
+        let mut archive = create(test_path);
+        archive.write_file_direct("zeros.bin", &vec![0u8; 100_000], 9).unwrap();
+        archive.write_file_direct("a.txt", &[b'a'; 1000], 0).unwrap();
+        archive.write_file_direct("b.txt", &[b'b'; 1000], 0).unwrap();
+
+        let kind_of = |e: std::io::Error| LimitExceeded::from_io(&e).map(|l| l.kind);
+        let mut reopened = open(test_path);
+        reopened.set_limits(Limits { max_entry_size: Some(50_000), ..Default::default() });
+        assert_eq!(kind_of(reopened.extract_file("zeros.bin").unwrap_err()), Some(LimitKind::EntrySize));
+
+        reopened.set_limits(Limits { max_ratio: Some(10), ..Default::default() });
+        assert_eq!(kind_of(reopened.extract_file("zeros.bin").unwrap_err()), Some(LimitKind::Ratio));
+        assert_eq!(reopened.extract_file("a.txt").unwrap().len(), 1000);
+
+        reopened.set_limits(Limits { max_total_size: Some(1500), ..Default::default() });
+        reopened.extract_file("a.txt").unwrap();
+        assert_eq!(kind_of(reopened.extract_file("b.txt").unwrap_err()), Some(LimitKind::TotalSize));
+        assert_eq!(reopened.extracted_bytes(), 1000);
+
+        reopened.set_limits(Limits::untrusted());
+        assert_eq!(reopened.extract_file("zeros.bin").unwrap(), vec![0u8; 100_000]);
+    }
+
+    #[test]
+    fn test_unencrypted_archive() {
+        use crate::encrypt::Encryption;
+        let scratch = Scratch::new("unencrypted");
+        let test_path = &scratch.path("unencrypted.sk1");
+        let options = ArchiveOptions { encryption: Encryption::None, ..Default::default() };
+        let content = b"public asset, nothing to hide here";
+
+        let empty = ShokoArchive::create_with(test_path, options).unwrap();
+        drop(empty);
+        let mut archive = ShokoArchive::open(test_path).unwrap();
+        assert_eq!(archive.encryption(), Encryption::None);
+        archive.write_file_direct("asset.txt", content, 0).unwrap();
+        archive.write_file_direct("gone.txt", b"deleted soon", 0).unwrap();
+        assert_eq!(archive.entries[0].size, content.len() as u64);
+
+        let raw = fs::read(test_path).unwrap();
+        assert!(raw.windows(content.len()).any(|w| w == content));
+
+        let mut reopened = ShokoArchive::open(test_path).unwrap();
+        assert_eq!(reopened.extract_file("asset.txt").unwrap(), content);
+        reopened.delete_file("gone.txt").unwrap();
+        reopened.defrag().unwrap();
+        assert_eq!(reopened.encryption(), Encryption::None);
+        assert_eq!(reopened.extract_file("asset.txt").unwrap(), content);
+        assert_eq!(reopened.archive_stats().unwrap().encryption_overhead, 0);
+    }
+
+    #[test]
+    fn test_passphrase_key_derivation() {
+        use crate::encrypt::KeyDerivation;
+        let scratch = Scratch::new("passphrase_kdf");
+        let test_path = &scratch.path("passphrase_kdf.sk1");
+        let options = ArchiveOptions { kdf: Some(CHEAP), ..Default::default() };
+
+        let mut archive = ShokoArchive::create_with(test_path, options).unwrap();
+        archive.unlock(b"correct horse").unwrap();
+        archive.write_file_direct("secret.txt", b"under a short passphrase", 5).unwrap();
+        // the archive key is random, the passphrase only opens the creator's slot
+        assert_eq!(archive.key_derivation(), KeyDerivation::Wrapped);
+        let KeyDerivation::Argon2id { params, salt } = archive.key_slots()[0].kdf else {
+            panic!("the creator's slot should derive its key");
+        };
+        assert_eq!(params, CHEAP);
+        assert_eq!(archive.options().kdf, Some(CHEAP));
+        assert_eq!(KeyDerivation::from_bytes(&archive.key_slots()[0].kdf.to_bytes()).unwrap(), archive.key_slots()[0].kdf);
+        // costs come from an unauthenticated header, anything past the caps is refused before deriving
+        let greedy = KeyDerivation::Argon2id { params: Argon2Params { m_cost: u32::MAX, ..CHEAP }, salt: [0; 16] };
+        assert_eq!(KeyDerivation::from_bytes(&greedy.to_bytes()).unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+        let slow = ArchiveOptions { kdf: Some(Argon2Params { t_cost: 17, ..CHEAP }), ..Default::default() };
+        assert_eq!(ShokoArchive::create_with(&scratch.path("slow.sk1"), slow).err().map(|e| e.kind()), Some(std::io::ErrorKind::InvalidInput));
+
+        let mut reopened = ShokoArchive::open(test_path).unwrap();
+        assert_eq!(reopened.key_slots()[0].kdf, KeyDerivation::Argon2id { params, salt });
+        let err = reopened.unlock(b"wrong horse").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+        reopened.unlock(b"correct horse").unwrap();
+        assert_eq!(reopened.extract_file("secret.txt").unwrap(), b"under a short passphrase");
+
+        // same passphrase, different salt and a different random key
+        let mut other = ShokoArchive::create_with(&scratch.path("passphrase_kdf_other.sk1"), options).unwrap();
+        other.unlock(b"correct horse").unwrap();
+        assert_ne!(other.key_slots()[0].kdf, reopened.key_slots()[0].kdf);
+        assert_ne!(other.key_id(), reopened.key_id());
+
+        let raw = ArchiveOptions { kdf: None, ..Default::default() };
+        let mut raw_key = ShokoArchive::create_with(test_path, raw).unwrap();
+        assert_eq!(raw_key.options().kdf, None);
+        assert!(raw_key.unlock(b"31 bytes is not a valid raw key").is_err());
+        raw_key.unlock(&[7u8; 32]).unwrap();
+        assert_eq!(raw_key.key_slots()[0].kdf, KeyDerivation::Raw);
+    }
+
+    #[test]
+    fn test_key_providers() {
+        use crate::keys::{EnvKey, KeyEncoding, KeyFile, KeyProvider};
+        let scratch = Scratch::new("key_providers");
+        let (path_a, path_b, key_b) = (&scratch.path("provider_a.sk1"), &scratch.path("provider_b.sk1"), &scratch.path("provider_b.key"));
+        let options = ArchiveOptions { kdf: Some(CHEAP), ..Default::default() };
+
+        let mut a = ShokoArchive::create_with(path_a, options).unwrap();
+        a.unlock_with(&RawKey::new("alpha")).unwrap();
+        a.write_file_direct("a.txt", b"from archive a", 5).unwrap();
+        let mut b = ShokoArchive::create_with(path_b, options).unwrap();
+        b.unlock_with(&RawKey::new("bravo")).unwrap();
+        b.write_file_direct("b.txt", b"from archive b", 5).unwrap();
+        drop((a, b));
+
+        // two handles with different keys at once, neither touches SHOKO_KEY
+        fs::write(key_b, "bravo\n").unwrap();
+        let mut a = ShokoArchive::open_with(path_a, &RawKey::new("alpha")).unwrap();
+        let mut b = ShokoArchive::open_with(path_b, &KeyFile::new(key_b).with_encoding(KeyEncoding::Text)).unwrap();
+        assert_eq!(a.extract_file("a.txt").unwrap(), b"from archive a");
+        assert_eq!(b.extract_file("b.txt").unwrap(), b"from archive b");
+
+        assert!(EnvKey::new("SHOKO_TEST_UNSET_KEY_VAR").secret().is_err());
+        assert!(ShokoArchive::open_with(path_a, &KeyFile::new(scratch.path("no_such.key"))).is_err());
+    }
+
+    #[test]
+    fn test_blobs_bound_to_their_entry() {
+        use crate::archive::BlobRef;
+        let scratch = Scratch::new("aad_binding");
+        let test_path = &scratch.path("aad_binding.sk1");
+        let key = RawKey::new(vec![0x42; 32]);
+        let options = ArchiveOptions { kdf: None, ..Default::default() };
+        let mut archive = ShokoArchive::create_with(test_path, options).unwrap();
+        archive.unlock_with(&key).unwrap();
+        archive.write_file_direct("a.txt", b"contents of a", 5).unwrap();
+        archive.write_file_direct("b.txt", b"contents of b", 5).unwrap();
+        let old_a = archive.entries[0].blob();
+        archive.write_file_direct("a.txt", b"contents of a, take two", 5).unwrap();
+        assert!(archive.archive_id().is_some());
+
+        // what someone with write access to the plaintext index could do
+        let point_at = |archive: &mut ShokoArchive, path: &str, blob: BlobRef| {
+            let entry = archive.entries.iter_mut().find(|e| e.path == path).unwrap();
+            entry.offset = blob.offset;
+            entry.size = blob.size;
+        };
+        let blob_of = |archive: &ShokoArchive, path: &str| archive.entries.iter().find(|e| e.path == path).unwrap().blob();
+        let (a, b) = (blob_of(&archive, "a.txt"), blob_of(&archive, "b.txt"));
+
+        point_at(&mut archive, "a.txt", b);
+        point_at(&mut archive, "b.txt", a);
+        archive.rewrite_index().unwrap();
+        let mut swapped = ShokoArchive::open_with(test_path, &key).unwrap();
+        assert!(swapped.extract_file("a.txt").is_err());
+        assert!(swapped.extract_file("b.txt").is_err());
+
+        // both pointing at b.txt's blob
+        point_at(&mut swapped, "b.txt", b);
+        assert!(swapped.extract_file("a.txt").is_err());
+        assert_eq!(swapped.extract_file("b.txt").unwrap(), b"contents of b");
+        // the old a.txt blob is still in the file, but it was sealed under an older generation
+        point_at(&mut swapped, "a.txt", old_a);
+        assert!(swapped.extract_file("a.txt").is_err());
+        point_at(&mut swapped, "a.txt", a);
+        assert_eq!(swapped.extract_file("a.txt").unwrap(), b"contents of a, take two");
+    }
+
+    #[test]
+    fn test_encrypted_index() {
+        let scratch = Scratch::new("sealed_index");
+        let test_path = &scratch.path("sealed_index.sk1");
+        let options = ArchiveOptions { kdf: Some(CHEAP), encrypt_index: true, ..Default::default() };
+        let mut archive = ShokoArchive::create_with(test_path, options).unwrap();
+        archive.unlock(b"index passphrase").unwrap();
+        archive.write_file_direct("secret/plans.txt", b"the plans", 5).unwrap();
+        archive.write_file_direct("secret/names.txt", b"the names", 5).unwrap();
+        drop(archive);
+
+        let raw = fs::read(test_path).unwrap();
+        assert!(!raw.windows(b"plans.txt".len()).any(|w| w == b"plans.txt"));
+
+        let mut locked = ShokoArchive::open(test_path).unwrap();
+        assert!(locked.is_locked());
+        assert!(locked.entries.is_empty());
+        assert!(locked.options().encrypt_index);
+        assert!(locked.unlock_with(&RawKey::new("wrong passphrase")).is_err());
+        assert!(locked.is_locked());
+
+        let mut archive = ShokoArchive::open_with(test_path, &RawKey::new("index passphrase")).unwrap();
+        assert_eq!(archive.entries.len(), 2);
+        assert_eq!(archive.extract_file("secret/plans.txt").unwrap(), b"the plans");
+        archive.delete_file("secret/names.txt").unwrap();
+        archive.defrag().unwrap();
+        drop(archive);
+
+        let mut reopened = ShokoArchive::open_with(test_path, &RawKey::new("index passphrase")).unwrap();
+        assert!(reopened.options().encrypt_index);
+        let paths: Vec<&str> = reopened.entries.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(paths, ["secret/plans.txt"]);
+        assert_eq!(reopened.extract_file("secret/plans.txt").unwrap(), b"the plans");
+
+        // emptied with the key it's still sealed, not the empty form a keyless new archive writes
+        reopened.delete_file("secret/plans.txt").unwrap();
+        assert!(ShokoArchive::open(test_path).unwrap().is_locked());
+        // which is what someone swapping the index out would have to write
+        reopened.cipher = None;
+        reopened.rewrite_index().unwrap();
+        assert_eq!(ShokoArchive::open(test_path).err().map(|e| e.kind()), Some(std::io::ErrorKind::InvalidData));
+    }
+
+    #[test]
+    fn test_stream_aead() {
+        use crate::stream::{self, CHUNK_LEN, STREAM_HEADER_LEN, TAG_LEN};
+        use std::io::Cursor;
+        let key = [7u8; 32];
+        let data: Vec<u8> = (0..3 * CHUNK_LEN + 100).map(|i| (i * 31 % 251) as u8).collect();
+
+        for len in [0, 1, CHUNK_LEN, CHUNK_LEN + 1, data.len()] {
+            let sealed = stream::seal(&key, &data[..len], b"aad").unwrap();
+            assert_eq!(sealed.len() as u64, stream::sealed_len(len as u64));
+            assert_eq!(stream::unseal(&key, &sealed, b"aad").unwrap(), &data[..len]);
+            assert!(stream::unseal(&key, &sealed, b"other aad").is_err());
+        }
+
+        let sealed = stream::seal(&key, &data, b"aad").unwrap();
+        let sealed_chunk = CHUNK_LEN + TAG_LEN;
+        // cut off right after a full chunk, or with the last chunk dropped
+        assert!(stream::unseal(&key, &sealed[..STREAM_HEADER_LEN + 2 * sealed_chunk], b"aad").is_err());
+        assert!(stream::unseal(&key, &sealed[..sealed.len() - 1], b"aad").is_err());
+        let mut swapped = sealed.clone();
+        let (first, second) = (STREAM_HEADER_LEN, STREAM_HEADER_LEN + sealed_chunk);
+        let chunk: Vec<u8> = swapped[first..second].to_vec();
+        swapped.copy_within(second..second + sealed_chunk, first);
+        swapped[second..second + sealed_chunk].copy_from_slice(&chunk);
+        assert!(stream::unseal(&key, &swapped, b"aad").is_err());
+
+        let len = sealed.len() as u64;
+        let chunk = stream::open_chunk(&mut Cursor::new(&sealed), 0, len, &key, b"aad", 2).unwrap();
+        assert_eq!(chunk, &data[2 * CHUNK_LEN..3 * CHUNK_LEN]);
+        let tail = stream::open_chunk(&mut Cursor::new(&sealed), 0, len, &key, b"aad", 3).unwrap();
+        assert_eq!(tail, &data[3 * CHUNK_LEN..]);
+        assert!(stream::open_chunk(&mut Cursor::new(&sealed), 0, len, &key, b"aad", 4).is_err());
+        let err = stream::open_chunk(&mut Cursor::new(&sealed), 0, 3, &key, b"aad", 0).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+
+        // straight through an archive, in and out without holding the entry
+        let scratch = Scratch::new("stream_aead");
+        let test_path = &scratch.path("stream_aead.sk1");
+        let mut archive = create(test_path);
+        archive.write_file_from("big.bin", &mut &data[..], 5).unwrap();
+        archive.write_file_from("stored.bin", &mut &data[..1000], 0).unwrap();
+        let mut out = Vec::new();
+        assert_eq!(archive.extract_to("big.bin", &mut out).unwrap(), data.len() as u64);
+        assert_eq!(out, data);
+        assert_eq!(archive.extract_file("stored.bin").unwrap(), &data[..1000]);
+
+        // a reader failing halfway has already written over the index, it gets put back
+        struct Broken;
+        impl std::io::Read for Broken {
+            fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+                Err(std::io::Error::other("disk went away"))
+            }
+        }
+        let err = archive.write_file_from("half.bin", &mut std::io::Read::chain(&data[..], Broken), 5).unwrap_err();
+        assert_eq!(err.to_string(), "disk went away");
+        drop(archive);
+        let mut reopened = open(test_path);
+        assert!(reopened.extract_file("half.bin").is_err());
+        assert_eq!(reopened.extract_file("big.bin").unwrap(), data);
+        assert_eq!(reopened.extract_file("stored.bin").unwrap(), &data[..1000]);
+    }
+
+    #[test]
+    fn test_key_slots() {
+        let scratch = Scratch::new("key_slots");
+        let test_path = &scratch.path("key_slots.sk1");
+        let options = ArchiveOptions { kdf: Some(CHEAP), ..Default::default() };
+
+        let mut archive = ShokoArchive::create_with(test_path, options).unwrap();
+        archive.unlock_with(&RawKey::new("owner")).unwrap();
+        archive.write_file_direct("team.txt", b"shared with the team", 5).unwrap();
+        let blobs = fs::read(test_path).unwrap()[..archive.data_end() as usize].to_vec();
+        assert_eq!(archive.add_key_slot("alice", &RawKey::new("alice secret")).unwrap(), 1);
+        assert_eq!(archive.add_key_slot("bob", &RawKey::new("bob secret")).unwrap(), 2);
+        let long = "x".repeat(u16::MAX as usize + 1);
+        let err = archive.add_key_slot(&long, &RawKey::new("carol secret")).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        // only the header moved
+        assert_eq!(fs::read(test_path).unwrap()[..blobs.len()], blobs[..]);
+        drop(archive);
+
+        for secret in ["owner", "alice secret", "bob secret"] {
+            let mut archive = ShokoArchive::open_with(test_path, &RawKey::new(secret)).unwrap();
+            assert_eq!(archive.extract_file("team.txt").unwrap(), b"shared with the team");
+        }
+        let mut archive = ShokoArchive::open(test_path).unwrap();
+        let labels: Vec<&str> = archive.key_slots().iter().map(|s| s.label.as_str()).collect();
+        assert_eq!(labels, ["creator", "alice", "bob"]);
+
+        archive.unlock_with(&RawKey::new("alice secret")).unwrap();
+        archive.remove_key_slot(2).unwrap();
+        assert!(archive.remove_key_slot(2).is_err());
+        // the creator is just slot 0, it can be revoked too
+        archive.remove_key_slot(0).unwrap();
+        archive.defrag().unwrap();
+        drop(archive);
+
+        for secret in ["owner", "bob secret"] {
+            let err = ShokoArchive::open_with(test_path, &RawKey::new(secret)).err().unwrap();
+            assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+        }
+        let mut alice = ShokoArchive::open_with(test_path, &RawKey::new("alice secret")).unwrap();
+        assert_eq!(alice.key_slots().len(), 1);
+        assert_eq!(alice.extract_file("team.txt").unwrap(), b"shared with the team");
+        // nothing else would open it
+        assert_eq!(alice.remove_key_slot(1).unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_rekey() {
+        let scratch = Scratch::new("rekey");
+        let test_path = &scratch.path("rekey.sk1");
+        let options = ArchiveOptions { kdf: Some(CHEAP), ..Default::default() };
+        let (old, new) = (RawKey::new("leaked"), RawKey::new("rotated"));
+
+        let mut archive = ShokoArchive::create_with(test_path, options).unwrap();
+        archive.unlock_with(&old).unwrap();
+        archive.write_file_direct("a.txt", b"first", 5).unwrap();
+        archive.write_file_direct("b.txt", b"second", 0).unwrap();
+        archive.write_file_direct("c.txt", b"third", 5).unwrap();
+        archive.add_key_slot("alice", &RawKey::new("alice")).unwrap();
+        let last = archive.entries.iter().find(|e| e.path == "c.txt").unwrap().offset as usize;
+        drop(archive);
+
+        // a bad blob stops it halfway, the two before it are kept
+        let mut bytes = fs::read(test_path).unwrap();
+        bytes[last + 20] ^= 1;
+        fs::write(test_path, &bytes).unwrap();
+        let mut archive = ShokoArchive::open(test_path).unwrap();
+        assert!(archive.rekey(&old, &new).is_err());
+        assert!(fs::metadata(format!("{}.rekey-journal", test_path)).is_ok());
+        let err = archive.rekey(&old, &RawKey::new("someone else")).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+
+        bytes[last + 20] ^= 1;
+        fs::write(test_path, &bytes).unwrap();
+        let mut archive = ShokoArchive::open(test_path).unwrap();
+        let summary = archive.rekey(&old, &new).unwrap();
+        assert_eq!((summary.blobs, summary.resumed, summary.slots_dropped), (3, 2, 2));
+        assert_eq!(archive.extract_file("c.txt").unwrap(), b"third");
+        assert!(fs::metadata(format!("{}.rekey", test_path)).is_err());
+        assert!(fs::metadata(format!("{}.rekey-journal", test_path)).is_err());
+        drop(archive);
+
+        assert!(ShokoArchive::open_with(test_path, &old).is_err());
+        let mut archive = ShokoArchive::open_with(test_path, &new).unwrap();
+        let slots: Vec<u32> = archive.key_slots().iter().map(|s| s.id).collect();
+        assert_eq!(slots, [0]);
+        assert_eq!(archive.verify().unwrap(), 3);
+        assert_eq!(archive.extract_file("b.txt").unwrap(), b"second");
+    }
+
+    #[test]
+    fn test_cipher_agility() {
+        use crate::encrypt::Encryption;
+        let scratch = Scratch::new("cipher_agility");
+        let test_path = &scratch.path("cipher_agility.sk1");
+        let content = b"same bytes, different cipher";
+
+        for encryption in [Encryption::Aes256Gcm, Encryption::ChaCha20Poly1305, Encryption::XChaCha20Poly1305] {
+            assert_eq!(Encryption::from_name(encryption.name()), Some(encryption));
+            let options = ArchiveOptions { encryption, kdf: Some(CHEAP), encrypt_index: true, ..Default::default() };
+            let mut archive = ShokoArchive::create_with(test_path, options).unwrap();
+            archive.unlock_with(&RawKey::new("agile")).unwrap();
+            archive.write_file_direct("note.txt", content, 0).unwrap();
+            // stored, so the blob is the payload plus the nonce and tag
+            let size = archive.entries[0].size;
+            assert_eq!(size, content.len() as u64 + encryption.overhead(size));
+            drop(archive);
+
+            let mut reopened = ShokoArchive::open(test_path).unwrap();
+            assert_eq!(reopened.encryption(), encryption);
+            reopened.unlock_with(&RawKey::new("agile")).unwrap();
+            assert_eq!(reopened.extract_file("note.txt").unwrap(), content);
+            assert_eq!(reopened.verify().unwrap(), 1);
+        }
+        assert_eq!(Encryption::XChaCha20Poly1305.overhead(0), 24 + 16);
+        assert_eq!(Encryption::from_name("rot13"), None);
+    }
+
+    #[test]
+    fn test_archive_signing() {
+        use crate::sign;
+        use std::io::ErrorKind;
+        let scratch = Scratch::new("signing");
+        let test_path = &scratch.path("signing.sk1");
+        let options = ArchiveOptions { kdf: Some(CHEAP), ..Default::default() };
+
+        let key = sign::generate_signing_key();
+        sign::write_signing_key(scratch.path("signing.key"), &key).unwrap();
+        sign::write_verifying_key(scratch.path("signing.pub"), &key.verifying_key()).unwrap();
+        assert_eq!(sign::read_signing_key(scratch.path("signing.key")).unwrap().to_bytes(), key.to_bytes());
+        // a second key doesn't replace the first
+        let err = sign::write_signing_key(scratch.path("signing.key"), &sign::generate_signing_key()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        assert_eq!(sign::read_signing_key(scratch.path("signing.key")).unwrap().to_bytes(), key.to_bytes());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(scratch.path("signing.key")).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+        let trusted = sign::read_verifying_key(&scratch.path("signing.pub")).unwrap();
+        assert_eq!(sign::read_verifying_key(&sign::to_hex(trusted.as_bytes())).unwrap(), trusted);
+
+        let mut archive = ShokoArchive::create_with(test_path, options).unwrap();
+        archive.unlock_with(&RawKey::new("shared")).unwrap();
+        archive.write_file_direct("release/app.bin", b"the real thing", 5).unwrap();
+        archive.write_file_direct("release/notes.txt", b"v1.0", 0).unwrap();
+        assert_eq!(archive.verify_signature(&trusted).unwrap_err().kind(), ErrorKind::NotFound);
+        archive.sign(&key).unwrap();
+        let blob = archive.entries[0].offset as usize;
+        drop(archive);
+
+        // no archive key needed to check it
+        let mut received = ShokoArchive::open(test_path).unwrap();
+        assert_eq!(received.signature().unwrap().public_key, trusted.to_bytes());
+        received.verify_signature(&trusted).unwrap();
+        let stranger = sign::generate_signing_key().verifying_key();
+        assert_eq!(received.verify_signature(&stranger).unwrap_err().kind(), ErrorKind::PermissionDenied);
+
+        let mut bytes = fs::read(test_path).unwrap();
+        bytes[blob + 30] ^= 1;
+        fs::write(test_path, &bytes).unwrap();
+        let mut tampered = ShokoArchive::open(test_path).unwrap();
+        assert_eq!(tampered.verify_signature(&trusted).unwrap_err().kind(), ErrorKind::InvalidData);
+        bytes[blob + 30] ^= 1;
+        fs::write(test_path, &bytes).unwrap();
+
+        // someone with the archive key adds a file, the signature no longer holds until re-signed
+        let mut archive = ShokoArchive::open_with(test_path, &RawKey::new("shared")).unwrap();
+        archive.verify_signature(&trusted).unwrap();
+        archive.write_file_direct("release/extra.bin", b"not from us", 5).unwrap();
+        assert_eq!(archive.verify_signature(&trusted).unwrap_err().kind(), ErrorKind::InvalidData);
+        archive.sign(&key).unwrap();
+        archive.verify_signature(&trusted).unwrap();
+    }
+
+    #[test]
+    fn test_recipients() {
+        use crate::recipient::{self, PublicKey};
+        use std::io::ErrorKind;
+        let scratch = Scratch::new("recipients");
+        let test_path = &scratch.path("recipients.sk1");
+        let (alice, bob, carol) = (recipient::generate_identity(), recipient::generate_identity(), recipient::generate_identity());
+        let options = ArchiveOptions { encrypt_index: true, ..Default::default() };
+
+        let mut archive = ShokoArchive::create_for(test_path, options, &[PublicKey::from(&alice), PublicKey::from(&bob)]).unwrap();
+        archive.write_file_direct("for/partners.txt", b"no shared secret needed", 5).unwrap();
+        drop(archive);
+        // stanzas don't name their recipient
+        let bytes = fs::read(test_path).unwrap();
+        assert!(!bytes.windows(32).any(|w| w == PublicKey::from(&alice).as_bytes()));
+
+        let mut archive = ShokoArchive::open(test_path).unwrap();
+        assert!(archive.is_locked());
+        assert_eq!(archive.recipient_count(), 2);
+        assert_eq!(archive.unlock_with_identity(&carol).unwrap_err().kind(), ErrorKind::PermissionDenied);
+        assert!(archive.unlock_with(&RawKey::new("guess")).is_err());
+        archive.unlock_with_identity(&alice).unwrap();
+        assert_eq!(archive.extract_file("for/partners.txt").unwrap(), b"no shared secret needed");
+
+        recipient::write_identity(scratch.path("bob.identity"), &bob).unwrap();
+        recipient::write_recipient(scratch.path("carol.recipient"), &PublicKey::from(&carol)).unwrap();
+        let bob = recipient::read_identity(scratch.path("bob.identity")).unwrap();
+        let mut archive = ShokoArchive::open_with_identity(test_path, &bob).unwrap();
+        archive.add_recipient(&recipient::read_recipient(&scratch.path("carol.recipient")).unwrap()).unwrap();
+        archive.defrag().unwrap();
+        drop(archive);
+
+        let mut archive = ShokoArchive::open_with_identity(test_path, &carol).unwrap();
+        assert_eq!(archive.recipient_count(), 3);
+        assert_eq!(archive.extract_file("for/partners.txt").unwrap(), b"no shared secret needed");
+    }
+
+    #[test]
+    fn test_secure_memory() {
+        use crate::keys::KeyProvider;
+        use crate::shadow::{SecretKey, SecureArena, SecureBuf};
+        let scratch = Scratch::new("secure_memory");
+        let test_path = &scratch.path("secure_memory.sk1");
+
+        let arena = SecureArena::with_capacity(64).unwrap();
+        let first = arena.alloc(48).unwrap();
+        unsafe { first.as_ptr().write_bytes(0xaa, 48) };
+        assert!(arena.alloc(32).is_none());
+        arena.reset();
+        // same bytes handed out again, wiped
+        let again = arena.alloc(48).unwrap();
+        assert_eq!(first, again);
+        assert!(unsafe { std::slice::from_raw_parts(again.as_ptr(), 48) }.iter().all(|&b| b == 0));
+
+        let buf = SecureBuf::from_slice(b"hunter2").unwrap();
+        assert_eq!(&buf[..], b"hunter2");
+        assert!(!format!("{:?}", buf).contains("hunter2"));
+
+        let key = SecretKey::from_slice(&[7u8; 32]).unwrap();
+        let shared = key.clone();
+        assert_eq!(*shared, [7u8; 32]);
+        assert!(SecretKey::from_slice(b"short").is_err());
+        assert_eq!(&RawKey::new(b"passphrase".to_vec()).secret().unwrap()[..], b"passphrase");
+
+        let mut archive = create(test_path);
+        archive.write_file_direct("secrets/token", b"not for core dumps", 5).unwrap();
+        let secret = archive.extract_secure("secrets/token").unwrap();
+        assert_eq!(&secret[..], b"not for core dumps");
+        assert!(archive.extract_secure("secrets/missing").is_err());
+    }
+
+    #[test]
+    fn test_key_id() {
+        use std::io::ErrorKind;
+        let scratch = Scratch::new("key_id");
+        let test_path = &scratch.path("key_id.sk1");
+        let options = ArchiveOptions { kdf: Some(CHEAP), ..Default::default() };
+
+        let mut archive = ShokoArchive::create_with(test_path, options).unwrap();
+        assert!(archive.key_id().is_none());
+        archive.unlock(b"right").unwrap();
+        archive.write_file_direct("a.txt", b"checked up front", 5).unwrap();
+        let id = archive.key_id().unwrap();
+        drop(archive);
+
+        // readable without the key, and a wrong one is turned away before any blob is touched
+        let mut reopened = ShokoArchive::open(test_path).unwrap();
+        assert_eq!(reopened.key_id(), Some(id));
+        let err = reopened.unlock(b"wrong").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        assert!(err.to_string().contains("Wrong key"));
+        reopened.unlock(b"right").unwrap();
+        assert_eq!(reopened.extract_file("a.txt").unwrap(), b"checked up front");
+        // a slot unwraps the same key, so it has the same id
+        reopened.add_key_slot("ci", &RawKey::new("ci secret")).unwrap();
+        assert_eq!(ShokoArchive::open_with(test_path, &RawKey::new("ci secret")).unwrap().key_id(), Some(id));
+
+        // tampering is still told apart from a wrong key
+        let blob = reopened.entries[0].blob();
+        drop(reopened);
+        let mut bytes = fs::read(test_path).unwrap();
+        bytes[blob.offset as usize + 20] ^= 1;
+        fs::write(test_path, &bytes).unwrap();
+        let mut tampered = ShokoArchive::open_with(test_path, &RawKey::new("right")).unwrap();
+        assert_eq!(tampered.extract_file("a.txt").unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_header_mac() {
+        use crate::encrypt::Encryption;
+        use std::io::ErrorKind;
+        let scratch = Scratch::new("header_mac");
+        let test_path = &scratch.path("header_mac.sk1");
+        let mut archive = create(test_path);
+        archive.write_file_direct("a.txt", b"keep me", 5).unwrap();
+        archive.write_file_direct("b.txt", b"drop me", 5).unwrap();
+        assert!(archive.header_mac.is_some());
+        drop(archive);
+        let original = fs::read(test_path).unwrap();
+
+        // a rewrite without the key that leaves the key records alone keeps the MAC valid
+        let mut keyless = ShokoArchive::open(test_path).unwrap();
+        keyless.delete_file("b.txt").unwrap();
+        assert_eq!(open(test_path).extract_file("a.txt").unwrap(), b"keep me");
+
+        // what someone without the key could do to the clear header
+        let tamper = |change: &dyn Fn(&mut ShokoArchive)| {
+            fs::write(test_path, &original).unwrap();
+            let mut archive = ShokoArchive::open(test_path).unwrap();
+            change(&mut archive);
+            archive.rewrite_index().unwrap();
+            ShokoArchive::open_with(test_path, &RawKey::new(SECRET)).map(|_| ()).unwrap_err().kind()
+        };
+        assert_eq!(tamper(&|a| a.encryption = Encryption::None), ErrorKind::InvalidInput);
+        assert_eq!(tamper(&|a| a.generation -= 1), ErrorKind::InvalidData);
+        assert_eq!(tamper(&|a| a.header_mac = None), ErrorKind::InvalidData);
+        assert_eq!(tamper(&|a| a.archive_id = Some([0; 16])), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_key_files() {
+        use crate::keys::{self, KeyEncoding, KeyFile, KeyProvider};
+        let scratch = Scratch::new("key_files");
+        let test_path = &scratch.path("key_files.sk1");
+        let key_path = &scratch.path("key_files.key");
+
+        for encoding in [KeyEncoding::Binary, KeyEncoding::Hex, KeyEncoding::Base64] {
+            let key = [0x0a; 32];
+            assert_eq!(&encoding.decode(&encoding.encode(&key)).unwrap()[..], &key);
+        }
+        assert_eq!(&KeyEncoding::Text.decode(b"pass\r\n").unwrap()[..], b"pass");
+        assert!(KeyEncoding::Hex.decode(b"not hex").is_err());
+
+        keys::generate_key_file(key_path, KeyEncoding::Hex).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(key_path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+        // an existing key is never overwritten
+        let written = fs::read(key_path).unwrap();
+        assert!(keys::generate_key_file(key_path, KeyEncoding::Hex).is_err());
+        assert_eq!(fs::read(key_path).unwrap(), written);
+
+        // decoded it's a full 32 byte key, good enough for an archive without a KDF. hex is what
+        // both sides default to
+        let provider = KeyFile::new(key_path);
+        assert_eq!(provider.secret().unwrap().len(), 32);
+        let mut archive = ShokoArchive::create_with(test_path, ArchiveOptions { kdf: None, ..Default::default() }).unwrap();
+        archive.unlock_with(&provider).unwrap();
+        archive.write_file_direct("provisioned.txt", b"no prompt involved", 5).unwrap();
+        drop(archive);
+        let mut reopened = ShokoArchive::open_with(test_path, &provider).unwrap();
+        assert_eq!(reopened.extract_file("provisioned.txt").unwrap(), b"no prompt involved");
+        // read as text it's 65 bytes, not a raw key
+        assert!(ShokoArchive::open_with(test_path, &KeyFile::new(key_path).with_encoding(KeyEncoding::Text)).is_err());
+    }
+
+    #[test]
+    fn test_data_keys() {
+        use crate::encrypt::BlobBinding;
+        use crate::pipeline::PackItem;
+        use crate::solid::SolidOptions;
+        let scratch = Scratch::new("data_keys");
+        let test_path = &scratch.path("data_keys.sk1");
+        let options = ArchiveOptions { kdf: Some(CHEAP), ..Default::default() };
+
+        let mut archive = ShokoArchive::create_with(test_path, options).unwrap();
+        archive.unlock(b"compliance").unwrap();
+        archive.write_file_direct("users/42.json", b"{\"name\": \"to be forgotten\"}", 5).unwrap();
+        archive.write_file_direct("users/43.json", b"{\"name\": \"stays\"}", 5).unwrap();
+        let entry = archive.entries.iter().find(|e| e.path == "users/42.json").unwrap().clone();
+        let wrapped = entry.data_key.clone().unwrap();
+        assert_ne!(Some(&wrapped), archive.entries.iter().find(|e| e.path == "users/43.json").unwrap().data_key.as_ref());
+
+        // the archive key alone doesn't open an entry's blob
+        let binding = BlobBinding::Entry { path: &entry.path, generation: entry.generation };
+        assert!(archive.read_blob_ref(entry.blob(), binding).is_err());
+
+        archive.delete_file("users/42.json").unwrap();
+        let raw = fs::read(test_path).unwrap();
+        // the ciphertext is still there until a defrag, its key isn't
+        let blob = entry.offset as usize..(entry.offset + entry.size) as usize;
+        assert!(raw.len() > blob.end);
+        assert!(!raw.windows(wrapped.len()).any(|w| w == wrapped));
+        drop(archive);
+        let mut reopened = ShokoArchive::open_with(test_path, &RawKey::new("compliance")).unwrap();
+        assert_eq!(reopened.extract_file("users/43.json").unwrap(), b"{\"name\": \"stays\"}");
+
+        // a delta version stays under its chain's key
+        reopened.set_delta_mode(4);
+        let long = vec![b'x'; 4096];
+        reopened.write_file_direct("log.txt", &long, 5).unwrap();
+        let key = reopened.entries.iter().find(|e| e.path == "log.txt").unwrap().data_key.clone();
+        let mut edited = long.clone();
+        edited[100] = b'y';
+        reopened.write_file_direct("log.txt", &edited, 5).unwrap();
+        let entry = reopened.entries.iter().find(|e| e.path == "log.txt").unwrap();
+        assert!(entry.is_delta());
+        assert_eq!(entry.data_key, key);
+        assert_eq!(reopened.extract_file("log.txt").unwrap(), edited);
+
+        // solid members share their block's key, so deleting one moves the rest to a new block
+        let items = vec![PackItem::bytes("s/a", b"aaaa".to_vec()), PackItem::bytes("s/b", b"bbbb".to_vec())];
+        reopened.write_files_solid(items, 5, 1, SolidOptions::default()).unwrap();
+        let keys: Vec<_> = reopened.entries.iter().filter(|e| e.is_solid()).map(|e| e.data_key.clone().unwrap()).collect();
+        assert_eq!(keys[0], keys[1]);
+        let block = reopened.entries.iter().find(|e| e.path == "s/b").unwrap().blob();
+        reopened.delete_file("s/a").unwrap();
+        let moved = reopened.entries.iter().find(|e| e.path == "s/b").unwrap().clone();
+        assert!(moved.is_solid());
+        assert_ne!(moved.blob(), block);
+        assert_ne!(moved.data_key.as_ref(), Some(&keys[0]));
+        assert!(!fs::read(test_path).unwrap().windows(keys[0].len()).any(|w| w == keys[0]));
+        assert_eq!(reopened.extract_file("s/b").unwrap(), b"bbbb");
+
+        // every chunk has its own key, and the ones only a deleted entry had go with it
+        let mut state = 7u32;
+        let mut noise = |len: usize| -> Vec<u8> {
+            (0..len).map(|_| { state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345); (state >> 16) as u8 }).collect()
+        };
+        let one = noise(40_000);
+        let mut two = one[..20_000].to_vec();
+        two.extend(noise(20_000));
+        reopened.write_file_chunked("c/one", &one, 5).unwrap();
+        reopened.write_file_chunked("c/two", &two, 5).unwrap();
+        let chunk_list = |archive: &ShokoArchive, path: &str| archive.entries.iter().find(|e| e.path == path).unwrap().chunks.clone().unwrap();
+        let (one_chunks, two_chunks) = (chunk_list(&reopened, "c/one"), chunk_list(&reopened, "c/two"));
+        let only_one: Vec<_> = one_chunks.iter().filter(|h| !two_chunks.contains(h)).copied().collect();
+        assert!(!only_one.is_empty() && only_one.len() < one_chunks.len());
+        let chunk_keys: Vec<Vec<u8>> = reopened.chunks.values().map(|c| c.data_key.clone().unwrap()).collect();
+        assert!(chunk_keys.iter().enumerate().all(|(i, k)| !chunk_keys[..i].contains(k)));
+        let first = reopened.chunks[&one_chunks[0]].blob;
+        assert!(reopened.read_blob_ref(first, BlobBinding::Chunk(&one_chunks[0])).is_err());
+        let dropped: Vec<Vec<u8>> = only_one.iter().map(|h| reopened.chunks[h].data_key.clone().unwrap()).collect();
+
+        reopened.delete_file("c/one").unwrap();
+        assert!(only_one.iter().all(|h| !reopened.chunks.contains_key(h)));
+        let raw = fs::read(test_path).unwrap();
+        assert!(dropped.iter().all(|key| !raw.windows(key.len()).any(|w| w == &key[..])));
+        drop(reopened);
+        let mut reopened = ShokoArchive::open_with(test_path, &RawKey::new("compliance")).unwrap();
+        assert_eq!(reopened.extract_file("c/two").unwrap(), two);
+        // a rekey gives chunks new keys like it does entries
+        reopened.rekey(&RawKey::new("compliance"), &RawKey::new("rotated")).unwrap();
+        assert!(reopened.chunks.values().all(|c| !chunk_keys.contains(c.data_key.as_ref().unwrap())));
+        assert_eq!(reopened.extract_file("c/two").unwrap(), two);
+    }
+
+    #[test]
+    fn test_padding() {
+        use crate::padding::Padding;
+        let scratch = Scratch::new("padding");
+        let test_path = &scratch.path("padding.sk1");
+
+        assert_eq!(Padding::PowerOfTwo.padded_len(1000), 1024);
+        assert_eq!(Padding::Block(4096).padded_len(1), 4096);
+        assert_eq!(Padding::Block(4096).padded_len(4097), 8192);
+        for len in [9u64, 1000, 65_537, 1 << 30] {
+            let padded = Padding::Padme.padded_len(len);
+            assert!(padded >= len && padded - len <= len / 8);
+        }
+        assert_eq!(Padding::Padme.padded_len(9), 10);
+        let framed = Padding::Padme.pad(b"payload".to_vec());
+        assert_eq!(Padding::Padme.unpad(&framed).unwrap(), b"payload");
+        assert!(Padding::Padme.unpad(&[0xff; 16]).is_err());
+        for name in ["none", "pow2", "padme", "block:512"] {
+            let policy = Padding::from_name(name).unwrap();
+            assert_eq!(policy.name(), name);
+            assert_eq!(Padding::from_bytes(&policy.to_bytes()).unwrap(), policy);
+        }
+        assert!(Padding::from_name("block:0").is_none());
+
+        // files of different sizes end up the same size on disk
+        let options = ArchiveOptions { kdf: Some(CHEAP), padding: Padding::Block(1024), ..Default::default() };
+        let mut archive = ShokoArchive::create_with(test_path, options).unwrap();
+        archive.unlock(b"sizes").unwrap();
+        archive.write_file_direct("short.txt", b"yes", 0).unwrap();
+        archive.write_file_direct("longer.txt", &[7u8; 700], 0).unwrap();
+        archive.write_file_from("streamed.txt", &mut &b"through the reader"[..], 0).unwrap();
+        let sizes: Vec<u64> = archive.entries.iter().map(|e| e.size).collect();
+        assert!(sizes.iter().all(|s| *s == sizes[0]));
+        drop(archive);
+        // and the index that would give them away is sealed, without encrypt_index
+        let raw = fs::read(test_path).unwrap();
+        assert!(!raw.windows(8).any(|w| w == 700u64.to_le_bytes()));
+        assert!(!raw.windows(10).any(|w| w == b"longer.txt"));
+        assert!(ShokoArchive::open(test_path).unwrap().is_locked());
+
+        let mut reopened = ShokoArchive::open(test_path).unwrap();
+        assert_eq!(reopened.options().padding, Padding::Block(1024));
+        reopened.unlock(b"sizes").unwrap();
+        assert_eq!(reopened.extract_file("short.txt").unwrap(), b"yes");
+        let mut out = Vec::new();
+        reopened.extract_to("longer.txt", &mut out).unwrap();
+        assert_eq!(out, vec![7u8; 700]);
+        assert_eq!(reopened.extract_file("streamed.txt").unwrap(), b"through the reader");
+        // defrag keeps the policy
+        reopened.delete_file("short.txt").unwrap();
+        reopened.defrag().unwrap();
+        assert_eq!(reopened.options().padding, Padding::Block(1024));
+        assert_eq!(reopened.entries[0].size, sizes[0]);
+        assert_eq!(reopened.verify().unwrap(), 2);
+
+        // nothing to hide in an unencrypted archive
+        let plain = ArchiveOptions { encryption: crate::encrypt::Encryption::None, padding: Padding::PowerOfTwo, ..Default::default() };
+        assert_eq!(ShokoArchive::create_with(test_path, plain).unwrap().options().padding, Padding::None);
     }
 }
@@ -1,7 +1,11 @@
 use std::io::{self, Write, Seek};
 use std::fs::File;
-use crate::compress::compress;
-use crate::encrypt;
+use crate::archive::ShokoEntry;
+use crate::codec;
+use crate::dict::Dictionary;
+use crate::encrypt::{ArchiveId, BlobBinding, Cipher};
+use crate::format::{self, ExtWriter};
+use crate::padding::Padding;
 
 pub struct ShokoWriter<'a> {
     handle: &'a mut File,
@@ -12,32 +16,32 @@ impl<'a> ShokoWriter<'a> {
         Self { handle }
     }
 
-    pub fn write_blob(&mut self, data: &[u8], clevel: u8) -> io::Result<u64> {
-        let processed_data = if clevel > 0 {
-            compress(data, clevel)
-        } else {
-            data.to_vec()
-        };
-
-        let encrypted_data = encrypt::encrypt_data(&processed_data)?;
+    pub fn write_blob(&mut self, data: &[u8], clevel: u8, cipher: &Cipher) -> io::Result<u64> {
+        let sealed = prepare_blob(data, clevel, cipher)?;
+        self.write_raw(&sealed)
+    }
 
+    /// writes an already compressed + encrypted blob (see `prepare_blob`) at the current position
+    pub fn write_raw(&mut self, sealed: &[u8]) -> io::Result<u64> {
         let start_pos = self.handle.stream_position()?;
-        self.handle.write_all(&encrypted_data)?;
+        self.handle.write_all(sealed)?;
         let end_pos = self.handle.stream_position()?;
 
         Ok(end_pos - start_pos)
     }
 
-    pub fn write_index_entry(&mut self, path: &str, size: u64, offset: u64, clevel: u8) -> io::Result<()> {
-        let path_bytes = path.as_bytes();
-        self.handle.write_all(&(path_bytes.len() as u32).to_le_bytes())?;
-        self.handle.write_all(path_bytes)?;
-        self.handle.write_all(&size.to_le_bytes())?;
-        self.handle.write_all(&offset.to_le_bytes())?;
-        self.handle.write_all(&[clevel])?;
+    /// the archive header block that leads a v3+ index
+    pub fn write_header_block(&mut self, block: &[u8]) -> io::Result<()> {
+        self.handle.write_all(&(block.len() as u32).to_le_bytes())?;
+        self.handle.write_all(block)?;
         Ok(())
     }
 
+    /// writes one v2+ index entry: the fixed v1 fields followed by the extension block
+    pub fn write_index_entry(&mut self, entry: &ShokoEntry) -> io::Result<()> {
+        self.handle.write_all(&index_entry_bytes(entry))
+    }
+
     pub fn finalize(&mut self, index_start: u64, entry_count: u32) -> io::Result<()> {
         self.handle.write_all(&index_start.to_le_bytes())?;
         self.handle.write_all(&count_to_bytes(entry_count))?;
@@ -46,6 +50,97 @@ impl<'a> ShokoWriter<'a> {
     }
 }
 
+/// everything needed to turn plaintext into a sealed blob, cheap to clone into worker threads
+#[derive(Debug, Clone)]
+pub struct Sealer {
+    pub(crate) dict: Option<Dictionary>,
+    pub(crate) cipher: Cipher,
+    /// None for archives from before blobs were bound to their place, they seal without AAD
+    pub(crate) archive_id: Option<ArchiveId>,
+    pub(crate) padding: Padding,
+}
+
+impl Sealer {
+    /// seals with `cipher` alone: no dictionary, no padding and no archive id in the associated data
+    pub fn new(cipher: Cipher) -> Self {
+        Self { dict: None, cipher, archive_id: None, padding: Padding::None }
+    }
+
+    /// compresses and encrypts a blob without touching any file, so it can run off the writer thread
+    pub fn seal(&self, data: &[u8], clevel: u8, binding: BlobBinding) -> io::Result<Vec<u8>> {
+        let processed_data = codec::encode(data, clevel, self.dict.as_ref());
+        self.cipher.seal(&self.padding.pad(processed_data), &binding.aad(self.archive_id.as_ref()))
+    }
+}
+
+/// seals a blob with no dictionary and no associated data, the way archives without an
+/// encryption record are. `Cipher::from_env` gives the key those use
+pub fn prepare_blob(data: &[u8], clevel: u8, cipher: &Cipher) -> io::Result<Vec<u8>> {
+    Sealer::new(cipher.clone()).seal(data, clevel, BlobBinding::Unbound)
+}
+
+/// one serialized index entry, what `write_index_entry` writes and what goes into a sealed index
+pub(crate) fn index_entry_bytes(entry: &ShokoEntry) -> Vec<u8> {
+    let mut out = Vec::new();
+    let path_bytes = entry.path.as_bytes();
+    out.extend_from_slice(&(path_bytes.len() as u32).to_le_bytes());
+    out.extend_from_slice(path_bytes);
+    out.extend_from_slice(&entry.size.to_le_bytes());
+    out.extend_from_slice(&entry.offset.to_le_bytes());
+    out.push(entry.compression_level);
+
+    let ext = entry_extensions(entry);
+    out.extend_from_slice(&(ext.len() as u32).to_le_bytes());
+    out.extend_from_slice(&ext);
+    out
+}
+
+fn entry_extensions(entry: &ShokoEntry) -> Vec<u8> {
+    let mut ext = ExtWriter::default();
+
+    if !entry.delta_chain.is_empty() {
+        let mut payload = (entry.delta_chain.len() as u32).to_le_bytes().to_vec();
+        for blob in &entry.delta_chain {
+            payload.extend_from_slice(&blob.offset.to_le_bytes());
+            payload.extend_from_slice(&blob.size.to_le_bytes());
+            payload.push(blob.compression_level);
+        }
+        ext.record(format::EXT_DELTA_CHAIN, &payload);
+    }
+
+    if let Some(slice) = entry.solid {
+        let mut payload = slice.offset.to_le_bytes().to_vec();
+        payload.extend_from_slice(&slice.len.to_le_bytes());
+        ext.record(format::EXT_SOLID, &payload);
+    }
+
+    if let Some(chunks) = &entry.chunks {
+        let mut payload = (chunks.len() as u32).to_le_bytes().to_vec();
+        for hash in chunks {
+            payload.extend_from_slice(hash);
+        }
+        ext.record(format::EXT_CHUNKS, &payload);
+    }
+
+    if let Some(original_size) = entry.original_size {
+        ext.record(format::EXT_ORIGINAL_SIZE, &original_size.to_le_bytes());
+    }
+
+    if let Some(data_key) = &entry.data_key {
+        ext.record(format::EXT_DATA_KEY, data_key);
+    }
+
+    if entry.generation != 0 {
+        let mut payload = entry.generation.to_le_bytes().to_vec();
+        for generation in &entry.delta_generations {
+            payload.extend_from_slice(&generation.to_le_bytes());
+        }
+        ext.record(format::EXT_GENERATION, &payload);
+    }
+
+    ext.into_bytes()
+}
+
 fn count_to_bytes(count: u32) -> [u8; 4] {
     count.to_le_bytes()
 }
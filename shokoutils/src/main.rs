@@ -1,16 +1,38 @@
 use std::env;
 use std::fs;
+use std::io::{self, Write};
 use std::path::Path;
 use std::process::Command;
-use shoko::archive::ShokoArchive;
-use env_logger;
+use shoko::archive::{ArchiveOptions, ShokoArchive};
+use shoko::codec;
+use shoko::compress::RleEncoder;
+use shoko::decompress::RleDecoder;
+use shoko::dict;
+use shoko::encrypt::{self, Argon2Params, Encryption, KeyDerivation};
+use shoko::keys::{self, DefaultKey, KeyEncoding, KeyFile, KeyProvider, RawKey};
+use shoko::limits::Limits;
+use shoko::padding::Padding;
+use shoko::pipeline::{self, walk_parallel, PackItem};
+use shoko::recipient;
+use shoko::sign;
+use shoko::solid::SolidOptions;
+use shoko::stats::{EntryStats, Layout};
 use log::info;
+use inquire::{Password, PasswordDisplayMode};
 
 use petgraph::graph::NodeIndex;
 use petgraph::stable_graph::StableGraph;
 use petgraph::Direction;
 
-fn main() -> std::io::Result<()> {
+fn main() {
+    // a plain message instead of the Debug dump returning the error from main would give
+    if let Err(e) = run() {
+        eprintln!("sar: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run() -> std::io::Result<()> {
     env_logger::init();
     let args: Vec<String> = env::args().collect();
     if args.len() < 2 {
@@ -19,29 +41,119 @@ fn main() -> std::io::Result<()> {
     }
 
     let mut clevel = 1;
-    for arg in &args {
+    let mut jobs = pipeline::default_jobs();
+    let mut delta_chain = 0;
+    let mut use_lz = false;
+    let mut dict_source = None;
+    // archives being unpacked might come from anywhere, so they get bomb limits by default
+    let mut limits = Limits::untrusted();
+    for (i, arg) in args.iter().enumerate() {
         if arg.starts_with("--clevel=") {
             if let Ok(val) = arg.replace("--clevel=", "").parse::<u8>() {
                 clevel = val.clamp(1, 9);
             }
         }
+        if arg == "--codec=lz" {
+            use_lz = true;
+        }
+        if arg == "--dict" {
+            dict_source = Some(None);
+            use_lz = true;
+        } else if let Some(file) = arg.strip_prefix("--dict=") {
+            dict_source = Some(Some(file.to_string()));
+            use_lz = true;
+        }
+        if arg == "--delta" {
+            delta_chain = 8;
+        } else if let Some(val) = arg.strip_prefix("--delta=") {
+            delta_chain = val.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!(
+                "Bad delta chain '{}', expected a plain number (--delta alone means 8)", arg
+            )))?;
+        }
+        let jobs_val = if arg == "--jobs" || arg == "-j" {
+            args.get(i + 1).cloned()
+        } else {
+            arg.strip_prefix("--jobs=").map(str::to_string)
+        };
+        if let Some(val) = jobs_val.and_then(|v| v.parse::<usize>().ok()) {
+            jobs = val.max(1);
+        }
+        if arg == "--no-limits" {
+            limits = Limits::default();
+        } else if let Some(val) = arg.strip_prefix("--max-entry=") {
+            limits.max_entry_size = Some(parse_limit(arg, val)?);
+        } else if let Some(val) = arg.strip_prefix("--max-total=") {
+            limits.max_total_size = Some(parse_limit(arg, val)?);
+        } else if let Some(val) = arg.strip_prefix("--max-ratio=") {
+            limits.max_ratio = Some(parse_limit(arg, val)?);
+        }
     }
 
+    // the codec rides along in the clevel byte
+    let clevel = if use_lz { codec::lz(clevel) } else { clevel };
+
     match args[1].as_str() {
         "pack" => {
             if args.len() < 4 { return print_usage("pack <folder> -o <archive.sk1>"); }
             let folder = &args[2];
-            let output = &args[args.len() - 1];
-            let mut archive = ShokoArchive::create(output)?;
-            pack_recursive(&mut archive, folder, "", clevel)?;
-            info!("Packed {} into {} (clevel: {})", folder, output, clevel);
+            let output = args.iter()
+                .position(|a| a == "-o")
+                .and_then(|i| args.get(i + 1))
+                .unwrap_or(&args[args.len() - 1]);
+            // --append adds to an existing archive, with --prefix=P keeping each run in its own folder
+            let prefix = args.iter()
+                .find_map(|a| a.strip_prefix("--prefix="))
+                .map(|p| format!("{}/", p.trim_end_matches('/')))
+                .unwrap_or_default();
+            let mut archive = if args.iter().any(|a| a == "--append") && Path::new(output).exists() {
+                let mut archive = ShokoArchive::open(output)?;
+                unlock(&mut archive, false)?;
+                archive
+            } else {
+                let recipients = recipients(&args)?;
+                if recipients.is_empty() {
+                    let mut archive = ShokoArchive::create_with(output, archive_options(&args)?)?;
+                    unlock(&mut archive, true)?;
+                    archive
+                } else {
+                    ShokoArchive::create_for(output, archive_options(&args)?, &recipients)?
+                }
+            };
+            match &dict_source {
+                Some(Some(file)) => {
+                    archive.add_dictionary(&fs::read(file)?)?;
+                }
+                Some(None) => {
+                    let trained = dict::train_from_dir(Path::new(folder), dict::DEFAULT_DICT_SIZE, jobs)?;
+                    info!("Trained a {} byte dictionary", trained.len());
+                    archive.add_dictionary(&trained)?;
+                }
+                None => {}
+            }
+            let items: Vec<PackItem> = walk_parallel(Path::new(folder), jobs)?
+                .into_iter()
+                .map(|(path, source)| PackItem::file(format!("{}{}", prefix, path), source))
+                .collect();
+            let packed = if args.iter().any(|a| a == "--dedup") {
+                archive.write_files_chunked(items, clevel, jobs)?
+            } else if args.iter().any(|a| a == "--solid") {
+                archive.write_files_solid(items, clevel, jobs, SolidOptions::default())?
+            } else {
+                archive.write_files_parallel(items, clevel, jobs)?
+            };
+            for packed in packed {
+                info!("Packed: {}", packed);
+            }
+            info!("Packed {} into {} (clevel: {}, jobs: {})", folder, output, clevel, jobs);
         }
         "unpack" => {
             if args.len() < 3 { return print_usage("unpack <archive.sk1> [out_dir] [--glob=pattern]"); }
             let mut archive = ShokoArchive::open(&args[2])?;
+            unlock(&mut archive, false)?;
+            archive.set_limits(limits);
             let out_dir = args.get(3).map(|s| s.as_str()).unwrap_or(".");
             
-            let mut target_paths = Vec::new();
+            let target_paths;
             let mut filter = None;
             for arg in &args {
                 if arg.starts_with("--glob=") {
@@ -60,24 +172,54 @@ fn main() -> std::io::Result<()> {
 
             fs::create_dir_all(out_dir)?;
             for path_str in target_paths {
-                let content = archive.extract_file(&path_str)?;
                 let out_path = Path::new(out_dir).join(&path_str);
                 if let Some(parent) = out_path.parent() {
                     fs::create_dir_all(parent)?;
                 }
-                fs::write(out_path, content)?;
+                // streamed straight to disk where the entry allows it, big files never sit in memory
+                let mut out = io::BufWriter::new(fs::File::create(out_path)?);
+                archive.extract_to(&path_str, &mut out)?;
+                out.flush()?;
                 info!("Extracted: {}", path_str);
             }
             info!("Unpack complete.");
         }
         "read" => {
             if args.len() < 3 { return print_usage("read <archive.sk1>"); }
-            let archive = ShokoArchive::open(&args[2])?;
-            render_tree(&archive);
+            let mut archive = ShokoArchive::open(&args[2])?;
+            if archive.is_locked() {
+                unlock(&mut archive, false)?;
+            }
+            let stats = archive.entry_stats()?;
+            render_tree(&archive, &stats);
+        }
+        "ls" => {
+            if args.len() < 3 { return print_usage("ls <archive.sk1>"); }
+            let mut archive = ShokoArchive::open(&args[2])?;
+            if archive.is_locked() {
+                unlock(&mut archive, false)?;
+            }
+            let total = archive.archive_stats()?;
+            println!("encryption: {}", archive.encryption().name());
+            println!("{:>12} {:>12} {:>8}  {:<6} {:<6} path", "original", "stored", "ratio", "codec", "layout");
+            for e in archive.entry_stats()? {
+                println!(
+                    "{:>12} {:>12} {:>7.2}x  {:<6} {:<6} {}",
+                    e.original_size, e.stored_size, e.ratio(), e.codec.name(), e.layout.name(), e.path
+                );
+            }
+            println!(
+                "{:>12} {:>12} {:>7.2}x  {} entries, {} bytes encryption overhead, {} bytes index, {} bytes reclaimable by defrag",
+                total.original_size, total.stored_size, total.ratio(), total.entries,
+                total.encryption_overhead, total.index_size, total.dead_size
+            );
         }
         "search" => {
             if args.len() < 4 { return print_usage("search <archive.sk1> <pattern>"); }
-            let archive = ShokoArchive::open(&args[2])?;
+            let mut archive = ShokoArchive::open(&args[2])?;
+            if archive.is_locked() {
+                unlock(&mut archive, false)?;
+            }
             let pattern = &args[3];
             let matches = archive.match_glob(pattern).map_err(|e| {
                 std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("Invalid glob: {}", e))
@@ -91,6 +233,7 @@ fn main() -> std::io::Result<()> {
         "delete" => {
             if args.len() < 4 { return print_usage("delete <archive.sk1> <internal_path>"); }
             let mut archive = ShokoArchive::open(&args[2])?;
+            unlock(&mut archive, false)?;
             let internal_path = &args[3];
             
             info!("Deleting '{}'...", internal_path);
@@ -101,13 +244,15 @@ fn main() -> std::io::Result<()> {
             info!("Successfully removed and optimized.");
         }
         "write" => {
-            if args.len() < 3 { return print_usage("write <archive.sk1>/<file>"); }
+            if args.len() < 3 { return print_usage("write <archive.sk1>/<file> [--delta[=max_chain]]"); }
             let target = &args[2];
             let (arc_path, inner_path) = target.split_once('/').ok_or_else(|| {
                 std::io::Error::new(std::io::ErrorKind::InvalidInput, "Format: archive.sk1/file.txt")
             })?;
 
             let mut archive = ShokoArchive::open(arc_path)?;
+            unlock(&mut archive, false)?;
+            archive.set_delta_mode(delta_chain);
             let initial_content = archive.extract_file(inner_path).unwrap_or_default();
             
             let tmp_path = ".shoko_edit.tmp";
@@ -121,24 +266,294 @@ fn main() -> std::io::Result<()> {
             fs::remove_file(tmp_path)?;
             info!("Successfully updated {}", inner_path);
         }
+        "info" => {
+            if args.len() < 3 { return print_usage("info <archive.sk1>"); }
+            // header only, no key needed
+            let archive = ShokoArchive::open(&args[2])?;
+            println!("encryption: {}", archive.encryption().name());
+            if archive.encryption().is_encrypted() {
+                let kdf = match archive.key_derivation() {
+                    KeyDerivation::Argon2id { params, .. } => format!("argon2id m={} t={} p={}", params.m_cost, params.t_cost, params.p_cost),
+                    KeyDerivation::Wrapped => "random key, see key slots and recipients".to_string(),
+                    _ => "raw".to_string(),
+                };
+                println!("key derivation: {}", kdf);
+                match archive.key_id() {
+                    Some(id) => println!("key id: {}", sign::to_hex(&id)),
+                    None => println!("key id: not recorded (older archive)"),
+                }
+                println!("index: {}", if archive.options().encrypt_index { "sealed" } else { "plain" });
+                println!("padding: {}", archive.options().padding.name());
+                println!("key slots: {}, recipients: {}", archive.key_slots().len(), archive.recipient_count());
+            }
+            if let Some(id) = archive.archive_id() {
+                println!("archive id: {}", sign::to_hex(&id));
+            }
+            if let Some(signature) = archive.signature() {
+                println!("signed by: {}", sign::to_hex(&signature.public_key));
+            }
+            if !archive.is_locked() {
+                println!("entries: {}", archive.entries.len());
+            }
+        }
+        "key" => {
+            if args.len() < 4 { return print_usage("key add|remove|list <archive.sk1> [slot id] [--label=NAME] [--new-key-file=PATH] [--recipient=PUB]"); }
+            let mut archive = ShokoArchive::open(&args[3])?;
+            match args[2].as_str() {
+                "list" => {
+                    println!("encryption: {}", archive.encryption().name());
+                    for slot in archive.key_slots() {
+                        let kdf = match slot.kdf {
+                            KeyDerivation::Argon2id { params, .. } => format!("argon2id m={} t={} p={}", params.m_cost, params.t_cost, params.p_cost),
+                            _ => "raw".to_string(),
+                        };
+                        println!("{:>4}  {:<24} {}", slot.id, slot.label, kdf);
+                    }
+                    if archive.recipient_count() > 0 {
+                        println!("{} recipients (public keys, who they are isn't recorded)", archive.recipient_count());
+                    }
+                    if archive.key_derivation() != KeyDerivation::Wrapped {
+                        println!("the passphrase the archive was created with unlocks it too (older archive, rekey to be able to remove it)");
+                    }
+                }
+                "add" => {
+                    unlock(&mut archive, false)?;
+                    let recipients = recipients(&args)?;
+                    if !recipients.is_empty() {
+                        for r in &recipients {
+                            archive.add_recipient(r)?;
+                        }
+                        info!("Added {} recipients", recipients.len());
+                        return Ok(());
+                    }
+                    let label = args.iter().find_map(|a| a.strip_prefix("--label=")).unwrap_or("");
+                    let id = archive.add_key_slot(label, new_key_provider("New passphrase for the slot:")?.as_ref())?;
+                    info!("Added key slot {}", id);
+                }
+                "remove" => {
+                    let id = args.get(4).and_then(|v| v.parse().ok()).ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::InvalidInput, "key remove needs a slot id, see `sar key list`")
+                    })?;
+                    unlock(&mut archive, false)?;
+                    archive.remove_key_slot(id)?;
+                    info!("Removed key slot {}", id);
+                }
+                other => return print_usage(&format!("key add|remove|list <archive.sk1>, not '{}'", other)),
+            }
+        }
+        "rekey" => {
+            if args.len() < 3 { return print_usage("rekey <archive.sk1> [--new-key-file=PATH]"); }
+            let mut archive = ShokoArchive::open(&args[2])?;
+            // the old key can be an --identity too, so it's unlocked here and rekey won't ask
+            unlock(&mut archive, false)?;
+            let new = new_key_provider("New archive passphrase:")?;
+            let summary = archive.rekey(&RawKey::new(Vec::new()), new.as_ref())?;
+            if summary.resumed > 0 {
+                info!("Picked up an interrupted rekey, {} of {} blobs were already done", summary.resumed, summary.blobs);
+            }
+            info!("Re-encrypted {} blobs, all {} entries open under the new key", summary.blobs, archive.entries.len());
+            if summary.slots_dropped > 0 {
+                println!("{} key slots wrapped the old key and were dropped, add them again with `sar key add`", summary.slots_dropped);
+            }
+        }
+        "verify" => {
+            if args.len() < 3 { return print_usage("verify <archive.sk1>"); }
+            let mut archive = ShokoArchive::open(&args[2])?;
+            archive.set_limits(limits);
+            unlock(&mut archive, false)?;
+            let count = archive.verify()?;
+            println!("{} entries OK", count);
+        }
+        "sign" => {
+            let key_path = args.iter().find_map(|a| a.strip_prefix("--key="));
+            let (Some(archive_path), Some(key_path)) = (args.get(2), key_path) else {
+                return print_usage("sign <archive.sk1> --key=SIGNING_KEY");
+            };
+            let key = sign::read_signing_key(key_path)?;
+            let mut archive = ShokoArchive::open(archive_path)?;
+            if archive.is_locked() {
+                unlock(&mut archive, false)?;
+            }
+            archive.sign(&key)?;
+            println!("Signed with {}", sign::to_hex(key.verifying_key().as_bytes()));
+        }
+        "verify-sig" => {
+            let pubkey = args.iter().find_map(|a| a.strip_prefix("--pubkey="));
+            let (Some(archive_path), Some(pubkey)) = (args.get(2), pubkey) else {
+                return print_usage("verify-sig <archive.sk1> --pubkey=FILE|HEX");
+            };
+            let trusted = sign::read_verifying_key(pubkey)?;
+            let mut archive = ShokoArchive::open(archive_path)?;
+            if archive.is_locked() {
+                unlock(&mut archive, false)?;
+            }
+            archive.verify_signature(&trusted)?;
+            println!("Good signature from {}", sign::to_hex(trusted.as_bytes()));
+        }
+        "train-dict" => {
+            let output = args.iter().position(|a| a == "-o").and_then(|i| args.get(i + 1));
+            let (Some(folder), Some(output)) = (args.get(2), output) else {
+                return print_usage("train-dict <folder> -o <dict.bin>");
+            };
+            let trained = dict::train_from_dir(Path::new(folder), dict::DEFAULT_DICT_SIZE, jobs)?;
+            fs::write(output, &trained)?;
+            info!("Wrote a {} byte dictionary to {}", trained.len(), output);
+        }
+        "compress" => {
+            // the stream format is RLE records only, there's no LZ framing for stdin/stdout
+            if use_lz {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "compress only speaks RLE, --codec=lz and --dict are for pack"));
+            }
+            let stdout = io::stdout().lock();
+            let mut encoder = RleEncoder::new(stdout, clevel);
+            io::copy(&mut io::stdin().lock(), &mut encoder)?;
+            let _ = encoder.finish()?;
+        }
+        "decompress" => {
+            let mut decoder = RleDecoder::new(io::stdin().lock());
+            io::copy(&mut decoder, &mut io::stdout().lock())?;
+        }
         _ => print_help(),
     }
 
     Ok(())
 }
 
-fn render_tree(archive: &ShokoArchive) {
+/// a --max-*= value. one that doesn't parse is an error, not a limit quietly turned off
+fn parse_limit(arg: &str, val: &str) -> io::Result<u64> {
+    val.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!(
+        "Bad limit '{}', expected a plain number (use --no-limits to turn the limits off)", arg
+    )))
+}
+
+/// a --kdf-*= value. the default costs aren't what someone who typed a value asked for
+fn parse_cost(arg: &str, val: &str) -> io::Result<u32> {
+    val.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!(
+        "Bad Argon2 cost '{}', expected a plain number (KiB for --kdf-mem)", arg
+    )))
+}
+
+/// encryption and key derivation for a new archive, from the pack flags
+fn archive_options(args: &[String]) -> io::Result<ArchiveOptions> {
+    let mut options = ArchiveOptions::default();
+    let mut params = Argon2Params::default();
+    for arg in args {
+        if arg == "--no-encrypt" {
+            options.encryption = Encryption::None;
+        } else if arg == "--raw-key" {
+            options.kdf = None;
+        } else if arg == "--encrypt-index" {
+            options.encrypt_index = true;
+        } else if let Some(name) = arg.strip_prefix("--cipher=") {
+            options.encryption = Encryption::from_name(name)
+                .filter(|e| e.is_encrypted())
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!(
+                    "Unknown cipher '{}', pick one of aes-256-gcm-stream, aes-256-gcm, chacha20-poly1305, xchacha20-poly1305", name
+                )))?;
+        } else if let Some(name) = arg.strip_prefix("--pad=") {
+            options.padding = Padding::from_name(name).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!(
+                "Unknown padding '{}', pick one of none, pow2, padme, block:BYTES", name
+            )))?;
+        } else if let Some(val) = arg.strip_prefix("--kdf-mem=") {
+            params.m_cost = parse_cost(arg, val)?;
+        } else if let Some(val) = arg.strip_prefix("--kdf-passes=") {
+            params.t_cost = parse_cost(arg, val)?;
+        }
+    }
+    options.kdf = options.kdf.map(|_| params);
+    Ok(options)
+}
+
+/// gets the archive its secret: from --key-file=PATH, from SHOKO_KEY or SHOKO_KEY_FILE, or else by asking.
+/// `confirm` makes the user type it twice, for new archives
+fn unlock(archive: &mut ShokoArchive, confirm: bool) -> io::Result<()> {
+    if !archive.encryption().is_encrypted() {
+        // a key on the command line means the archive should be encrypted, don't let a flipped
+        // header pass it off as plaintext. SHOKO_KEY tends to be set for everything, so it isn't
+        if env::args().any(|a| a.starts_with("--key-file=") || a.starts_with("--identity=")) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "The archive says it isn't encrypted but a key was given, leave the key off if that's expected"));
+        }
+        return Ok(());
+    }
+    if let Some(path) = env::args().find_map(|a| a.strip_prefix("--identity=").map(str::to_string)) {
+        return archive.unlock_with_identity(&recipient::read_identity(path)?);
+    }
+    archive.unlock_with(key_provider(confirm)?.as_ref())
+}
+
+/// every --recipient=PUB, each a public key file or the key as hex
+fn recipients(args: &[String]) -> io::Result<Vec<recipient::PublicKey>> {
+    args.iter()
+        .filter_map(|a| a.strip_prefix("--recipient="))
+        .map(recipient::read_recipient)
+        .collect()
+}
+
+/// where the archive's secret comes from: --key-file, then SHOKO_KEY or SHOKO_KEY_FILE, then a prompt
+fn key_provider(confirm: bool) -> io::Result<Box<dyn KeyProvider>> {
+    let args: Vec<String> = env::args().collect();
+    if let Some(path) = args.iter().find_map(|a| a.strip_prefix("--key-file=")) {
+        return Ok(Box::new(KeyFile::new(path).with_encoding(key_encoding()?)));
+    }
+    if env::var_os(encrypt::KEY_ENV).is_some() || env::var_os(keys::KEY_FILE_ENV).is_some() {
+        return Ok(Box::new(DefaultKey));
+    }
+    let mut prompt = Password::new("Archive passphrase:").with_display_mode(PasswordDisplayMode::Masked);
+    if !confirm {
+        prompt = prompt.without_confirmation();
+    }
+    let passphrase = prompt.prompt().map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("Archive is encrypted and no key was given (set {} or {}, or pass --key-file): {}", encrypt::KEY_ENV, keys::KEY_FILE_ENV, e),
+        )
+    })?;
+    Ok(Box::new(RawKey::new(passphrase)))
+}
+
+/// how --key-file and --new-key-file are read, --key-encoding=text|binary|hex|base64. hex like
+/// skgenkey writes them if not given
+fn key_encoding() -> io::Result<KeyEncoding> {
+    let Some(name) = env::args().find_map(|a| a.strip_prefix("--key-encoding=").map(str::to_string)) else {
+        return Ok(KeyEncoding::default());
+    };
+    KeyEncoding::from_name(&name).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!(
+        "Unknown key encoding '{}', pick one of text, binary, hex, base64", name
+    )))
+}
+
+/// a secret being handed out (a key slot, a rekey): --new-key-file, otherwise asked for twice
+fn new_key_provider(what: &str) -> io::Result<Box<dyn KeyProvider>> {
+    let args: Vec<String> = env::args().collect();
+    if let Some(path) = args.iter().find_map(|a| a.strip_prefix("--new-key-file=")) {
+        return Ok(Box::new(KeyFile::new(path).with_encoding(key_encoding()?)));
+    }
+    let secret = Password::new(what)
+        .with_display_mode(PasswordDisplayMode::Masked)
+        .prompt()
+        .map_err(|e| io::Error::new(io::ErrorKind::PermissionDenied, format!("No new key given (pass --new-key-file): {}", e)))?;
+    Ok(Box::new(RawKey::new(secret)))
+}
+
+fn render_tree(archive: &ShokoArchive, stats: &[EntryStats]) {
     let mut graph = StableGraph::<String, ()>::new();
     let root_idx = graph.add_node("ROOT".to_string());
     
-    for entry in &archive.entries {
+    for (entry, stat) in archive.entries.iter().zip(stats) {
         let parts: Vec<&str> = entry.path.split('/').collect();
         let mut current_idx = root_idx;
         
         for (i, part) in parts.iter().enumerate() {
             let is_file = i == parts.len() - 1;
             let node_label = if is_file {
-                format!("{} ({} bytes)", part, entry.size)
+                let layout = match stat.layout {
+                    Layout::Plain => String::new(),
+                    other => format!(", {}", other.name()),
+                };
+                format!(
+                    "{} ({} -> {} bytes, {:.2}x, {}{})",
+                    part, stat.original_size, stat.stored_size, stat.ratio(), stat.codec.name(), layout
+                )
             } else {
                 part.to_string()
             };
@@ -178,25 +593,6 @@ fn print_branch(graph: &StableGraph<String, ()>, node: NodeIndex, prefix: &str)
     }
 }
 
-fn pack_recursive(archive: &mut ShokoArchive, root: &str, prefix: &str, clevel: u8) -> std::io::Result<()> {
-    let root_path = Path::new(root);
-    for entry in fs::read_dir(root_path)? {
-        let entry = entry?;
-        let path = entry.path();
-        let name = path.file_name().unwrap().to_str().unwrap();
-        let internal_name = if prefix.is_empty() { name.to_string() } else { format!("{}/{}", prefix, name) };
-
-        if path.is_dir() {
-            pack_recursive(archive, path.to_str().unwrap(), &internal_name, clevel)?;
-        } else {
-            let content = fs::read(&path)?;
-            archive.write_file_direct(&internal_name, &content, clevel)?;
-            info!("Packed: {}", internal_name);
-        }
-    }
-    Ok(())
-}
-
 fn print_help() {
     println!("sar - Shoko Archive CLI");
     println!("Commands:");
@@ -204,11 +600,46 @@ fn print_help() {
     println!("  unpack <arc> [out]          Extract all files");
     println!("  unpack <arc> --glob='*.txt' Selective extraction");
     println!("  read <arc>                  Show tree structure");
+    println!("  ls <arc>                    List entries with sizes, codec and compression ratio");
     println!("  search <arc> <glob>         Find files in archive");
     println!("  delete <arc> <path>         Remove file and optimize");
     println!("  write <arc>/<path>          Edit file in-place");
+    println!("  info <arc>                  Show the archive header: cipher, key id, slots, signature");
+    println!("  key list <arc>              Show the key slots");
+    println!("  key add <arc> [--label=N]   Let one more passphrase (or --new-key-file=PATH) unlock the archive");
+    println!("  key remove <arc> <id>       Drop a key slot");
+    println!("  rekey <arc>                 Re-encrypt everything under a new key (--new-key-file=PATH), resumes if interrupted");
+    println!("  verify <arc>                Check every entry opens and decodes");
+    println!("  sign <arc> --key=FILE       Sign the archive with an Ed25519 key (make one with skgenkey)");
+    println!("  verify-sig <arc> --pubkey=K Check the archive was signed by K (a .pub file or hex) and is unchanged");
+    println!("  train-dict <folder> -o <f>  Train a compression dictionary from sample files");
+    println!("  compress                    RLE-encode stdin to stdout");
+    println!("  decompress                  RLE-decode stdin to stdout");
     println!("\nFlags:");
     println!("  --clevel=N (1-9)            Set RLE compression threshold");
+    println!("  --jobs=N                    Worker threads for pack (default: all cores)");
+    println!("  --solid                     pack: group small files into shared blocks");
+    println!("  --no-encrypt                pack: store blobs unencrypted, no SHOKO_KEY needed");
+    println!("  --cipher=NAME               pack: aes-256-gcm-stream (default), aes-256-gcm, chacha20-poly1305 or xchacha20-poly1305");
+    println!("  --recipient=PUB             pack, key add: encrypt to an X25519 public key (file or hex), repeatable");
+    println!("  --identity=FILE             Open a recipient archive with your X25519 identity");
+    println!("  --key-file=PATH             Read the archive key from a file instead of SHOKO_KEY (or SHOKO_KEY_FILE)");
+    println!("  --key-encoding=E            How key files are read: hex (default), text, binary or base64");
+    println!("  --raw-key                   pack: use SHOKO_KEY as a raw 32 byte key, no passphrase stretching");
+    println!("  --encrypt-index             pack: seal paths and sizes too, listing the archive needs the key");
+    println!("  --pad=P                     pack: hide blob sizes, padding them to pow2, padme or block:BYTES");
+    println!("  --kdf-mem=KiB               pack: Argon2id memory cost (default 19456)");
+    println!("  --kdf-passes=N              pack: Argon2id passes (default 2)");
+    println!("  --dedup                     pack: split files into chunks stored once per archive");
+    println!("  --append                    pack: add to an existing archive instead of replacing it");
+    println!("  --prefix=P                  pack: store files under P/ inside the archive");
+    println!("  --codec=lz                  Use the LZ codec instead of RLE");
+    println!("  --dict[=FILE]               pack: LZ with a dictionary trained from the input (or FILE)");
+    println!("  --max-entry=BYTES           unpack: largest entry to extract (default 1 GiB)");
+    println!("  --max-total=BYTES           unpack: most bytes to extract overall (default 16 GiB)");
+    println!("  --max-ratio=N               unpack: how far a blob may expand (default 1024x)");
+    println!("  --no-limits                 unpack: trust the archive, no extraction limits");
+    println!("  --delta[=N]                 write: store edits as a diff, at most N deep (default 8)");
 }
 
 fn print_usage(s: &str) -> std::io::Result<()> {
@@ -1,54 +1,125 @@
-use inquire::{Confirm, Select, Text};
-use rand::{distributions::Alphanumeric, Rng};
-use std::io::{self, Write};
+use inquire::{InquireError, Select, Text};
+use shoko::keys::{self, KeyEncoding};
+use shoko::{recipient, sign};
+use std::env;
+use std::io;
+
+const KINDS: [&str; 3] = [
+    "Archive key (SHOKO_KEY_FILE)",
+    "Recipient keypair (X25519, for sar --recipient/--identity)",
+    "Signing keypair (Ed25519, for sar sign)",
+];
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("skgenkey: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run() -> io::Result<()> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.iter().any(|a| a == "-h" || a == "--help") {
+        print_help();
+        return Ok(());
+    }
+    // any flag at all means a script is calling, nothing gets asked
+    if !args.is_empty() {
+        return batch(&args);
+    }
 
-fn main() -> io::Result<()> {
     println!("--- Shoko Key Generator (skgenkey) ---");
-    println!("This utility will help you generate a high-security 32-byte key for SHOKO_KEY.\n");
-
-    let key_name = Text::new("What is the name/alias for this key?")
-        .with_default("main-vault-key")
-        .prompt()
-        .unwrap_or_else(|_| "shoko-key".to_string());
-
-    let options = vec!["Standard (Alphanumeric)", "High (Full ASCII Symbols)"];
-    let complexity = Select::new("Select key complexity:", options).prompt().unwrap();
-
-    let key: String = if complexity == "Standard (Alphanumeric)" {
-        rand::thread_rng()
-            .sample_iter(&Alphanumeric)
-            .take(32)
-            .map(char::from)
-            .collect()
-    } else {
-        (0..32)
-            .map(|_| {
-                let c = rand::thread_rng().gen_range(33..126) as u8;
-                c as char
-            })
-            .collect()
-    };
-
-    let separator = "=".repeat(40);
-    println!("\n{}", separator);
-    println!("GENERATED KEY [Name: {}]", key_name);
-    println!("{}", key);
-    println!("{}", separator);
-    
-    println!("\nCRITICAL: Copy this key now. It will be wiped from the screen shortly.");
-    println!("To use it, add this to your .bashrc or .zshrc:");
-    println!("export SHOKO_KEY=\"{}\"", key);
-
-    let _ = Confirm::new("Have you copied the key and saved it in a secure place?")
-        .with_default(false)
-        .prompt();
-
-    print!("\x1B[12A\x1B[J");
-    io::stdout().flush()?;
-
-    println!("SUCCESS: Key wiped from terminal display.");
-    println!("Note: If you haven't exported it yet, run:");
-    println!("export SHOKO_KEY=\"your_copied_key\"");
+    let kind = Select::new("What kind of key?", KINDS.to_vec()).prompt().map_err(prompt_failed)?;
+    if kind == KINDS[1] {
+        let name = ask("File name for the keypair?", "shoko-identity")?;
+        return recipient_keypair(&name);
+    }
+    if kind == KINDS[2] {
+        let name = ask("File name for the keypair?", "shoko-signing")?;
+        return signing_keypair(&name);
+    }
+
+    println!("A random 256 bit key goes straight into a file only you can read, it's never shown.\n");
+    let path = ask("Where should the key file go?", "shoko.key")?;
+    let encodings = vec!["hex", "base64", "binary"];
+    let encoding = Select::new("How should it be written?", encodings).prompt().map_err(prompt_failed)?;
+    archive_key(&path, KeyEncoding::from_name(encoding).unwrap())
+}
+
+/// skgenkey [--kind=archive|recipient|signing] [-o PATH] [--encoding=hex|base64|binary]
+fn batch(args: &[String]) -> io::Result<()> {
+    let mut kind = "archive";
+    let mut out = None;
+    let mut encoding = KeyEncoding::default();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if let Some(val) = arg.strip_prefix("--kind=") {
+            kind = val;
+        } else if let Some(val) = arg.strip_prefix("--out=") {
+            out = Some(val.to_string());
+        } else if arg == "-o" {
+            out = iter.next().cloned();
+        } else if let Some(val) = arg.strip_prefix("--encoding=") {
+            encoding = KeyEncoding::from_name(val)
+                .filter(|e| *e != KeyEncoding::Text)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("Unknown encoding '{}', pick hex, base64 or binary", val)))?;
+        } else {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Unknown argument '{}', see skgenkey --help", arg)));
+        }
+    }
+    match kind {
+        "archive" => archive_key(out.as_deref().unwrap_or("shoko.key"), encoding),
+        "recipient" => recipient_keypair(out.as_deref().unwrap_or("shoko-identity")),
+        "signing" => signing_keypair(out.as_deref().unwrap_or("shoko-signing")),
+        other => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Unknown kind '{}', pick archive, recipient or signing", other))),
+    }
+}
+
+fn archive_key(path: &str, encoding: KeyEncoding) -> io::Result<()> {
+    keys::generate_key_file(path, encoding).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))?;
+    println!("Wrote a 256 bit key to {} ({}, readable by you only)", path, encoding.name());
+    println!("To use it:");
+    println!("export {}=\"{}\" {}={}", keys::KEY_FILE_ENV, path, keys::KEY_ENCODING_ENV, encoding.name());
+    println!("or pass sar --key-file={} --key-encoding={}", path, encoding.name());
+    Ok(())
+}
+
+fn ask(question: &str, default: &str) -> io::Result<String> {
+    Text::new(question).with_default(default).prompt().map_err(prompt_failed)
+}
+
+fn prompt_failed(e: InquireError) -> io::Error {
+    io::Error::new(io::ErrorKind::Interrupted, format!("{} (pass flags to run without prompts, see --help)", e))
+}
+
+fn print_help() {
+    println!("skgenkey - make keys for Shoko archives");
+    println!("With no arguments it asks what to make. Otherwise:");
+    println!("  --kind=K           archive (default), recipient or signing");
+    println!("  -o, --out=PATH     archive: the key file (default shoko.key, never overwritten)");
+    println!("                     recipient/signing: file name without the extension");
+    println!("  --encoding=E       archive: hex (default), base64 or binary");
+}
+
+fn signing_keypair(name: &str) -> io::Result<()> {
+    let key = sign::generate_signing_key();
+    sign::write_signing_key(format!("{}.key", name), &key)?;
+    sign::write_verifying_key(format!("{}.pub", name), &key.verifying_key())?;
+
+    println!("\nSecret key: {}.key (keep this one to yourself, it signs archives)", name);
+    println!("Public key: {}.pub (hand this out, it checks them)", name);
+    println!("Fingerprint: {}", sign::to_hex(key.verifying_key().as_bytes()));
+    Ok(())
+}
+
+fn recipient_keypair(name: &str) -> io::Result<()> {
+    let identity = recipient::generate_identity();
+    let public = recipient::PublicKey::from(&identity);
+    recipient::write_identity(format!("{}.identity", name), &identity)?;
+    recipient::write_recipient(format!("{}.recipient", name), &public)?;
 
+    println!("\nIdentity: {}.identity (keep this one to yourself, it opens archives sent to you)", name);
+    println!("Recipient: {}.recipient (hand this out, people encrypt archives to it)", name);
+    println!("Public key: {}", sign::to_hex(public.as_bytes()));
     Ok(())
 }